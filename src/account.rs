@@ -0,0 +1,304 @@
+//! Self service account recovery: email verification and password reset by email
+use crate::audit::AuditContext;
+use crate::mail::{Mail, Mailer};
+use crate::repository::internal;
+use crate::state::AppState;
+use crate::token::{generate_action_token, verify_action_token};
+use crate::user::{UserInDB, UserProfile};
+use crate::{active_profile, utils};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+use tracing::{error, info};
+
+const VERIFY_EMAIL: &str = "verify_email";
+const RESET_PASSWORD: &str = "reset_password";
+const VERIFY_EXPIRE_IN: i64 = 24 * 3600;
+const RESET_EXPIRE_IN: i64 = 3600;
+const PASSWORD_MIN: usize = 8;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenQuery {
+    pub token: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordResetRequest {
+    /// user name or email
+    pub login: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PasswordReset {
+    pub token: String,
+    pub password: String,
+}
+
+/// Fingerprint of the password hash, reset links stop working once the password changed
+fn password_binding(password_hash: &str) -> String {
+    let digest = Sha256::digest(password_hash.as_bytes());
+    digest.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn link(base: &str, path: &str, token: &str) -> String {
+    format!("{}{}?token={}", base.trim_end_matches('/'), path, token)
+}
+
+async fn find_user(state: &AppState, user_id: &str) -> Result<UserInDB, (StatusCode, String)> {
+    state
+        .users
+        .find_user_by_id(user_id, None)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Not Found".to_string()))
+}
+
+async fn send(mailer: &Arc<dyn Mailer>, mail: Mail) -> Result<(), (StatusCode, String)> {
+    mailer.send(mail).await.map_err(|e| {
+        error!("send mail failed, {:?}", e);
+        (StatusCode::BAD_GATEWAY, "Failed to send mail".to_string())
+    })
+}
+
+/// Send a verification link to the caller's email
+pub async fn request_email_verification(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = active_profile(&headers, &state, "request_email_verification", "").await?;
+    let user = find_user(&state, &p._id).await?;
+    let email = user
+        .user_base
+        .email
+        .clone()
+        .ok_or((StatusCode::BAD_REQUEST, "No email to verify".to_string()))?;
+    if user.user_base.email_verified {
+        return Err((StatusCode::CONFLICT, "Email already verified".to_string()));
+    }
+    let token = generate_action_token(&p._id, VERIFY_EMAIL, &email, VERIFY_EXPIRE_IN)
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mail = Mail {
+        to: email,
+        subject: "Verify your email".to_string(),
+        body: format!(
+            "Hello {},\n\nOpen the link below within 24 hours to verify your email:\n{}\n",
+            user.user_base.name,
+            link(&state.link_base, "/cf/account/verify", &token)
+        ),
+    };
+    send(&state.mailer, mail).await?;
+    Ok("Verification mail sent".to_string())
+}
+
+/// Mark the email verified, as long as it did not change since the link was sent
+pub async fn verify_email(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<TokenQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let claims = verify_action_token(&query.token, VERIFY_EMAIL)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    let user = find_user(&state, &claims.sub).await?;
+    let profile = UserProfile::from(user.clone());
+    let audit = AuditContext::new(&headers, &profile, "verify_email", &claims.binding);
+    let res = if user.user_base.email.as_deref() != Some(claims.binding.as_str()) {
+        Err((StatusCode::BAD_REQUEST, "Email changed since the link was sent".to_string()))
+    } else {
+        let mut user_base = user.user_base.clone();
+        user_base.email_verified = true;
+        state
+            .users
+            .update_user_base(&claims.sub, None, &user_base)
+            .await
+            .map(|_| "Email verified".to_string())
+            .map_err(|e| {
+                error!("verify email failed: {:?}", e);
+                (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+            })
+    };
+    audit.record(&state, &res, None, None).await;
+    res
+}
+
+/// Send a password reset link to the verified email of a user.
+/// Always answers the same so it does not reveal which users exist
+pub async fn request_password_reset(
+    state: State<AppState>,
+    Json(payload): Json<PasswordResetRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let answer = "If the account has a verified email, a reset link was sent".to_string();
+    let user = state
+        .users
+        .find_verified_user(&payload.login)
+        .await
+        .map_err(internal)?;
+    let Some(user) = user else {
+        info!("no password reset for {}", payload.login);
+        return Ok(answer);
+    };
+    let (Some(id), Some(email)) = (
+        user._id.as_object_id().map(|id| id.to_hex()),
+        user.user_base.email.clone(),
+    ) else {
+        return Ok(answer);
+    };
+    let token = generate_action_token(
+        &id,
+        RESET_PASSWORD,
+        &password_binding(&user.password),
+        RESET_EXPIRE_IN,
+    )
+    .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?;
+    let mail = Mail {
+        to: email,
+        subject: "Reset your password".to_string(),
+        body: format!(
+            "Hello {},\n\nOpen the link below within an hour to choose a new password:\n{}\n\nIgnore this mail if you did not ask for it.\n",
+            user.user_base.name,
+            link(&state.link_base, "/cf/account/reset", &token)
+        ),
+    };
+    // a failure answered differently would tell which accounts exist
+    if let Err(e) = state.mailer.send(mail).await {
+        error!("send password reset for {} failed, {:?}", id, e);
+    }
+    Ok(answer)
+}
+
+/// Set a new password with a reset token, the token can only be used once
+pub async fn reset_password(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<PasswordReset>,
+) -> Result<String, (StatusCode, String)> {
+    let claims = verify_action_token(&payload.token, RESET_PASSWORD)
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+    if payload.password.chars().count() < PASSWORD_MIN {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Password must have at least {} characters", PASSWORD_MIN),
+        ));
+    }
+    let user = find_user(&state, &claims.sub).await?;
+    let profile = UserProfile::from(user.clone());
+    let audit = AuditContext::new(&headers, &profile, "reset_password", &profile._id);
+    let res = if password_binding(&user.password) != claims.binding {
+        Err((StatusCode::BAD_REQUEST, "Link already used".to_string()))
+    } else {
+        match utils::encrypt(&payload.password) {
+            Ok(hashed) => state
+                .users
+                .set_password(&claims.sub, &hashed)
+                .await
+                .map(|_| "Password changed".to_string())
+                .map_err(|e| {
+                    error!("reset password failed: {:?}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                }),
+            Err(e) => Err((StatusCode::INTERNAL_SERVER_ERROR, e.to_string())),
+        }
+    };
+    audit.record(&state, &res, None, None).await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mail::MemoryMailer;
+    use crate::user::{UserCreation, UserCreationDB};
+
+    struct DownMailer;
+
+    #[async_trait::async_trait]
+    impl Mailer for DownMailer {
+        async fn send(&self, _mail: Mail) -> anyhow::Result<()> {
+            anyhow::bail!("relay unreachable")
+        }
+    }
+
+    #[test]
+    fn binding_test() {
+        let h1 = utils::encrypt("hunter22").unwrap();
+        let h2 = utils::encrypt("hunter22").unwrap();
+        assert_eq!(password_binding(&h1), password_binding(&h1));
+        assert_ne!(password_binding(&h1), password_binding(&h2));
+        // stable across builds, links mailed before an upgrade keep working
+        assert_eq!(
+            password_binding("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(
+            link("http://cf/", "/cf/account/reset", "abc"),
+            "http://cf/cf/account/reset?token=abc"
+        );
+    }
+
+    #[tokio::test]
+    async fn reset_flow_test() {
+        let mailer = MemoryMailer::default();
+        let state = State(AppState::memory(Arc::new(mailer.clone()), "http://cf"));
+        let creation: UserCreation = serde_json::from_str(
+            r#"{"name":"zhangsan","phone":"+8613800138000","password":"hunter22","email":"zs@example.com"}"#,
+        )
+        .unwrap();
+        let id = state
+            .users
+            .insert_user(UserCreationDB::from(creation))
+            .await
+            .unwrap();
+
+        let request = || Json(PasswordResetRequest {
+            login: "zs@example.com".to_string(),
+        });
+        request_password_reset(state.clone(), request()).await.unwrap();
+        assert!(mailer.sent().is_empty(), "unverified emails get no reset link");
+
+        let mut user_base = state.users.find_user_by_id(&id, None).await.unwrap().unwrap().user_base;
+        user_base.email_verified = true;
+        state.users.update_user_base(&id, None, &user_base).await.unwrap();
+        request_password_reset(state.clone(), request()).await.unwrap();
+        let body = &mailer.sent()[0].body;
+        let token = body.split("token=").nth(1).unwrap().lines().next().unwrap();
+
+        let reset = || Json(PasswordReset {
+            token: token.to_string(),
+            password: "correct horse".to_string(),
+        });
+        reset_password(HeaderMap::new(), state.clone(), reset()).await.unwrap();
+        let stored = state.users.find_user_by_id(&id, None).await.unwrap().unwrap();
+        assert!(utils::valid("correct horse", &stored.password).unwrap());
+        let reused = reset_password(HeaderMap::new(), state.clone(), reset()).await;
+        assert_eq!(reused.unwrap_err().0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn reset_mail_failure_test() {
+        let state = State(AppState::memory(Arc::new(DownMailer), "http://cf"));
+        let creation: UserCreation = serde_json::from_str(
+            r#"{"name":"lisi","phone":"+8613800138001","password":"hunter22","email":"ls@example.com"}"#,
+        )
+        .unwrap();
+        let id = state
+            .users
+            .insert_user(UserCreationDB::from(creation))
+            .await
+            .unwrap();
+        let mut user_base = state.users.find_user_by_id(&id, None).await.unwrap().unwrap().user_base;
+        user_base.email_verified = true;
+        state.users.update_user_base(&id, None, &user_base).await.unwrap();
+
+        let request = |login: &str| Json(PasswordResetRequest {
+            login: login.to_string(),
+        });
+        let known = request_password_reset(state.clone(), request("ls@example.com")).await;
+        let unknown = request_password_reset(state.clone(), request("nobody@example.com")).await;
+        assert_eq!(known, unknown);
+    }
+}
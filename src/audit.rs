@@ -0,0 +1,369 @@
+//! Persistent audit trail of privileged actions
+use crate::repository::internal;
+use crate::state::AppState;
+use crate::tenant::default_tenant;
+use crate::tenant_admin_check;
+use crate::user::UserProfile;
+use axum::extract::{ConnectInfo, Query, Request, State};
+use axum::http::header::{HeaderMap, HeaderValue};
+use axum::http::StatusCode;
+use axum::middleware::Next;
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, Document};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tracing::error;
+
+const DEFAULT_LIMIT: i64 = 100;
+
+/// The actor of the actions refused for a missing or invalid token
+pub const ANONYMOUS: &str = "anonymous";
+
+/// The header `client_address` gives the client address in, never the one the client sent
+pub const CLIENT_IP_HEADER: &str = "x-cf-client-ip";
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Success,
+    Failure,
+}
+
+/// One audited action, as returned to clients
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditRecord {
+    pub actor: String,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub action: String,
+    pub target: String,
+    pub request_id: String,
+    pub source_ip: Option<String>,
+    pub outcome: Outcome,
+    pub detail: Option<String>,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+    pub at: DateTime<Utc>,
+}
+
+/// The stored form, `at` is kept as BSON datetime so range queries are exact
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub(crate) struct AuditRecordDB {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    _id: Option<Bson>,
+    actor: String,
+    #[serde(default = "default_tenant")]
+    tenant: String,
+    action: String,
+    target: String,
+    request_id: String,
+    source_ip: Option<String>,
+    outcome: Outcome,
+    detail: Option<String>,
+    before: Option<serde_json::Value>,
+    after: Option<serde_json::Value>,
+    at: bson::DateTime,
+}
+
+impl From<AuditRecord> for AuditRecordDB {
+    fn from(value: AuditRecord) -> Self {
+        AuditRecordDB {
+            _id: None,
+            actor: value.actor,
+            tenant: value.tenant,
+            action: value.action,
+            target: value.target,
+            request_id: value.request_id,
+            source_ip: value.source_ip,
+            outcome: value.outcome,
+            detail: value.detail,
+            before: value.before,
+            after: value.after,
+            at: bson::DateTime::from_millis(value.at.timestamp_millis()),
+        }
+    }
+}
+
+impl From<AuditRecordDB> for AuditRecord {
+    fn from(value: AuditRecordDB) -> Self {
+        AuditRecord {
+            actor: value.actor,
+            tenant: value.tenant,
+            action: value.action,
+            target: value.target,
+            request_id: value.request_id,
+            source_ip: value.source_ip,
+            outcome: value.outcome,
+            detail: value.detail,
+            before: value.before,
+            after: value.after,
+            at: DateTime::from_timestamp_millis(value.at.timestamp_millis()).unwrap_or_default(),
+        }
+    }
+}
+
+/// Who did what on which target, captured before the action runs
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    actor: String,
+    tenant: String,
+    action: String,
+    target: String,
+    request_id: String,
+    source_ip: Option<String>,
+}
+
+impl AuditContext {
+    pub fn new(headers: &HeaderMap, actor: &UserProfile, action: &str, target: &str) -> Self {
+        AuditContext {
+            actor: actor.user_base.name.clone(),
+            tenant: actor.user_base.tenant.clone(),
+            action: action.to_string(),
+            target: target.to_string(),
+            request_id: request_id(headers),
+            source_ip: source_ip(headers),
+        }
+    }
+
+    /// The context of an action whose caller is unknown, its token being refused
+    pub fn anonymous(headers: &HeaderMap, action: &str, target: &str) -> Self {
+        AuditContext {
+            actor: ANONYMOUS.to_string(),
+            tenant: default_tenant(),
+            action: action.to_string(),
+            target: target.to_string(),
+            request_id: request_id(headers),
+            source_ip: source_ip(headers),
+        }
+    }
+
+    /// Build the record of the action from its result
+    pub fn to_record<T>(
+        &self,
+        result: &Result<T, (StatusCode, String)>,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) -> AuditRecord {
+        let (outcome, detail) = match result {
+            Ok(_) => (Outcome::Success, None),
+            Err((code, msg)) => (Outcome::Failure, Some(format!("{} {}", code, msg))),
+        };
+        AuditRecord {
+            actor: self.actor.clone(),
+            tenant: self.tenant.clone(),
+            action: self.action.clone(),
+            target: self.target.clone(),
+            request_id: self.request_id.clone(),
+            source_ip: self.source_ip.clone(),
+            outcome,
+            detail,
+            before,
+            after,
+            at: Utc::now(),
+        }
+    }
+
+    /// Persist the record of the action, failures are logged but never fail the action itself
+    pub async fn record<T>(
+        &self,
+        state: &AppState,
+        result: &Result<T, (StatusCode, String)>,
+        before: Option<serde_json::Value>,
+        after: Option<serde_json::Value>,
+    ) {
+        let record = self.to_record(result, before, after);
+        if let Err(e) = state.audit.insert_audit(record).await {
+            error!("write audit record failed, {:?}", e);
+        }
+    }
+}
+
+/// Take the request id from `x-request-id`, or make a new one
+fn request_id(headers: &HeaderMap) -> String {
+    headers
+        .get("x-request-id")
+        .and_then(|v| v.to_str().ok())
+        .map_or_else(|| ObjectId::new().to_hex(), |v| v.to_string())
+}
+
+/// Take the client address `client_address` found
+fn source_ip(headers: &HeaderMap) -> Option<String> {
+    headers
+        .get(CLIENT_IP_HEADER)
+        .and_then(|v| v.to_str().ok())
+        .map(|ip| ip.to_string())
+}
+
+/// The address of the client: the peer, or when the peer is one of the `trusted` proxies
+/// the last hop of `x-forwarded-for`, else `x-real-ip`, that is not a trusted proxy itself
+pub fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    if !trusted.contains(&peer) {
+        return peer;
+    }
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .filter_map(|ip| ip.trim().parse().ok())
+        .collect();
+    if let Some(ip) = forwarded.iter().rev().find(|ip| !trusted.contains(ip)) {
+        return *ip;
+    }
+    forwarded
+        .first()
+        .copied()
+        .or_else(|| {
+            headers
+                .get("x-real-ip")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|ip| ip.trim().parse().ok())
+        })
+        .unwrap_or(peer)
+}
+
+/// Hand the client address to the handlers in `CLIENT_IP_HEADER`, replacing one sent by the client
+pub async fn client_address(
+    State(trusted): State<Arc<Vec<IpAddr>>>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(peer.ip(), request.headers(), &trusted);
+    let value = HeaderValue::from_str(&ip.to_string()).unwrap();
+    request.headers_mut().insert(CLIENT_IP_HEADER, value);
+    next.run(request).await
+}
+
+/// Serialize a snapshot for the `before` / `after` fields
+pub fn snapshot<T: Serialize>(value: &T) -> Option<serde_json::Value> {
+    serde_json::to_value(value).ok()
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub action: Option<String>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+
+impl AuditQuery {
+    /// The records to skip and the most to return
+    pub(crate) fn page(&self) -> (u64, i64) {
+        (self.skip.unwrap_or(0), self.limit.unwrap_or(DEFAULT_LIMIT))
+    }
+
+    /// Whether the record matches, like `filter` does in MongoDB
+    pub(crate) fn matches(&self, r: &AuditRecord) -> bool {
+        self.actor.as_ref().is_none_or(|a| a == &r.actor)
+            && self.action.as_ref().is_none_or(|a| a == &r.action)
+            && self.from.is_none_or(|from| r.at >= from)
+            && self.to.is_none_or(|to| r.at < to)
+    }
+
+    pub(crate) fn filter(&self) -> Document {
+        let mut filter = doc! {};
+        if let Some(actor) = &self.actor {
+            filter.insert("actor", actor);
+        }
+        if let Some(action) = &self.action {
+            filter.insert("action", action);
+        }
+        let mut range = doc! {};
+        if let Some(from) = &self.from {
+            range.insert("$gte", bson::DateTime::from_millis(from.timestamp_millis()));
+        }
+        if let Some(to) = &self.to {
+            range.insert("$lt", bson::DateTime::from_millis(to.timestamp_millis()));
+        }
+        if !range.is_empty() {
+            filter.insert("at", range);
+        }
+        filter
+    }
+}
+
+/// Search the audit trail by actor, action and time range, newest first.
+/// Only for admins, tenant admins only see the actions of their tenant.
+pub async fn search_audit(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<AuditQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = tenant_admin_check(&headers, &state, "search_audit", "").await?;
+    let records = state
+        .audit
+        .search_audit(&query, p.tenant_scope())
+        .await
+        .map_err(internal)?;
+    Ok(serde_json::to_string(&records).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn request_meta_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-request-id", "req-1".parse().unwrap());
+        headers.insert(CLIENT_IP_HEADER, "10.0.0.1".parse().unwrap());
+        let ctx = AuditContext::new(&headers, &UserProfile::default_super(), "update_user", "u1");
+        assert_eq!(ctx.request_id, "req-1");
+        assert_eq!(ctx.source_ip, Some("10.0.0.1".to_string()));
+
+        let failed: Result<(), (StatusCode, String)> =
+            Err((StatusCode::NOT_FOUND, "Not Found".to_string()));
+        let r = ctx.to_record(&failed, None, None);
+        assert_eq!(r.actor, "super");
+        assert_eq!(r.outcome, Outcome::Failure);
+
+        let ctx2 = AuditContext::new(&HeaderMap::new(), &UserProfile::default_super(), "a", "b");
+        assert!(!ctx2.request_id.is_empty());
+        assert_eq!(ctx2.source_ip, None);
+    }
+
+    #[test]
+    fn client_ip_test() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let proxies = [ip("10.0.0.9"), ip("10.0.0.8")];
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "1.1.1.1, 2.2.2.2, 10.0.0.8".parse().unwrap());
+        headers.insert("x-real-ip", "3.3.3.3".parse().unwrap());
+        // forged by a client connecting directly
+        assert_eq!(client_ip(ip("4.4.4.4"), &headers, &proxies), ip("4.4.4.4"));
+        assert_eq!(client_ip(ip("10.0.0.9"), &headers, &[]), ip("10.0.0.9"));
+        // the hop before the trusted proxies, the ones before it may be forged
+        assert_eq!(client_ip(ip("10.0.0.9"), &headers, &proxies), ip("2.2.2.2"));
+        headers.remove("x-forwarded-for");
+        assert_eq!(client_ip(ip("10.0.0.9"), &headers, &proxies), ip("3.3.3.3"));
+        assert_eq!(client_ip(ip("10.0.0.9"), &HeaderMap::new(), &proxies), ip("10.0.0.9"));
+    }
+
+    #[test]
+    fn query_filter_test() {
+        let q = AuditQuery {
+            actor: Some("super".to_string()),
+            from: Some(Utc::now()),
+            ..Default::default()
+        };
+        let f = q.filter();
+        assert_eq!(f.get_str("actor").unwrap(), "super");
+        assert!(f.get_document("at").unwrap().contains_key("$gte"));
+        assert!(!f.contains_key("action"));
+
+        let ctx = AuditContext::new(&HeaderMap::new(), &UserProfile::default_super(), "a", "b");
+        let r = ctx.to_record(&Ok::<(), (StatusCode, String)>(()), None, None);
+        assert!(q.matches(&r));
+        let earlier = AuditQuery {
+            to: q.from,
+            ..Default::default()
+        };
+        assert!(!earlier.matches(&r));
+    }
+}
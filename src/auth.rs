@@ -1,17 +1,14 @@
-use crate::user::{UserInDB, UserProfile};
+use crate::state::AppState;
+use crate::user::UserProfile;
 use crate::{token, utils};
 use axum::Json;
 use axum::{
     extract::State,
     http::StatusCode,
 };
-use mongodb::bson::doc;
-use mongodb::{Collection, Database};
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error};
 
-const COLLECTION: &str = "user";
-
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Authentication {
     pub name: String,
@@ -20,21 +17,23 @@ pub struct Authentication {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct AuthenticationResponse {
-    profile: UserProfile,
-    token: String,
+    pub profile: UserProfile,
+    pub token: String,
 }
 
 pub async fn authenticate(
-    db: State<Database>,
+    state: State<AppState>,
     Json(payload): Json<Authentication>,
 ) -> Result<String, (StatusCode, String)> {
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let f = c.find_one(doc! {"name":&payload.name}, None).await;
+    let f = state.users.find_user_by_name(&payload.name, None).await;
     if let Ok(Some(user_in_db)) = f {
         let password_encrypted = &user_in_db.password;
         match utils::valid(&payload.password, password_encrypted) {
             Ok(v) => {
-                if v {
+                if v && user_in_db.user_base.disabled {
+                    debug!("Disabled user {} tried to log in", payload.name);
+                    Err((StatusCode::FORBIDDEN, "User is disabled".to_string()))
+                } else if v {
                     let user_profile = UserProfile::from(user_in_db);
                     let jwt = token::generate_token(&user_profile, 14400).map_err(|e| {
                         error!("Failed to generate token {:?}", e);
@@ -0,0 +1,419 @@
+//! Batches of writes to the keys of a namespace, applied all together or not at all.
+//! Each write may require the revision its key is at, `0` for a key that must not exist yet,
+//! a batch whose requirements do not all hold is refused with the conflicting keys
+use crate::audit::{snapshot, AuditContext};
+use crate::changeset::check_unreviewed;
+use crate::config_schema::value_errors;
+use crate::configuration::{
+    append_history, seal, target_tenant, ChangeOp, ConfigurationHistory, ConfigurationItems,
+    ConfigurationQuery, ConfigurationValue, ValueType,
+};
+use crate::document::check_field_names;
+use crate::repository::internal;
+use crate::resource::{resource_path, Access};
+use crate::secret::REDACTED;
+use crate::state::AppState;
+use crate::tenant::validate_tenant;
+use crate::validation::{into_response, FieldError};
+use crate::{principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchWrite {
+    pub key: String,
+    pub op: ChangeOp,
+    /// the value of a put
+    pub value: Option<Value>,
+    /// the type of the value of a put, taken from the JSON value when unset
+    #[serde(rename = "type")]
+    pub value_type: Option<ValueType>,
+    /// the revision the key must be at, `0` when it must not exist, unchecked when unset
+    pub revision: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchRequest {
+    pub writes: Vec<BatchWrite>,
+}
+
+/// A write of a batch as stored, secrets sealed
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ItemWrite {
+    pub key: String,
+    pub op: ChangeOp,
+    pub value: Value,
+    #[serde(rename = "type")]
+    pub value_type: ValueType,
+    pub revision: Option<i64>,
+}
+
+impl ItemWrite {
+    /// The write with the value of a secret replaced by a placeholder
+    pub fn redacted(mut self) -> Self {
+        if self.value_type == ValueType::Secret {
+            self.value = Value::String(REDACTED.to_string());
+        }
+        self
+    }
+}
+
+/// A key whose revision is not the one a write requires, `0` for a missing key
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct RevisionConflict {
+    pub key: String,
+    pub expected: Option<i64>,
+    pub actual: i64,
+}
+
+/// The revisions written by a batch or the conflicts refusing it
+pub type BatchOutcome = Result<Vec<ConfigurationHistory>, Vec<RevisionConflict>>;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BatchResult {
+    pub change: String,
+    pub revisions: Vec<ConfigurationHistory>,
+}
+
+/// The writes whose requirement does not hold, `current` holding the items of the batch's keys.
+/// Deleting a missing key is a conflict too
+pub(crate) fn check_revisions(
+    writes: &[ItemWrite],
+    current: &BTreeMap<String, ConfigurationItems>,
+) -> Vec<RevisionConflict> {
+    writes
+        .iter()
+        .filter_map(|w| {
+            let actual = current.get(&w.key).map_or(0, |i| i.revision);
+            let stale = w.revision.is_some_and(|r| r != actual);
+            let missing = w.op == ChangeOp::Delete && actual == 0;
+            (stale || missing).then(|| RevisionConflict {
+                key: w.key.clone(),
+                expected: w.revision,
+                actual,
+            })
+        })
+        .collect()
+}
+
+/// The revision of a write applied to `current`, as recorded in history.
+/// A missing key continues from `deleted`, the revision it was deleted at, `0` if it never existed
+pub(crate) fn revision_of(
+    tenant: &str,
+    namespace: &str,
+    w: &ItemWrite,
+    current: Option<&ConfigurationItems>,
+    deleted: i64,
+    update_by: &str,
+) -> ConfigurationHistory {
+    let (value, value_type, revision) = match (&w.op, current) {
+        (ChangeOp::Delete, Some(item)) => (item.value.clone(), item.value_type, item.revision + 1),
+        (_, current) => (
+            w.value.clone(),
+            w.value_type,
+            current.map_or(deleted, |i| i.revision) + 1,
+        ),
+    };
+    let item = ConfigurationItems {
+        namespace: namespace.to_string(),
+        key: w.key.clone(),
+        value,
+        value_type,
+        tenant: tenant.to_string(),
+        revision,
+        update_at: Utc::now(),
+        update_by: update_by.to_string(),
+    };
+    ConfigurationHistory {
+        op: w.op.clone(),
+        change: None,
+        item,
+    }
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+/// Check the keys of a batch are valid, distinct and writable by the caller
+pub(crate) fn check_keys(
+    p: &Principal,
+    namespace: &str,
+    writes: &[BatchWrite],
+) -> Result<(), (StatusCode, String)> {
+    let mut keys = BTreeSet::new();
+    for w in writes {
+        if w.key.is_empty() || w.key.contains('/') || !keys.insert(w.key.as_str()) {
+            return Err(bad_request(format!("Invalid or repeated key: {}", w.key)));
+        }
+        let path = resource_path(namespace, &w.key);
+        if !p.can_access(Access::Write, &path) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Missing {:?} access on {}", Access::Write, path),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Check the writes of a batch and make them ready to store
+pub(crate) async fn prepare(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+    writes: Vec<BatchWrite>,
+) -> Result<Vec<ItemWrite>, (StatusCode, String)> {
+    let mut errors = Vec::new();
+    let mut prepared = Vec::new();
+    for w in writes {
+        if w.revision.is_some_and(|r| r < 0) || (w.op == ChangeOp::Delete && w.revision == Some(0))
+        {
+            return Err(bad_request(format!("Invalid revision for {}", w.key)));
+        }
+        if w.op == ChangeOp::Delete {
+            prepared.push(ItemWrite {
+                key: w.key,
+                op: w.op,
+                value: Value::Null,
+                value_type: ValueType::default(),
+                revision: w.revision,
+            });
+            continue;
+        }
+        let value = ConfigurationValue {
+            value: w
+                .value
+                .ok_or_else(|| bad_request(format!("Missing value for {}", w.key)))?,
+            value_type: w.value_type,
+        };
+        let value_type = value.declared_type()?;
+        check_field_names(&value.value, &format!("{}#", w.key), &mut errors);
+        for e in value_errors(state, tenant, namespace, &w.key, &value.value).await? {
+            errors.push(FieldError {
+                field: format!("{}#{}", w.key, e.field),
+                message: e.message,
+            });
+        }
+        let value = match value_type {
+            ValueType::Secret => seal(state, tenant, namespace, &w.key, &value.value)?,
+            _ => value.value,
+        };
+        prepared.push(ItemWrite {
+            key: w.key,
+            op: w.op,
+            value,
+            value_type,
+            revision: w.revision,
+        });
+    }
+    if errors.is_empty() {
+        Ok(prepared)
+    } else {
+        Err(into_response(errors))
+    }
+}
+
+/// Apply puts and deletes to keys of a namespace atomically.
+/// Answers 409 with the conflicting keys when a revision requirement does not hold
+pub async fn write_configuration_batch(
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+    Json(payload): Json<BatchRequest>,
+) -> Result<String, (StatusCode, String)> {
+    let namespace = namespace.trim_matches('/').to_string();
+    let p = principal(&headers, &state, "write_configuration_batch", &namespace).await?;
+    let audit = AuditContext::new(
+        &headers,
+        &p.profile,
+        "write_configuration_batch",
+        &namespace,
+    );
+    let res = apply_batch(&state, &p, &query, &namespace, payload.writes).await;
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|r| serde_json::to_string(&r).unwrap());
+    audit.record(&state, &res, None, after).await;
+    res
+}
+
+/// Check and write the batch of `write_configuration_batch`, at once or not at all
+async fn apply_batch(
+    state: &AppState,
+    p: &Principal,
+    query: &ConfigurationQuery,
+    namespace: &str,
+    writes: Vec<BatchWrite>,
+) -> Result<BatchResult, (StatusCode, String)> {
+    if namespace.is_empty() || writes.is_empty() {
+        return Err(bad_request("Missing namespace or writes".to_string()));
+    }
+    check_unreviewed(state, namespace)?;
+    check_keys(p, namespace, &writes)?;
+    let tenant = target_tenant(p, query.tenant.as_deref())?;
+    validate_tenant(state, &tenant).await?;
+    let writes = prepare(state, &tenant, namespace, writes).await?;
+    let outcome = state
+        .configs
+        .write_items(&tenant, namespace, &writes, &p.profile.user_base.name)
+        .await
+        .map_err(internal)?;
+    match outcome {
+        Ok(revisions) => {
+            let change = ObjectId::new().to_hex();
+            for h in &revisions {
+                append_history(state, h.op.clone(), &h.item, Some(&change)).await;
+            }
+            let revisions = revisions
+                .into_iter()
+                .map(|h| ConfigurationHistory {
+                    item: h.item.redacted(),
+                    change: Some(change.clone()),
+                    ..h
+                })
+                .collect();
+            Ok(BatchResult { change, revisions })
+        }
+        Err(conflicts) => Err((
+            StatusCode::CONFLICT,
+            serde_json::to_string(&conflicts).unwrap(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::{AuditQuery, Outcome};
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn put(key: &str, value: Value, revision: Option<i64>) -> BatchWrite {
+        BatchWrite {
+            key: key.to_string(),
+            op: ChangeOp::Put,
+            value: Some(value),
+            value_type: None,
+            revision,
+        }
+    }
+
+    fn delete(key: &str, revision: Option<i64>) -> BatchWrite {
+        BatchWrite {
+            key: key.to_string(),
+            op: ChangeOp::Delete,
+            value: None,
+            value_type: None,
+            revision,
+        }
+    }
+
+    #[tokio::test]
+    async fn batch_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let batch = |writes: Vec<BatchWrite>| {
+            write_configuration_batch(
+                headers.clone(),
+                Path("app".to_string()),
+                state.clone(),
+                Query(ConfigurationQuery::default()),
+                Json(BatchRequest { writes }),
+            )
+        };
+        let item = |key: &'static str| {
+            let state = state.clone();
+            async move {
+                state
+                    .configs
+                    .find_item("default", "app", key)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        let created = batch(vec![
+            put("db.host", json!("a"), Some(0)),
+            put("db.port", json!(5432), Some(0)),
+            put("db.user", json!("app"), None),
+        ])
+        .await
+        .unwrap();
+        let created: BatchResult = serde_json::from_str(&created).unwrap();
+        assert_eq!(created.revisions.len(), 3);
+        assert_eq!(item("db.port").await.unwrap().revision, 1);
+
+        // one stale revision refuses the whole batch
+        let stale = batch(vec![
+            put("db.host", json!("b"), Some(1)),
+            put("db.port", json!(6432), Some(7)),
+            delete("db.user", None),
+        ])
+        .await
+        .unwrap_err();
+        assert_eq!(stale.0, StatusCode::CONFLICT);
+        let conflicts: Vec<RevisionConflict> = serde_json::from_str(&stale.1).unwrap();
+        assert_eq!(
+            conflicts,
+            vec![RevisionConflict {
+                key: "db.port".to_string(),
+                expected: Some(7),
+                actual: 1,
+            }]
+        );
+        assert_eq!(item("db.host").await.unwrap().value, json!("a"));
+        assert!(item("db.user").await.is_some());
+
+        let applied = batch(vec![
+            put("db.host", json!("b"), Some(1)),
+            put("db.port", json!(6432), Some(1)),
+            delete("db.user", Some(1)),
+        ])
+        .await
+        .unwrap();
+        let applied: BatchResult = serde_json::from_str(&applied).unwrap();
+        assert_eq!(item("db.host").await.unwrap().value, json!("b"));
+        assert_eq!(item("db.port").await.unwrap().revision, 2);
+        assert!(item("db.user").await.is_none());
+        let history = state
+            .configs
+            .list_history("default", "app", "db.user")
+            .await
+            .unwrap();
+        assert_eq!(history[0].op, ChangeOp::Delete);
+        assert_eq!(history[0].change.as_ref(), Some(&applied.change));
+        assert_eq!(history[0].item.revision, 2);
+
+        let missing = batch(vec![delete("db.user", None)]).await.unwrap_err();
+        assert_eq!(missing.0, StatusCode::CONFLICT);
+        let repeated = batch(vec![delete("db.host", None), delete("db.host", None)]).await;
+        assert_eq!(repeated.unwrap_err().0, StatusCode::BAD_REQUEST);
+        // rejected batches are audited too
+        let records = state
+            .audit
+            .search_audit(&AuditQuery::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(records.len(), 5);
+        let failed = records.iter().filter(|r| r.outcome == Outcome::Failure);
+        assert_eq!(failed.count(), 3);
+    }
+}
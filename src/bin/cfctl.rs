@@ -0,0 +1,538 @@
+//! `cfctl`, the command line administration of the service through its REST API.
+//! `cfctl login <name>` keeps the token in `~/.cfctl/session.json` for the next commands
+use anyhow::{anyhow, Context};
+use cf::client::{write_private, Client};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use reqwest::Method;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::BufRead;
+use std::path::PathBuf;
+
+const DEFAULT_URL: &str = "http://localhost:8081";
+
+#[derive(Parser)]
+#[command(name = "cfctl", about = "Administrate users, roles and configuration")]
+struct Cli {
+    /// the service, like `https://cf.example.com`, the one logged in to by default
+    #[arg(long, global = true)]
+    url: Option<String>,
+    #[arg(long, short, global = true, value_enum, default_value_t = Output::Table)]
+    output: Output,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Output {
+    Table,
+    Json,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Log in and keep the token, the password is read from `CFCTL_PASSWORD` or stdin
+    Login { name: String },
+    /// Forget the token
+    Logout,
+    /// Manage users
+    #[command(subcommand)]
+    Users(UserCommand),
+    /// Manage roles
+    #[command(subcommand)]
+    Roles(RoleCommand),
+    /// Read and write configuration keys
+    #[command(subcommand)]
+    Config(ConfigCommand),
+}
+
+#[derive(Subcommand)]
+enum UserCommand {
+    List {
+        #[arg(long, default_value_t = 50)]
+        limit: i64,
+        #[arg(long, default_value_t = 0)]
+        skip: u64,
+    },
+    /// Create a user, the password is read from `CFCTL_PASSWORD` or stdin
+    Create(NewUser),
+    /// Forbid a user to log in
+    Disable {
+        name: String,
+    },
+    Enable {
+        name: String,
+    },
+    /// Send a password reset link to the verified email of a user
+    ResetPassword {
+        /// user name or email
+        login: String,
+    },
+}
+
+#[derive(Args)]
+struct NewUser {
+    name: String,
+    #[arg(long)]
+    phone: String,
+    #[arg(long)]
+    email: Option<String>,
+    #[arg(long)]
+    tenant: Option<String>,
+    #[arg(long = "role")]
+    roles: Vec<String>,
+}
+
+#[derive(Subcommand)]
+enum RoleCommand {
+    List,
+    Get {
+        name: String,
+    },
+    Create {
+        name: String,
+        #[arg(long, default_value = "")]
+        description: String,
+        #[arg(long = "permission")]
+        permissions: Vec<String>,
+    },
+    Delete {
+        name: String,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommand {
+    /// The value of `namespace/key`
+    Get {
+        path: String,
+        /// show the plain value of a secret
+        #[arg(long)]
+        reveal: bool,
+    },
+    /// Set `namespace/key` to a JSON value, a text not being JSON is taken as a string
+    Set {
+        path: String,
+        value: String,
+        /// string, number, bool, object, list or secret
+        #[arg(long = "type")]
+        value_type: Option<String>,
+    },
+    /// The keys of an application resolving differently in two environments
+    Diff {
+        app: String,
+        from: String,
+        to: String,
+    },
+    /// The revisions of `namespace/key`
+    History { path: String },
+    /// Import a file into a namespace
+    Import {
+        namespace: String,
+        file: PathBuf,
+        /// json, toml, yaml or dotenv, taken from the file extension by default
+        #[arg(long)]
+        format: Option<String>,
+        #[arg(long)]
+        overwrite: bool,
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Print the resolved configuration of an application in an environment as a file
+    Export {
+        app: String,
+        env: String,
+        /// json, toml, yaml, dotenv or properties
+        #[arg(long, default_value = "json")]
+        format: String,
+    },
+}
+
+/// What `login` keeps for the next commands
+#[derive(Serialize, Deserialize, Default)]
+struct Session {
+    url: Option<String>,
+    token: Option<String>,
+}
+
+impl Session {
+    /// The token, only good for the service it was issued by
+    fn token_for(&self, url: &str) -> Option<&str> {
+        let same_url = self.url.as_deref().map(|u| u.trim_end_matches('/'))
+            == Some(url.trim_end_matches('/'));
+        self.token.as_deref().filter(|_| same_url)
+    }
+}
+
+fn session_path() -> anyhow::Result<PathBuf> {
+    let home = std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?;
+    Ok(PathBuf::from(home).join(".cfctl").join("session.json"))
+}
+
+fn load_session() -> Session {
+    session_path()
+        .ok()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .and_then(|text| serde_json::from_str(&text).ok())
+        .unwrap_or_default()
+}
+
+/// Store the session readable by its owner only, it holds a token
+fn store_session(session: &Session) -> anyhow::Result<()> {
+    let path = session_path()?;
+    std::fs::create_dir_all(path.parent().unwrap())?;
+    write_private(&path, &serde_json::to_string_pretty(session)?)
+        .with_context(|| format!("Failed to write {:?}", path))
+}
+
+fn read_password() -> anyhow::Result<String> {
+    if let Ok(password) = std::env::var("CFCTL_PASSWORD") {
+        return Ok(password);
+    }
+    eprint!("Password: ");
+    let mut line = String::new();
+    std::io::stdin().lock().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+/// The text of a cell, strings unquoted
+fn cell(value: Option<&Value>) -> String {
+    match value {
+        None | Some(Value::Null) => String::new(),
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|v| cell(Some(v)))
+            .collect::<Vec<_>>()
+            .join(","),
+        Some(v) => v.to_string(),
+    }
+}
+
+/// Objects as a table of the fields `columns`, the fields of a nested object named by a dotted path
+fn table(rows: &[Value], columns: &[&str]) -> String {
+    let cells: Vec<Vec<String>> = rows
+        .iter()
+        .map(|row| {
+            columns
+                .iter()
+                .map(|c| cell(row.pointer(&format!("/{}", c.replace('.', "/")))))
+                .collect()
+        })
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, c)| {
+            cells
+                .iter()
+                .map(|r| r[i].chars().count())
+                .fold(c.len(), usize::max)
+        })
+        .collect();
+    let line = |fields: Vec<String>| {
+        let padded: Vec<String> = fields
+            .iter()
+            .zip(&widths)
+            .map(|(f, w)| format!("{:w$}", f, w = w))
+            .collect();
+        padded.join("  ").trim_end().to_string()
+    };
+    let mut lines = vec![line(columns.iter().map(|c| c.to_uppercase()).collect())];
+    lines.extend(cells.into_iter().map(line));
+    lines.join("\n")
+}
+
+/// Print a response, as a table of `columns` unless JSON is asked for,
+/// of the fields of the first row when no column is given
+fn print(output: Output, body: &str, columns: &[&str]) -> anyhow::Result<()> {
+    let value: Value = serde_json::from_str(body).unwrap_or(Value::String(body.to_string()));
+    let rows = match (output, value) {
+        (Output::Json, value) => {
+            println!("{}", serde_json::to_string_pretty(&value)?);
+            return Ok(());
+        }
+        (Output::Table, Value::Array(rows)) => rows,
+        (Output::Table, row @ Value::Object(_)) => vec![row],
+        (Output::Table, Value::String(s)) => {
+            println!("{}", s);
+            return Ok(());
+        }
+        (Output::Table, value) => {
+            println!("{}", value);
+            return Ok(());
+        }
+    };
+    let fields: Vec<&str> = match rows.first().and_then(Value::as_object) {
+        Some(row) if columns.is_empty() => row.keys().map(String::as_str).collect(),
+        _ => columns.to_vec(),
+    };
+    println!("{}", table(&rows, &fields));
+    Ok(())
+}
+
+const USER_COLUMNS: &[&str] = &["_id", "name", "tenant", "email", "roles", "disabled"];
+const ROLE_COLUMNS: &[&str] = &["name", "description", "permissions"];
+const ITEM_COLUMNS: &[&str] = &["namespace", "key", "type", "value", "revision", "update_by"];
+
+async fn set_disabled(
+    client: &Client,
+    output: Output,
+    name: &str,
+    disabled: bool,
+) -> anyhow::Result<()> {
+    let user = client
+        .request(Method::GET, &format!("/cf/user/name/{}", name), |r| r)
+        .await?;
+    let mut user: Value = serde_json::from_str(&user)?;
+    user["disabled"] = Value::Bool(disabled);
+    let body = client
+        .request(Method::PUT, "/cf/user", |r| r.json(&user))
+        .await?;
+    print(output, &body, &[])
+}
+
+async fn users(client: &Client, output: Output, command: UserCommand) -> anyhow::Result<()> {
+    match command {
+        UserCommand::List { limit, skip } => {
+            let options = json!({"limit": limit, "skip": skip, "sort_by_name": 1});
+            let body = client
+                .request(Method::POST, "/cf/user/pagi", |r| r.json(&options))
+                .await?;
+            print(output, &body, USER_COLUMNS)
+        }
+        UserCommand::Create(user) => {
+            let mut payload = json!({
+                "name": user.name,
+                "phone": user.phone,
+                "email": user.email,
+                "roles": user.roles,
+                "password": read_password()?,
+            });
+            if let Some(tenant) = user.tenant {
+                payload["tenant"] = Value::String(tenant);
+            }
+            let id = client
+                .request(Method::POST, "/cf/user", |r| r.json(&payload))
+                .await?;
+            print(output, &json!({"_id": id}).to_string(), &["_id"])
+        }
+        UserCommand::Disable { name } => set_disabled(client, output, &name, true).await,
+        UserCommand::Enable { name } => set_disabled(client, output, &name, false).await,
+        UserCommand::ResetPassword { login } => {
+            let body = client
+                .request(Method::POST, "/cf/account/reset/request", |r| {
+                    r.json(&json!({ "login": login }))
+                })
+                .await?;
+            print(output, &body, &[])
+        }
+    }
+}
+
+async fn roles(client: &Client, output: Output, command: RoleCommand) -> anyhow::Result<()> {
+    let (body, columns) = match command {
+        RoleCommand::List => (
+            client.request(Method::GET, "/cf/role", |r| r).await?,
+            ROLE_COLUMNS,
+        ),
+        RoleCommand::Get { name } => (
+            client
+                .request(Method::GET, &format!("/cf/role/{}", name), |r| r)
+                .await?,
+            ROLE_COLUMNS,
+        ),
+        RoleCommand::Create {
+            name,
+            description,
+            permissions,
+        } => {
+            let role =
+                json!({"name": name, "description": description, "permissions": permissions});
+            (
+                client
+                    .request(Method::POST, "/cf/role", |r| r.json(&role))
+                    .await?,
+                &[][..],
+            )
+        }
+        RoleCommand::Delete { name } => (
+            client
+                .request(Method::DELETE, &format!("/cf/role/{}", name), |r| r)
+                .await?,
+            &[][..],
+        ),
+    };
+    print(output, &body, columns)
+}
+
+async fn config(client: &Client, output: Output, command: ConfigCommand) -> anyhow::Result<()> {
+    match command {
+        ConfigCommand::Get { path, reveal } => {
+            let body = client
+                .request(Method::GET, &format!("/cf/config/{}", path), |r| {
+                    r.query(&[("reveal", reveal)])
+                })
+                .await?;
+            print(output, &body, ITEM_COLUMNS)
+        }
+        ConfigCommand::Set {
+            path,
+            value,
+            value_type,
+        } => {
+            let value: Value = serde_json::from_str(&value).unwrap_or(Value::String(value));
+            let payload = json!({"value": value, "type": value_type});
+            let body = client
+                .request(Method::PUT, &format!("/cf/config/{}", path), |r| {
+                    r.json(&payload)
+                })
+                .await?;
+            print(output, &body, ITEM_COLUMNS)
+        }
+        ConfigCommand::Diff { app, from, to } => {
+            let body = client
+                .request(Method::GET, &format!("/cf/diff/{}", app), |r| {
+                    r.query(&[("from", &from), ("to", &to)])
+                })
+                .await?;
+            print(output, &body, &["key", "from.value", "to.value"])
+        }
+        ConfigCommand::History { path } => {
+            let body = client
+                .request(Method::GET, &format!("/cf/history/{}", path), |r| r)
+                .await?;
+            print(
+                output,
+                &body,
+                &[
+                    "revision",
+                    "op",
+                    "value",
+                    "update_at",
+                    "update_by",
+                    "change",
+                ],
+            )
+        }
+        ConfigCommand::Import {
+            namespace,
+            file,
+            format,
+            overwrite,
+            dry_run,
+        } => {
+            let format = match format {
+                Some(format) => format,
+                None => match file.extension().and_then(|e| e.to_str()) {
+                    Some("yml") => "yaml".to_string(),
+                    Some("env") => "dotenv".to_string(),
+                    Some(extension) => extension.to_string(),
+                    None => "json".to_string(),
+                },
+            };
+            let text = std::fs::read_to_string(&file)
+                .with_context(|| format!("Failed to read {:?}", file))?;
+            let body = client
+                .request(Method::POST, &format!("/cf/import/{}", namespace), |r| {
+                    r.query(&[("format", format.as_str())])
+                        .query(&[("overwrite", overwrite), ("dry_run", dry_run)])
+                        .body(text.clone())
+                })
+                .await?;
+            match output {
+                Output::Json => print(output, &body, &[]),
+                Output::Table => {
+                    let report: Value = serde_json::from_str(&body)?;
+                    let count = |field: &str| report[field].as_array().map_or(0, Vec::len);
+                    println!(
+                        "{} added, {} unchanged, {} conflicts",
+                        count("added"),
+                        count("unchanged"),
+                        count("conflicts")
+                    );
+                    Ok(())
+                }
+            }
+        }
+        ConfigCommand::Export { app, env, format } => {
+            let body = client
+                .request(Method::GET, &format!("/cf/render/{}/{}", app, env), |r| {
+                    r.query(&[("format", &format)])
+                })
+                .await?;
+            print!("{}", body);
+            Ok(())
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let mut session = load_session();
+    let url = cli
+        .url
+        .clone()
+        .or(session.url.clone())
+        .unwrap_or_else(|| DEFAULT_URL.to_string());
+    let mut client = Client::new(&url);
+    match cli.command {
+        Command::Login { name } => {
+            client = client.with_credentials(&name, &read_password()?);
+            let token = client.login().await?;
+            store_session(&Session {
+                url: Some(url.clone()),
+                token: Some(token),
+            })?;
+            println!("Logged in to {} as {}", url, name);
+            return Ok(());
+        }
+        Command::Logout => {
+            session.token = None;
+            store_session(&session)?;
+            return Ok(());
+        }
+        _ => {}
+    }
+    let token = session
+        .token_for(&url)
+        .ok_or_else(|| anyhow!("Not logged in to {}, run `cfctl login <name>` first", url))?;
+    client = client.with_token(token);
+    match cli.command {
+        Command::Users(command) => users(&client, cli.output, command).await,
+        Command::Roles(command) => roles(&client, cli.output, command).await,
+        Command::Config(command) => config(&client, cli.output, command).await,
+        Command::Login { .. } | Command::Logout => unreachable!(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn table_test() {
+        let rows = vec![
+            json!({"name": "admin", "roles": ["super", "ops"], "to": {"value": 1}}),
+            json!({"name": "reader-bot", "roles": [], "to": null}),
+        ];
+        assert_eq!(
+            table(&rows, &["name", "roles", "to.value"]),
+            "NAME        ROLES      TO.VALUE\n\
+             admin       super,ops  1\n\
+             reader-bot"
+        );
+    }
+
+    #[test]
+    fn session_test() {
+        let session = Session {
+            url: Some("https://cf.example.com/".to_string()),
+            token: Some("t".to_string()),
+        };
+        assert_eq!(session.token_for("https://cf.example.com"), Some("t"));
+        assert_eq!(session.token_for("https://evil.example.com"), None);
+        assert_eq!(Session::default().token_for(DEFAULT_URL), None);
+    }
+}
@@ -0,0 +1,619 @@
+//! Reviewed configuration changes.
+//!
+//! A changeset is a batch of writes to the keys of a namespace. Its author drafts it and
+//! proposes it, reviewers holding the role configured in the `review` section approve or
+//! reject it, and once approved enough it is applied as one batch whose change id is the
+//! changeset's id. Every step is recorded in the changeset with the participant and a comment.
+//! The namespaces matching the patterns of the `review` section are only written this way
+use crate::audit::{snapshot, AuditContext};
+use crate::batch::{check_keys, check_revisions, prepare, BatchWrite, ItemWrite};
+use crate::configuration::{append_history, target_tenant, ConfigurationHistory};
+use crate::repository::internal;
+use crate::resource::{glob_match, resource_path, Access};
+use crate::state::AppState;
+use crate::tenant::validate_tenant;
+use crate::{principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::Map;
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangesetStatus {
+    /// editable by its author, not yet under review
+    Draft,
+    Proposed,
+    Approved,
+    Rejected,
+    Applied,
+    /// abandoned by its author
+    Withdrawn,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangesetAction {
+    Create,
+    Update,
+    Propose,
+    Approve,
+    Reject,
+    Comment,
+    Withdraw,
+    Apply,
+}
+
+/// A step of the life of a changeset
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ChangesetEvent {
+    pub action: ChangesetAction,
+    /// the name of the participant
+    pub user: String,
+    /// the id of the participant
+    pub user_id: String,
+    pub at: DateTime<Utc>,
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Changeset {
+    pub id: String,
+    pub tenant: String,
+    pub namespace: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// the writes, each requiring the revision its key was at when drafted
+    pub writes: Vec<ItemWrite>,
+    pub status: ChangesetStatus,
+    /// the name of the author
+    pub author: String,
+    /// the id of the author, names can be reused
+    #[serde(default)]
+    pub author_id: String,
+    /// bumped by every step
+    pub revision: i64,
+    pub events: Vec<ChangesetEvent>,
+}
+
+impl Changeset {
+    /// The changeset with the values of secrets replaced by a placeholder
+    pub fn redacted(mut self) -> Self {
+        self.writes = self.writes.into_iter().map(ItemWrite::redacted).collect();
+        self
+    }
+
+    /// The ids of the reviewers who approved the current proposal
+    fn approvers(&self) -> BTreeSet<&str> {
+        let proposed = self
+            .events
+            .iter()
+            .rposition(|e| e.action == ChangesetAction::Propose)
+            .unwrap_or(0);
+        self.events[proposed..]
+            .iter()
+            .filter(|e| e.action == ChangesetAction::Approve)
+            .map(|e| e.user_id.as_str())
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ChangesetPayload {
+    pub namespace: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    pub writes: Vec<BatchWrite>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChangesetComment {
+    pub comment: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ChangesetQuery {
+    pub status: Option<ChangesetStatus>,
+    pub namespace: Option<String>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+/// Refuse direct writes to a namespace changed only through changesets
+pub(crate) fn check_unreviewed(
+    state: &AppState,
+    namespace: &str,
+) -> Result<(), (StatusCode, String)> {
+    if state
+        .review
+        .namespaces
+        .iter()
+        .any(|p| glob_match(p, namespace))
+    {
+        return Err((
+            StatusCode::FORBIDDEN,
+            format!("Changes to {} need an approved changeset", namespace),
+        ));
+    }
+    Ok(())
+}
+
+fn event(p: &Principal, action: ChangesetAction, comment: Option<String>) -> ChangesetEvent {
+    ChangesetEvent {
+        action,
+        user: p.profile.user_base.name.clone(),
+        user_id: p.profile._id.clone(),
+        at: Utc::now(),
+        comment,
+    }
+}
+
+fn conflict(message: String) -> (StatusCode, String) {
+    (StatusCode::CONFLICT, message)
+}
+
+/// Check and prepare the writes of a changeset, pinning each key to its current revision
+/// unless the author required one
+async fn draft_writes(
+    state: &AppState,
+    p: &Principal,
+    tenant: &str,
+    payload: &ChangesetPayload,
+) -> Result<Vec<ItemWrite>, (StatusCode, String)> {
+    let namespace = payload.namespace.trim_matches('/');
+    if namespace.is_empty() || payload.writes.is_empty() || payload.title.trim().is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Missing namespace, title or writes".to_string(),
+        ));
+    }
+    check_keys(p, namespace, &payload.writes)?;
+    let mut writes = prepare(state, tenant, namespace, payload.writes.clone()).await?;
+    let current: BTreeMap<String, _> = state
+        .configs
+        .list_items(tenant, Some(namespace), &Map::new())
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(|i| (i.key.clone(), i))
+        .collect();
+    for w in writes.iter_mut() {
+        w.revision = w
+            .revision
+            .or_else(|| Some(current.get(&w.key).map_or(0, |i| i.revision)));
+    }
+    let conflicts = check_revisions(&writes, &current);
+    if !conflicts.is_empty() {
+        return Err(conflict(serde_json::to_string(&conflicts).unwrap()));
+    }
+    Ok(writes)
+}
+
+async fn find_changeset(
+    state: &AppState,
+    tenant: &str,
+    id: &str,
+) -> Result<Changeset, (StatusCode, String)> {
+    state
+        .changesets
+        .find_changeset(tenant, id)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Not Found".to_string()))
+}
+
+/// Store the next revision of a changeset, 409 when it changed meanwhile
+async fn save(state: &AppState, changeset: &mut Changeset) -> Result<(), (StatusCode, String)> {
+    changeset.revision += 1;
+    let replaced = state
+        .changesets
+        .replace_changeset(changeset)
+        .await
+        .map_err(internal)?;
+    if !replaced {
+        return Err(conflict(format!(
+            "Changeset {} changed meanwhile",
+            changeset.id
+        )));
+    }
+    Ok(())
+}
+
+/// Draft a changeset of the caller
+pub async fn create_changeset(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<ChangesetQuery>,
+    Json(payload): Json<ChangesetPayload>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "create_changeset", &payload.namespace).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_changeset", &payload.namespace);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    validate_tenant(&state, &tenant).await?;
+    let writes = draft_writes(&state, &p, &tenant, &payload).await?;
+    let changeset = Changeset {
+        id: ObjectId::new().to_hex(),
+        tenant,
+        namespace: payload.namespace.trim_matches('/').to_string(),
+        title: payload.title,
+        description: payload.description,
+        writes,
+        status: ChangesetStatus::Draft,
+        author: p.profile.user_base.name.clone(),
+        author_id: p.profile._id.clone(),
+        revision: 1,
+        events: vec![event(&p, ChangesetAction::Create, None)],
+    };
+    let res = state
+        .changesets
+        .insert_changeset(&changeset)
+        .await
+        .map_err(internal)
+        .map(|_| changeset.clone().redacted());
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|c| serde_json::to_string(&c).unwrap());
+    audit.record(&state, &res, None, after).await;
+    res
+}
+
+/// Replace the title, description and writes of a draft, by its author only
+pub async fn update_changeset(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ChangesetQuery>,
+    Json(payload): Json<ChangesetPayload>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "update_changeset", &id).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "update_changeset", &id);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let mut changeset = find_changeset(&state, &tenant, &id).await?;
+    let before = snapshot(&changeset.clone().redacted());
+    if changeset.author_id != p.profile._id {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only the author edits a changeset".to_string(),
+        ));
+    }
+    if changeset.status != ChangesetStatus::Draft {
+        return Err(conflict(format!("Changeset {} is no draft", id)));
+    }
+    if payload.namespace.trim_matches('/') != changeset.namespace {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "The namespace of a changeset can't change".to_string(),
+        ));
+    }
+    changeset.writes = draft_writes(&state, &p, &tenant, &payload).await?;
+    changeset.title = payload.title;
+    changeset.description = payload.description;
+    changeset
+        .events
+        .push(event(&p, ChangesetAction::Update, None));
+    let res = save(&state, &mut changeset)
+        .await
+        .map(|_| changeset.redacted());
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|c| serde_json::to_string(&c).unwrap());
+    audit.record(&state, &res, before, after).await;
+    res
+}
+
+/// The changesets of a tenant, newest first, secrets redacted
+pub async fn list_changesets(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<ChangesetQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "list_changesets", "").await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let changesets: Vec<Changeset> = state
+        .changesets
+        .list_changesets(&tenant, query.status, query.namespace.as_deref())
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(Changeset::redacted)
+        .collect();
+    Ok(serde_json::to_string(&changesets).unwrap())
+}
+
+pub async fn get_changeset(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ChangesetQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_changeset", &id).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let changeset = find_changeset(&state, &tenant, &id).await?;
+    Ok(serde_json::to_string(&changeset.redacted()).unwrap())
+}
+
+/// Apply an approved changeset as one batch, 409 listing the keys changed since it was drafted
+async fn apply(
+    state: &AppState,
+    p: &Principal,
+    changeset: &Changeset,
+) -> Result<(), (StatusCode, String)> {
+    for w in &changeset.writes {
+        let path = resource_path(&changeset.namespace, &w.key);
+        if !p.can_access(Access::Write, &path) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Missing {:?} access on {}", Access::Write, path),
+            ));
+        }
+    }
+    let outcome = state
+        .configs
+        .write_items(
+            &changeset.tenant,
+            &changeset.namespace,
+            &changeset.writes,
+            &p.profile.user_base.name,
+        )
+        .await
+        .map_err(internal)?;
+    let revisions: Vec<ConfigurationHistory> =
+        outcome.map_err(|c| conflict(serde_json::to_string(&c).unwrap()))?;
+    for h in &revisions {
+        append_history(state, h.op.clone(), &h.item, Some(&changeset.id)).await;
+    }
+    Ok(())
+}
+
+/// Move a changeset a step forward: `propose`, `approve`, `reject`, `comment`, `withdraw`
+/// or `apply`. The author proposes, withdraws and applies, reviewers approve, reject and
+/// apply, everyone comments. The body may hold a comment, required to comment
+pub async fn act_on_changeset(
+    headers: HeaderMap,
+    Path((id, action)): Path<(String, ChangesetAction)>,
+    state: State<AppState>,
+    Query(query): Query<ChangesetQuery>,
+    Json(payload): Json<ChangesetComment>,
+) -> Result<String, (StatusCode, String)> {
+    let fn_name = format!("{:?}_changeset", action).to_lowercase();
+    let p = principal(&headers, &state, &fn_name, &id).await?;
+    let audit = AuditContext::new(&headers, &p.profile, &fn_name, &id);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let mut changeset = find_changeset(&state, &tenant, &id).await?;
+    let before = snapshot(&changeset.clone().redacted());
+    let author = changeset.author_id == p.profile._id;
+    let reviewer = p.grants.roles.contains(&state.review.role);
+    let status = changeset.status;
+    let refused = |message: &str| Err((StatusCode::FORBIDDEN, message.to_string()));
+    let next = match action {
+        ChangesetAction::Propose if !author => refused("Only the author proposes a changeset"),
+        ChangesetAction::Withdraw if !author => refused("Only the author withdraws a changeset"),
+        ChangesetAction::Approve | ChangesetAction::Reject if !reviewer => {
+            refused("Only reviewers review a changeset")
+        }
+        ChangesetAction::Approve if author => refused("Authors don't approve their changesets"),
+        ChangesetAction::Apply if !author && !reviewer => {
+            refused("Only the author or reviewers apply a changeset")
+        }
+        ChangesetAction::Comment if payload.comment.is_none() => {
+            Err((StatusCode::BAD_REQUEST, "Missing comment".to_string()))
+        }
+        ChangesetAction::Create | ChangesetAction::Update => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid changeset action {:?}", action),
+        )),
+        ChangesetAction::Propose if status == ChangesetStatus::Draft => {
+            Ok(ChangesetStatus::Proposed)
+        }
+        ChangesetAction::Approve if status == ChangesetStatus::Proposed => {
+            if changeset.approvers().contains(p.profile._id.as_str()) {
+                Err(conflict("Already approved".to_string()))
+            } else if changeset.approvers().len() + 1 >= state.review.approvals {
+                Ok(ChangesetStatus::Approved)
+            } else {
+                Ok(ChangesetStatus::Proposed)
+            }
+        }
+        ChangesetAction::Reject
+            if matches!(
+                status,
+                ChangesetStatus::Proposed | ChangesetStatus::Approved
+            ) =>
+        {
+            Ok(ChangesetStatus::Rejected)
+        }
+        ChangesetAction::Withdraw
+            if matches!(
+                status,
+                ChangesetStatus::Draft | ChangesetStatus::Proposed | ChangesetStatus::Approved
+            ) =>
+        {
+            Ok(ChangesetStatus::Withdrawn)
+        }
+        ChangesetAction::Apply if status == ChangesetStatus::Approved => {
+            Ok(ChangesetStatus::Applied)
+        }
+        ChangesetAction::Comment => Ok(status),
+        _ => Err(conflict(format!(
+            "Can't {:?} a changeset in status {:?}",
+            action, status
+        ))),
+    };
+    changeset.status = next?;
+    changeset.events.push(event(&p, action, payload.comment));
+    // the writes are pinned to revisions, applying twice conflicts, so they are applied first
+    let res = match action {
+        ChangesetAction::Apply => apply(&state, &p, &changeset).await,
+        _ => Ok(()),
+    };
+    let res = match res {
+        Ok(()) => save(&state, &mut changeset)
+            .await
+            .map(|_| changeset.redacted()),
+        Err(e) => Err(e),
+    };
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|c| serde_json::to_string(&c).unwrap());
+    audit.record(&state, &res, before, after).await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::batch::BatchWrite;
+    use crate::config::ReviewConfig;
+    use crate::configuration::{
+        put_configuration, ChangeOp, ConfigurationQuery, ConfigurationValue,
+    };
+    use crate::mail::MemoryMailer;
+    use crate::user::{stored_headers, UserBase};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    async fn headers_of(state: &AppState, name: &str, role: &str) -> HeaderMap {
+        let user_base = UserBase {
+            name: name.to_string(),
+            roles: vec![role.to_string()],
+            ..Default::default()
+        };
+        stored_headers(state, user_base).await
+    }
+
+    #[tokio::test]
+    async fn review_test() {
+        let review = ReviewConfig {
+            namespaces: vec!["*/prod".to_string()],
+            ..Default::default()
+        };
+        let state = State(
+            AppState::memory(Arc::new(MemoryMailer::default()), "http://cf").with_review(review),
+        );
+        state
+            .configs
+            .put_item(
+                "default",
+                "shop/prod",
+                "db.port",
+                &json!(5432),
+                Default::default(),
+                "u",
+            )
+            .await
+            .unwrap();
+        let alice = headers_of(&state, "alice", "admin").await;
+        let bob = headers_of(&state, "bob", "reviewer").await;
+
+        let direct = put_configuration(
+            alice.clone(),
+            Path("shop/prod/db.port".to_string()),
+            state.clone(),
+            Query(ConfigurationQuery::default()),
+            Json(ConfigurationValue {
+                value: json!(6432),
+                value_type: None,
+            }),
+        )
+        .await;
+        assert_eq!(direct.unwrap_err().0, StatusCode::FORBIDDEN);
+
+        let payload = ChangesetPayload {
+            namespace: "shop/prod".to_string(),
+            title: "Move to the new pool".to_string(),
+            description: String::new(),
+            writes: vec![BatchWrite {
+                key: "db.port".to_string(),
+                op: ChangeOp::Put,
+                value: Some(json!(6432)),
+                value_type: None,
+                revision: None,
+            }],
+        };
+        let created = create_changeset(
+            alice.clone(),
+            state.clone(),
+            Query(ChangesetQuery::default()),
+            Json(payload),
+        )
+        .await
+        .unwrap();
+        let created: Changeset = serde_json::from_str(&created).unwrap();
+        assert_eq!(created.status, ChangesetStatus::Draft);
+        assert_eq!(created.writes[0].revision, Some(1));
+
+        let act = |headers: &HeaderMap, action: ChangesetAction, comment: Option<&str>| {
+            act_on_changeset(
+                headers.clone(),
+                Path((created.id.clone(), action)),
+                state.clone(),
+                Query(ChangesetQuery::default()),
+                Json(ChangesetComment {
+                    comment: comment.map(str::to_string),
+                }),
+            )
+        };
+        let early = act(&bob, ChangesetAction::Approve, None).await.unwrap_err();
+        assert_eq!(early.0, StatusCode::CONFLICT);
+        // another user given the name of the author is not the author
+        let impostor = headers_of(&state, "alice", "admin").await;
+        let refused = act(&impostor, ChangesetAction::Propose, None).await.unwrap_err();
+        assert_eq!(refused.0, StatusCode::FORBIDDEN);
+        act(&alice, ChangesetAction::Propose, Some("ready"))
+            .await
+            .unwrap();
+        let own = act(&alice, ChangesetAction::Approve, None)
+            .await
+            .unwrap_err();
+        assert_eq!(own.0, StatusCode::FORBIDDEN);
+        let empty = act(&bob, ChangesetAction::Comment, None).await.unwrap_err();
+        assert_eq!(empty.0, StatusCode::BAD_REQUEST);
+        let approved = act(&bob, ChangesetAction::Approve, Some("lgtm"))
+            .await
+            .unwrap();
+        let approved: Changeset = serde_json::from_str(&approved).unwrap();
+        assert_eq!(approved.status, ChangesetStatus::Approved);
+
+        // bob may apply but has no write access on the keys
+        let denied = act(&bob, ChangesetAction::Apply, None).await.unwrap_err();
+        assert_eq!(denied.0, StatusCode::FORBIDDEN);
+        let applied = act(&alice, ChangesetAction::Apply, None).await.unwrap();
+        let applied: Changeset = serde_json::from_str(&applied).unwrap();
+        assert_eq!(applied.status, ChangesetStatus::Applied);
+        let trail: Vec<(&str, ChangesetAction)> = applied
+            .events
+            .iter()
+            .map(|e| (e.user.as_str(), e.action))
+            .collect();
+        assert_eq!(
+            trail,
+            vec![
+                ("alice", ChangesetAction::Create),
+                ("alice", ChangesetAction::Propose),
+                ("bob", ChangesetAction::Approve),
+                ("alice", ChangesetAction::Apply),
+            ]
+        );
+        let item = state
+            .configs
+            .find_item("default", "shop/prod", "db.port")
+            .await
+            .unwrap();
+        assert_eq!(item.unwrap().value, json!(6432));
+        let history = state
+            .configs
+            .list_change("default", &created.id)
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+
+        let again = act(&alice, ChangesetAction::Apply, None).await.unwrap_err();
+        assert_eq!(again.0, StatusCode::CONFLICT);
+    }
+}
@@ -0,0 +1,457 @@
+//! A client of the service for Rust applications, behind the `client` feature.
+//! It fetches the resolved configuration of an application in an environment into any
+//! `Deserialize` type, keeps the last configuration fetched on disk so that an application
+//! starts while the service is unreachable, and watches for changes by polling with the
+//! ETag of the configuration it holds. Secrets are only fetched when revealed, a configuration
+//! still holding redacted secrets is refused rather than deserialized with placeholders
+use crate::auth::{Authentication, AuthenticationResponse};
+use crate::secret::REDACTED;
+use anyhow::{anyhow, Context};
+use reqwest::header::{ETAG, IF_NONE_MATCH};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tracing::warn;
+
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    /// like `https://cf.example.com`
+    base_url: String,
+    /// to log in again when the token expires
+    credentials: Option<Arc<Authentication>>,
+    token: Arc<Mutex<Option<String>>>,
+    /// where the last configuration fetched is kept, nowhere when unset
+    cache_dir: Option<PathBuf>,
+    /// read the configuration of another tenant than the user's, admins only
+    tenant: Option<String>,
+    /// fetch secrets in plain
+    reveal: bool,
+}
+
+/// A configuration as fetched, and as kept on disk
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct Fetched {
+    etag: Option<String>,
+    config: Value,
+}
+
+/// Whether a failed fetch is worth falling back to the cache, which it is unless
+/// the service answered the request is wrong
+fn unreachable(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| !e.status().is_some_and(|s| s.is_client_error()))
+}
+
+/// Whether a value holds a secret replaced by the placeholder
+fn has_redacted(value: &Value) -> bool {
+    match value {
+        Value::String(s) => s == REDACTED,
+        Value::Array(items) => items.iter().any(has_redacted),
+        Value::Object(fields) => fields.values().any(has_redacted),
+        _ => false,
+    }
+}
+
+/// Write a file readable by its owner only, an existing file too
+pub fn write_private(path: &Path, contents: &str) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    // the mode only applies to a file created
+    #[cfg(unix)]
+    file.set_permissions(std::os::unix::fs::PermissionsExt::from_mode(0o600))?;
+    file.write_all(contents.as_bytes())
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Self {
+        Client {
+            http: reqwest::Client::new(),
+            base_url: base_url.trim_end_matches('/').to_string(),
+            credentials: None,
+            token: Arc::default(),
+            cache_dir: None,
+            tenant: None,
+            reveal: false,
+        }
+    }
+
+    /// Log in as `name` when a token is needed
+    pub fn with_credentials(mut self, name: &str, password: &str) -> Self {
+        self.credentials = Some(Arc::new(Authentication {
+            name: name.to_string(),
+            password: password.to_string(),
+        }));
+        self
+    }
+
+    /// Use a token obtained elsewhere
+    pub fn with_token(self, token: &str) -> Self {
+        *self.token.lock().unwrap() = Some(token.to_string());
+        self
+    }
+
+    /// Keep the last configuration fetched in `dir`
+    pub fn with_cache_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.cache_dir = Some(dir.into());
+        self
+    }
+
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    /// Fetch secrets in plain, the user needs reveal access on each of them
+    pub fn with_reveal(mut self) -> Self {
+        self.reveal = true;
+        self
+    }
+
+    /// Log in with the credentials of the client, returning the token
+    pub async fn login(&self) -> anyhow::Result<String> {
+        let credentials = self
+            .credentials
+            .as_ref()
+            .ok_or_else(|| anyhow!("No credentials to log in with"))?;
+        let response = self
+            .http
+            .post(format!("{}/cf/auth", self.base_url))
+            .json(credentials.as_ref())
+            .send()
+            .await?;
+        let auth: AuthenticationResponse = checked(response).await?.json().await?;
+        *self.token.lock().unwrap() = Some(auth.token.clone());
+        Ok(auth.token)
+    }
+
+    /// Send a request with the token, logging in first when there is none
+    /// and again when it expired
+    async fn send<F>(&self, request: F) -> anyhow::Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let token = self.token.lock().unwrap().clone();
+        let token = match token {
+            Some(token) => token,
+            None => self.login().await?,
+        };
+        let response = request().header("authorization", token).send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED && self.credentials.is_some() {
+            let token = self.login().await?;
+            return Ok(request().header("authorization", token).send().await?);
+        }
+        Ok(response)
+    }
+
+    /// The token in use, obtained by the last login
+    pub fn token(&self) -> Option<String> {
+        self.token.lock().unwrap().clone()
+    }
+
+    /// Call any endpoint, `path` being like `/cf/role`. `build` adds the query and body
+    /// to the request, the body of a successful response is returned
+    pub async fn request<F>(&self, method: Method, path: &str, build: F) -> anyhow::Result<String>
+    where
+        F: Fn(RequestBuilder) -> RequestBuilder,
+    {
+        let url = format!("{}{}", self.base_url, path);
+        let response = self
+            .send(|| build(self.http.request(method.clone(), &url)))
+            .await?;
+        Ok(checked(response).await?.text().await?)
+    }
+
+    /// The resolved configuration, `None` when its ETag is still `etag`
+    async fn fetch_raw(
+        &self,
+        app: &str,
+        env: &str,
+        etag: Option<&str>,
+    ) -> anyhow::Result<Option<Fetched>> {
+        let url = format!("{}/cf/render/{}/{}", self.base_url, app, env);
+        let response = self
+            .send(|| {
+                let mut request = self.http.get(&url).query(&[("format", "json")]);
+                if let Some(tenant) = &self.tenant {
+                    request = request.query(&[("tenant", tenant)]);
+                }
+                if self.reveal {
+                    request = request.query(&[("reveal", "true")]);
+                }
+                if let Some(etag) = etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                request
+            })
+            .await?;
+        if response.status() == StatusCode::NOT_MODIFIED {
+            return Ok(None);
+        }
+        let response = checked(response).await?;
+        let etag = response
+            .headers()
+            .get(ETAG)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string);
+        let config: Value = response.json().await?;
+        if has_redacted(&config) {
+            let hint = if self.reveal { "reveal access" } else { "the client to reveal" };
+            return Err(anyhow!(
+                "The configuration of {}/{} holds redacted secrets, it needs {}",
+                app,
+                env,
+                hint
+            ));
+        }
+        Ok(Some(Fetched { etag, config }))
+    }
+
+    fn cache_path(&self, app: &str, env: &str) -> Option<PathBuf> {
+        let name = match &self.tenant {
+            Some(tenant) => format!("{}.{}.{}.json", tenant, app, env),
+            None => format!("{}.{}.json", app, env),
+        };
+        self.cache_dir.as_ref().map(|dir| dir.join(name))
+    }
+
+    fn load_cache(&self, app: &str, env: &str) -> Option<Fetched> {
+        let path = self.cache_path(app, env)?;
+        let text = std::fs::read_to_string(&path).ok()?;
+        serde_json::from_str(&text)
+            .map_err(|e| warn!("Ignore the cache {:?}, {}", path, e))
+            .ok()
+    }
+
+    fn store_cache(&self, app: &str, env: &str, fetched: &Fetched) {
+        let Some(path) = self.cache_path(app, env) else {
+            return;
+        };
+        // it may hold secrets
+        let stored = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| write_private(&path, &serde_json::to_string(fetched).unwrap()));
+        if let Err(e) = stored {
+            warn!("Failed to cache the configuration in {:?}, {}", path, e);
+        }
+    }
+
+    /// The configuration from the service, else from the cache while the service is unreachable
+    async fn fetch_or_cached(&self, app: &str, env: &str) -> anyhow::Result<Fetched> {
+        match self.fetch_raw(app, env, None).await {
+            Ok(fetched) => {
+                let fetched = fetched.ok_or_else(|| anyhow!("Unexpected 304"))?;
+                self.store_cache(app, env, &fetched);
+                Ok(fetched)
+            }
+            Err(e) if unreachable(&e) => match self.load_cache(app, env) {
+                Some(cached) => {
+                    warn!("Use the cached configuration of {}/{}, {}", app, env, e);
+                    Ok(cached)
+                }
+                None => Err(e),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    /// The resolved configuration of `app` in `env`, dotted keys nesting into objects
+    pub async fn fetch<T: DeserializeOwned>(&self, app: &str, env: &str) -> anyhow::Result<T> {
+        let fetched = self.fetch_or_cached(app, env).await?;
+        serde_json::from_value(fetched.config)
+            .with_context(|| format!("Unexpected configuration of {}/{}", app, env))
+    }
+
+    /// The configuration of `app` in `env`, checked for changes every `interval` until
+    /// the receivers are dropped. A changed configuration that doesn't deserialize is skipped
+    pub async fn watch<T>(
+        &self,
+        app: &str,
+        env: &str,
+        interval: Duration,
+    ) -> anyhow::Result<watch::Receiver<T>>
+    where
+        T: DeserializeOwned + Send + Sync + 'static,
+    {
+        let fetched = self.fetch_or_cached(app, env).await?;
+        let mut etag = fetched.etag;
+        let (tx, rx) = watch::channel(serde_json::from_value(fetched.config)?);
+        let client = self.clone();
+        let (app, env) = (app.to_string(), env.to_string());
+        tokio::spawn(async move {
+            while !tx.is_closed() {
+                tokio::time::sleep(interval).await;
+                match client.fetch_raw(&app, &env, etag.as_deref()).await {
+                    Ok(Some(fetched)) => match serde_json::from_value(fetched.config.clone()) {
+                        Ok(value) => {
+                            client.store_cache(&app, &env, &fetched);
+                            etag = fetched.etag;
+                            let _ = tx.send(value);
+                        }
+                        Err(e) => warn!("Skip the configuration of {}/{}, {}", app, env, e),
+                    },
+                    Ok(None) => {}
+                    Err(e) => warn!(
+                        "Failed to check the configuration of {}/{}, {}",
+                        app, env, e
+                    ),
+                }
+            }
+        });
+        Ok(rx)
+    }
+
+    /// Call `callback` with the configuration of `app` in `env`, then with every change
+    pub async fn watch_with<T, F>(
+        &self,
+        app: &str,
+        env: &str,
+        interval: Duration,
+        mut callback: F,
+    ) -> anyhow::Result<JoinHandle<()>>
+    where
+        T: DeserializeOwned + Clone + Send + Sync + 'static,
+        F: FnMut(T) + Send + 'static,
+    {
+        let mut rx = self.watch::<T>(app, env, interval).await?;
+        Ok(tokio::spawn(async move {
+            loop {
+                let value = rx.borrow_and_update().clone();
+                callback(value);
+                if rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        }))
+    }
+}
+
+/// The response of a successful request, else an error holding the reason the service gave
+async fn checked(response: Response) -> anyhow::Result<Response> {
+    let status = response.status();
+    if status.is_client_error() {
+        let url = response.url().clone();
+        let reason = response.text().await.unwrap_or_default();
+        return Err(anyhow!("{} {}: {}", status, url, reason));
+    }
+    Ok(response.error_for_status()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::ValueType;
+    use crate::mail::MemoryMailer;
+    use crate::render::render_configuration;
+    use crate::state::AppState;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use axum::routing::get;
+    use axum::Router;
+    use mongodb::bson::oid::ObjectId;
+    use serde_json::json;
+
+    #[derive(Debug, Deserialize, Clone, PartialEq)]
+    struct Db {
+        host: String,
+        port: u16,
+    }
+
+    #[derive(Debug, Deserialize, Clone, PartialEq)]
+    struct Config {
+        db: Db,
+    }
+
+    #[tokio::test]
+    async fn client_test() {
+        let state = AppState::memory(Arc::new(MemoryMailer::default()), "http://cf");
+        let put = |key: &'static str, value: Value| {
+            let state = state.clone();
+            async move {
+                let value_type = ValueType::of(&value).unwrap();
+                state
+                    .configs
+                    .put_item("default", "shop", key, &value, value_type, "admin")
+                    .await
+                    .unwrap();
+            }
+        };
+        put("db.host", json!("a")).await;
+        put("db.port", json!(5432)).await;
+        let app = Router::new().route(
+            "/cf/render/:app/:env",
+            get(render_configuration).with_state(state.clone()),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let server = tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        let cache_dir =
+            std::env::temp_dir().join(format!("cf-client-{}", ObjectId::new().to_hex()));
+        let client = Client::new(&base_url)
+            .with_token(&token)
+            .with_cache_dir(&cache_dir);
+        let config: Config = client.fetch("shop", "prod").await.unwrap();
+        assert_eq!(config.db.host, "a");
+
+        let mut rx = client
+            .watch::<Config>("shop", "prod", Duration::from_millis(20))
+            .await
+            .unwrap();
+        put("db.host", json!("b")).await;
+        tokio::time::timeout(Duration::from_secs(5), rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rx.borrow().db.host, "b");
+
+        // starts from the cache while the service is down
+        server.abort();
+        let _ = server.await;
+        drop(rx);
+        let offline = Client::new(&base_url)
+            .with_token(&token)
+            .with_cache_dir(&cache_dir);
+        let config: Config = offline.fetch("shop", "prod").await.unwrap();
+        assert_eq!(
+            config.db,
+            Db {
+                host: "b".to_string(),
+                port: 5432
+            }
+        );
+        let uncached = Client::new(&base_url).with_token(&token);
+        assert!(uncached.fetch::<Config>("shop", "prod").await.is_err());
+        std::fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn redacted_test() {
+        assert!(has_redacted(&json!({"db": {"hosts": ["a", REDACTED]}})));
+        assert!(!has_redacted(&json!({"db": {"password": "hunter2", "port": 5432}})));
+
+        let path = std::env::temp_dir().join(format!("cf-private-{}", ObjectId::new().to_hex()));
+        std::fs::write(&path, "old").unwrap();
+        write_private(&path, "new").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "new");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,350 @@
+//! JSON Schemas attached to a configuration namespace or to one of its keys.
+//! Every value written must be valid against each schema applying to its key,
+//! and a schema is only accepted when the values already stored are valid against it
+use crate::audit::{snapshot, AuditContext};
+use crate::configuration::{target_tenant, ValueType};
+use crate::flag::flag_errors;
+use crate::repository::internal;
+use crate::resource::Access;
+use crate::schema::Schema;
+use crate::state::AppState;
+use crate::tenant::{default_tenant, validate_tenant};
+use crate::validation::{into_response, FieldError};
+use crate::authorize_resource;
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConfigSchema {
+    pub namespace: String,
+    /// the key the schema applies to, every key of the namespace when unset
+    #[serde(default)]
+    pub key: Option<String>,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    pub schema: Value,
+    pub update_at: DateTime<Utc>,
+    #[serde(default)]
+    pub update_by: String,
+}
+
+/// A schema as stored in MongoDB, as JSON text since `$` keywords are not valid field names there
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ConfigSchemaDB {
+    namespace: String,
+    key: Option<String>,
+    tenant: String,
+    schema: String,
+    update_at: DateTime<Utc>,
+    update_by: String,
+}
+
+impl From<&ConfigSchema> for ConfigSchemaDB {
+    fn from(s: &ConfigSchema) -> Self {
+        ConfigSchemaDB {
+            namespace: s.namespace.clone(),
+            key: s.key.clone(),
+            tenant: s.tenant.clone(),
+            schema: s.schema.to_string(),
+            update_at: s.update_at,
+            update_by: s.update_by.clone(),
+        }
+    }
+}
+
+impl TryFrom<ConfigSchemaDB> for ConfigSchema {
+    type Error = anyhow::Error;
+
+    fn try_from(s: ConfigSchemaDB) -> anyhow::Result<Self> {
+        Ok(ConfigSchema {
+            namespace: s.namespace,
+            key: s.key,
+            tenant: s.tenant,
+            schema: serde_json::from_str(&s.schema)?,
+            update_at: s.update_at,
+            update_by: s.update_by,
+        })
+    }
+}
+
+impl ConfigSchema {
+    pub fn applies_to(&self, key: &str) -> bool {
+        self.key.as_deref().is_none_or(|k| k == key)
+    }
+
+    /// The resource path guarding the schema, `namespace/*` for a namespace wide schema
+    fn resource(namespace: &str, key: Option<&str>) -> (String, String) {
+        (namespace.to_string(), key.unwrap_or("*").to_string())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct SchemaQuery {
+    /// the key of the namespace the schema applies to, the whole namespace when unset
+    pub key: Option<String>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+async fn list_schemas(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+) -> Result<Vec<ConfigSchema>, (StatusCode, String)> {
+    state
+        .configs
+        .list_schemas(tenant, namespace)
+        .await
+        .map_err(internal)
+}
+
+fn compile(schema: &ConfigSchema) -> Result<Schema, (StatusCode, String)> {
+    Schema::new(schema.schema.clone()).map_err(into_response)
+}
+
+/// The violations of `value` against every schema applying to `namespace/key`,
+/// and against the shape of a flag in flag namespaces
+pub(crate) async fn value_errors(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+    key: &str,
+    value: &Value,
+) -> Result<Vec<FieldError>, (StatusCode, String)> {
+    let mut errors = flag_errors(namespace, value);
+    for s in list_schemas(state, tenant, namespace).await? {
+        if s.applies_to(key) {
+            errors.extend(compile(&s)?.validate(value));
+        }
+    }
+    Ok(errors)
+}
+
+/// Check `value` against every schema applying to `namespace/key`, 422 listing the violations
+pub async fn check_value(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+    key: &str,
+    value: &Value,
+) -> Result<(), (StatusCode, String)> {
+    let errors = value_errors(state, tenant, namespace, key, value).await?;
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(into_response(errors))
+    }
+}
+
+/// Validate the stored values a schema would apply to, but the sealed secrets,
+/// errors are reported as `namespace/key#pointer`
+async fn check_existing(
+    state: &AppState,
+    schema: &ConfigSchema,
+    compiled: &Schema,
+) -> Result<(), (StatusCode, String)> {
+    let items = state
+        .configs
+        .list_items(&schema.tenant, Some(&schema.namespace), &Map::new())
+        .await
+        .map_err(internal)?;
+    let mut errors = Vec::new();
+    let checked = items
+        .iter()
+        .filter(|i| schema.applies_to(&i.key) && i.value_type != ValueType::Secret);
+    for item in checked {
+        for e in compiled.validate(&item.value) {
+            errors.push(FieldError {
+                field: format!("{}/{}#{}", item.namespace, item.key, e.field),
+                message: e.message,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(into_response(errors))
+    }
+}
+
+/// The schemas attached to a namespace and to its keys
+pub async fn get_config_schemas(
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<SchemaQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let (ns, key) = ConfigSchema::resource(&namespace, query.key.as_deref());
+    let p = authorize_resource(&headers, &state, "get_config_schemas", Access::Read, &ns, &key)
+        .await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let schemas: Vec<ConfigSchema> = list_schemas(&state, &tenant, &namespace)
+        .await?
+        .into_iter()
+        .filter(|s| query.key.is_none() || s.key == query.key)
+        .collect();
+    Ok(serde_json::to_string(&schemas).unwrap())
+}
+
+/// Attach a schema to a namespace or to a key, replacing the previous one.
+/// Refused with 422 when the schema is malformed or a stored value violates it
+pub async fn put_config_schema(
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<SchemaQuery>,
+    Json(payload): Json<Value>,
+) -> Result<String, (StatusCode, String)> {
+    let (ns, key) = ConfigSchema::resource(&namespace, query.key.as_deref());
+    let p = authorize_resource(&headers, &state, "put_config_schema", Access::Write, &ns, &key)
+        .await?;
+    let target = format!("{}/{}", ns, key);
+    let audit = AuditContext::new(&headers, &p.profile, "put_config_schema", &target);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    validate_tenant(&state, &tenant).await?;
+    let schema = ConfigSchema {
+        namespace: namespace.clone(),
+        key: query.key.clone(),
+        tenant,
+        schema: payload,
+        update_at: Utc::now(),
+        update_by: p.profile.user_base.name.clone(),
+    };
+    let compiled = compile(&schema)?;
+    check_existing(&state, &schema, &compiled).await?;
+    let before = list_schemas(&state, &schema.tenant, &namespace)
+        .await?
+        .into_iter()
+        .find(|s| s.key == schema.key);
+    let res = state
+        .configs
+        .put_schema(&schema)
+        .await
+        .map(|_| serde_json::to_string(&schema).unwrap())
+        .map_err(internal);
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), snapshot(&schema))
+        .await;
+    res
+}
+
+pub async fn delete_config_schema(
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<SchemaQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let (ns, key) = ConfigSchema::resource(&namespace, query.key.as_deref());
+    let p = authorize_resource(&headers, &state, "delete_config_schema", Access::Write, &ns, &key)
+        .await?;
+    let target = format!("{}/{}", ns, key);
+    let audit = AuditContext::new(&headers, &p.profile, "delete_config_schema", &target);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let before = list_schemas(&state, &tenant, &namespace)
+        .await?
+        .into_iter()
+        .find(|s| s.key == query.key);
+    let res = match &before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => state
+            .configs
+            .delete_schema(&tenant, &namespace, query.key.as_deref())
+            .await
+            .map(|r| serde_json::to_string(&r).unwrap())
+            .map_err(internal),
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::{put_configuration, ConfigurationQuery, ConfigurationValue};
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn schema_enforced_test() {
+        let state = State(AppState::memory(Arc::new(MemoryMailer::default()), "http://cf"));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let put = |path: &str, value: Value| {
+            put_configuration(
+                headers.clone(),
+                Path(path.to_string()),
+                state.clone(),
+                Query(ConfigurationQuery::default()),
+                Json(ConfigurationValue {
+                    value,
+                    value_type: None,
+                }),
+            )
+        };
+        let key = |key: Option<&str>| {
+            Query(SchemaQuery {
+                key: key.map(|k| k.to_string()),
+                tenant: None,
+            })
+        };
+        put("app/db", json!({"host": "db", "port": 80})).await.unwrap();
+
+        // the stored value has no user, the schema is refused
+        let strict = json!({"type": "object", "required": ["host", "user"]});
+        let refused = put_config_schema(
+            headers.clone(),
+            Path("app".to_string()),
+            state.clone(),
+            key(Some("db")),
+            Json(strict),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(refused.0, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors: Vec<FieldError> = serde_json::from_str(&refused.1).unwrap();
+        assert_eq!(errors[0].field, "app/db#/user");
+
+        let port = json!({"properties": {"port": {"type": "integer", "maximum": 65535}}});
+        put_config_schema(
+            headers.clone(),
+            Path("app".to_string()),
+            state.clone(),
+            key(None),
+            Json(port),
+        )
+        .await
+        .unwrap();
+        let rejected = put("app/db", json!({"host": "db", "port": 70000})).await.unwrap_err();
+        assert_eq!(rejected.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(rejected.1.contains("/port"));
+        put("app/db", json!({"host": "db", "port": 8080})).await.unwrap();
+
+        let malformed = put_config_schema(
+            headers.clone(),
+            Path("app".to_string()),
+            state.clone(),
+            key(None),
+            Json(json!({"type": 1})),
+        )
+        .await;
+        assert_eq!(malformed.unwrap_err().0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        delete_config_schema(headers.clone(), Path("app".to_string()), state.clone(), key(None))
+            .await
+            .unwrap();
+        put("app/db", json!({"port": "any"})).await.unwrap();
+    }
+}
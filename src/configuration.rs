@@ -0,0 +1,589 @@
+//! The configuration store: values under `namespace/key`, per tenant, with revisions and history
+use crate::audit::{snapshot, AuditContext};
+use crate::changeset::check_unreviewed;
+use crate::config_schema::check_value;
+use crate::document::{check_field_names, parse_filter};
+use crate::repository::{internal, DeleteResult};
+use crate::resource::{resource_path, Access};
+use crate::secret::{MasterKey, SealedSecret, REDACTED};
+use crate::state::AppState;
+use crate::tenant::{default_tenant, validate_tenant};
+use crate::validation::into_response;
+use crate::{authorize_resource, principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tracing::error;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ConfigurationItems {
+    pub namespace: String,
+    pub key: String,
+    pub value: Value,
+    #[serde(rename = "type", default)]
+    pub value_type: ValueType,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    #[serde(default)]
+    pub revision: i64,
+    pub update_at: DateTime<Utc>,
+    #[serde(default)]
+    pub update_by: String,
+}
+
+/// The declared type of a configuration value
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ValueType {
+    #[default]
+    String,
+    Number,
+    Bool,
+    Object,
+    List,
+    /// a string not to be disclosed
+    Secret,
+}
+
+impl ValueType {
+    /// The type of a JSON value, `None` for null
+    pub fn of(value: &Value) -> Option<ValueType> {
+        match value {
+            Value::Null => None,
+            Value::Bool(_) => Some(ValueType::Bool),
+            Value::Number(_) => Some(ValueType::Number),
+            Value::String(_) => Some(ValueType::String),
+            Value::Array(_) => Some(ValueType::List),
+            Value::Object(_) => Some(ValueType::Object),
+        }
+    }
+
+    /// Whether `value` is of this type, secrets are strings
+    pub fn admits(self, value: &Value) -> bool {
+        match self {
+            ValueType::Secret => value.is_string(),
+            t => ValueType::of(value) == Some(t),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeOp {
+    Put,
+    Delete,
+}
+
+impl ConfigurationItems {
+    /// The item with the value of a secret replaced by a placeholder
+    pub fn redacted(mut self) -> Self {
+        if self.value_type == ValueType::Secret {
+            self.value = Value::String(REDACTED.to_string());
+        }
+        self
+    }
+}
+
+/// One revision of a key
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigurationHistory {
+    pub op: ChangeOp,
+    /// the id shared by the revisions of the keys written together, by an import for instance
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub change: Option<String>,
+    #[serde(flatten)]
+    pub item: ConfigurationItems,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ConfigurationValue {
+    pub value: Value,
+    /// the type of the value, taken from the JSON value when unset
+    #[serde(rename = "type")]
+    pub value_type: Option<ValueType>,
+}
+
+impl ConfigurationValue {
+    /// The declared type, 400 when it does not match the value
+    pub(crate) fn declared_type(&self) -> Result<ValueType, (StatusCode, String)> {
+        match self.value_type {
+            Some(t) if t.admits(&self.value) => Ok(t),
+            Some(t) => Err((
+                StatusCode::BAD_REQUEST,
+                format!("Value is not of type {:?}", t),
+            )),
+            None => ValueType::of(&self.value)
+                .ok_or((StatusCode::BAD_REQUEST, "Value must not be null".to_string())),
+        }
+    }
+}
+
+/// Query options common to configuration endpoints
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ConfigurationQuery {
+    pub namespace: Option<String>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+    /// a JSON object of the values the fields of listed values must hold, keyed by dotted paths
+    pub filter: Option<String>,
+    /// return the plain value of a secret, needs reveal access
+    pub reveal: Option<bool>,
+}
+
+/// Split `namespace/key`, the namespace may itself contain `/`
+pub(crate) fn split_path(path: &str) -> Result<(&str, &str), (StatusCode, String)> {
+    match path.trim_matches('/').rsplit_once('/') {
+        Some((namespace, key)) if !namespace.is_empty() && !key.is_empty() => Ok((namespace, key)),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid configuration path: {}", path),
+        )),
+    }
+}
+
+/// The tenant a configuration request works on
+pub(crate) fn target_tenant(p: &Principal, requested: Option<&str>) -> Result<String, (StatusCode, String)> {
+    match (requested, p.tenant_scope()) {
+        (Some(t), Some(scope)) if t != scope => Err((
+            StatusCode::FORBIDDEN,
+            format!("Not allowed to access tenant {}", t),
+        )),
+        (Some(t), _) => Ok(t.to_string()),
+        (None, _) => Ok(p.profile.user_base.tenant.clone()),
+    }
+}
+
+pub(crate) async fn find_item(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+    key: &str,
+) -> Result<Option<ConfigurationItems>, (StatusCode, String)> {
+    state
+        .configs
+        .find_item(tenant, namespace, key)
+        .await
+        .map_err(internal)
+}
+
+/// The context sealed secrets are bound to
+fn secret_context(tenant: &str, namespace: &str, key: &str) -> String {
+    format!("{}/{}/{}", tenant, namespace, key)
+}
+
+fn master_key(state: &AppState) -> Result<&MasterKey, (StatusCode, String)> {
+    state.master_key.as_deref().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "Secrets are disabled, no master key is configured".to_string(),
+    ))
+}
+
+/// Seal the plain value of a secret before it is stored
+pub(crate) fn seal(
+    state: &AppState,
+    tenant: &str,
+    namespace: &str,
+    key: &str,
+    value: &Value,
+) -> Result<Value, (StatusCode, String)> {
+    let plain = value.as_str().unwrap_or_default();
+    let sealed = master_key(state)?
+        .seal(plain, &secret_context(tenant, namespace, key))
+        .map_err(internal)?;
+    Ok(serde_json::to_value(sealed).unwrap())
+}
+
+/// Replace the sealed value of a secret by its plain value
+pub(crate) fn reveal(
+    state: &AppState,
+    item: &mut ConfigurationItems,
+) -> Result<(), (StatusCode, String)> {
+    if item.value_type != ValueType::Secret {
+        return Ok(());
+    }
+    let sealed: SealedSecret =
+        serde_json::from_value(item.value.clone()).map_err(|e| internal(e.into()))?;
+    let context = secret_context(&item.tenant, &item.namespace, &item.key);
+    let plain = master_key(state)?.open(&sealed, &context).map_err(internal)?;
+    item.value = Value::String(plain);
+    Ok(())
+}
+
+pub(crate) async fn append_history(
+    state: &AppState,
+    op: ChangeOp,
+    item: &ConfigurationItems,
+    change: Option<&str>,
+) {
+    let h = ConfigurationHistory {
+        op,
+        change: change.map(str::to_string),
+        item: item.clone(),
+    };
+    if let Err(e) = state.configs.append_history(&h).await {
+        error!("write configuration history failed, {:?}", e);
+    }
+}
+
+/// List the keys of a namespace the caller can read
+pub async fn list_configurations(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let namespace = query.namespace.clone().unwrap_or_default();
+    let p = principal(&headers, &state, "list_configurations", &namespace).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let namespace = Some(namespace.as_str()).filter(|n| !n.is_empty());
+    let filter = parse_filter(query.filter.as_deref())?;
+    let items: Vec<ConfigurationItems> = state
+        .configs
+        .list_items(&tenant, namespace, &filter)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .filter(|item| p.can_access(Access::Read, &resource_path(&item.namespace, &item.key)))
+        .map(ConfigurationItems::redacted)
+        .collect();
+    Ok(serde_json::to_string(&items).unwrap())
+}
+
+/// The value of a key, secrets are redacted unless revealed with `reveal=true`
+pub async fn get_configuration(
+    headers: HeaderMap,
+    Path(path): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let (namespace, key) = split_path(&path)?;
+    if query.reveal == Some(true) {
+        return reveal_configuration(&headers, &state, &path, &query).await;
+    }
+    let p = authorize_resource(&headers, &state, "get_configuration", Access::Read, namespace, key)
+        .await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    match find_item(&state, &tenant, namespace, key).await? {
+        Some(item) => Ok(serde_json::to_string(&item.redacted()).unwrap()),
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+    }
+}
+
+/// The plain value of a key, every reveal is audited
+async fn reveal_configuration(
+    headers: &HeaderMap,
+    state: &AppState,
+    path: &str,
+    query: &ConfigurationQuery,
+) -> Result<String, (StatusCode, String)> {
+    let (namespace, key) = split_path(path)?;
+    let p = authorize_resource(headers, state, "reveal_configuration", Access::Reveal, namespace, key)
+        .await?;
+    let audit = AuditContext::new(headers, &p.profile, "reveal_configuration", path);
+    let res = match target_tenant(&p, query.tenant.as_deref()) {
+        Ok(tenant) => match find_item(state, &tenant, namespace, key).await {
+            Ok(Some(mut item)) => {
+                reveal(state, &mut item).map(|_| serde_json::to_string(&item).unwrap())
+            }
+            Ok(None) => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(e),
+    };
+    audit.record(state, &res, None, None).await;
+    res
+}
+
+/// Create or replace the value of a key, bumping its revision
+pub async fn put_configuration(
+    headers: HeaderMap,
+    Path(path): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+    Json(payload): Json<ConfigurationValue>,
+) -> Result<String, (StatusCode, String)> {
+    let (namespace, key) = split_path(&path)?;
+    let p = authorize_resource(&headers, &state, "put_configuration", Access::Write, namespace, key)
+        .await?;
+    let audit = AuditContext::new(&headers, &p.profile, "put_configuration", &path);
+    let res = store_value(&state, &p, &query, namespace, key, payload).await;
+    let before = res.as_ref().ok().and_then(|(before, _)| before.as_ref()).and_then(snapshot);
+    let res = res.map(|(_, item)| item);
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|item| serde_json::to_string(&item).unwrap());
+    audit.record(&state, &res, before, after).await;
+    res
+}
+
+/// Check and store the value of `put_configuration`, giving the redacted item before and after
+async fn store_value(
+    state: &AppState,
+    p: &Principal,
+    query: &ConfigurationQuery,
+    namespace: &str,
+    key: &str,
+    payload: ConfigurationValue,
+) -> Result<(Option<ConfigurationItems>, ConfigurationItems), (StatusCode, String)> {
+    check_unreviewed(state, namespace)?;
+    let tenant = target_tenant(p, query.tenant.as_deref())?;
+    validate_tenant(state, &tenant).await?;
+    let value_type = payload.declared_type()?;
+    let mut errors = Vec::new();
+    check_field_names(&payload.value, "", &mut errors);
+    if !errors.is_empty() {
+        return Err(into_response(errors));
+    }
+    check_value(state, &tenant, namespace, key, &payload.value).await?;
+    let value = match value_type {
+        ValueType::Secret => seal(state, &tenant, namespace, key, &payload.value)?,
+        _ => payload.value,
+    };
+    let before = find_item(state, &tenant, namespace, key)
+        .await?
+        .map(ConfigurationItems::redacted);
+    let item = state
+        .configs
+        .put_item(
+            &tenant,
+            namespace,
+            key,
+            &value,
+            value_type,
+            &p.profile.user_base.name,
+        )
+        .await
+        .map_err(internal)?;
+    append_history(state, ChangeOp::Put, &item, None).await;
+    Ok((before, item.redacted()))
+}
+
+pub async fn delete_configuration(
+    headers: HeaderMap,
+    Path(path): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let (namespace, key) = split_path(&path)?;
+    let p = authorize_resource(&headers, &state, "delete_configuration", Access::Write, namespace, key)
+        .await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_configuration", &path);
+    let res = remove_value(&state, &p, &query, namespace, key).await;
+    let before = res.as_ref().ok().and_then(|(before, _)| snapshot(before));
+    let res = res.map(|(_, r)| serde_json::to_string(&r).unwrap());
+    audit.record(&state, &res, before, None).await;
+    res
+}
+
+/// Check and delete the key of `delete_configuration`, giving the redacted item deleted
+async fn remove_value(
+    state: &AppState,
+    p: &Principal,
+    query: &ConfigurationQuery,
+    namespace: &str,
+    key: &str,
+) -> Result<(ConfigurationItems, DeleteResult), (StatusCode, String)> {
+    check_unreviewed(state, namespace)?;
+    let tenant = target_tenant(p, query.tenant.as_deref())?;
+    let Some(item) = find_item(state, &tenant, namespace, key).await? else {
+        return Err((StatusCode::NOT_FOUND, "Not Found".to_string()));
+    };
+    let r = state
+        .configs
+        .delete_item(&tenant, namespace, key)
+        .await
+        .map_err(internal)?;
+    let mut deleted = item.clone();
+    deleted.revision += 1;
+    deleted.update_at = Utc::now();
+    deleted.update_by = p.profile.user_base.name.clone();
+    append_history(state, ChangeOp::Delete, &deleted, None).await;
+    Ok((item.redacted(), r))
+}
+
+/// Every revision of a key, newest first, secrets redacted
+pub async fn get_configuration_history(
+    headers: HeaderMap,
+    Path(path): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let (namespace, key) = split_path(&path)?;
+    let p = authorize_resource(
+        &headers,
+        &state,
+        "get_configuration_history",
+        Access::Read,
+        namespace,
+        key,
+    )
+    .await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let history: Vec<ConfigurationHistory> = state
+        .configs
+        .list_history(&tenant, namespace, key)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(|h| ConfigurationHistory {
+            item: h.item.redacted(),
+            ..h
+        })
+        .collect();
+    Ok(serde_json::to_string(&history).unwrap())
+}
+
+/// The revisions written together as one change, sorted by namespace and key, secrets redacted
+pub async fn get_configuration_change(
+    headers: HeaderMap,
+    Path(change): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ConfigurationQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_configuration_change", &change).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let history: Vec<ConfigurationHistory> = state
+        .configs
+        .list_change(&tenant, &change)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .filter(|h| p.can_access(Access::Read, &resource_path(&h.item.namespace, &h.item.key)))
+        .map(|h| ConfigurationHistory {
+            item: h.item.redacted(),
+            ..h
+        })
+        .collect();
+    if history.is_empty() {
+        return Err((StatusCode::NOT_FOUND, "Not Found".to_string()));
+    }
+    Ok(serde_json::to_string(&history).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::{AuditQuery, Outcome};
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::{stored_headers, UserBase, UserProfile};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn split_path_test() {
+        assert_eq!(split_path("app-a/db.host").unwrap(), ("app-a", "db.host"));
+        assert_eq!(
+            split_path("app-a/prod/db.host").unwrap(),
+            ("app-a/prod", "db.host")
+        );
+        assert!(split_path("db.host").is_err());
+        assert!(split_path("app-a/").is_err());
+    }
+
+    #[test]
+    fn declared_type_test() {
+        let value: ConfigurationValue = serde_json::from_str(r#"{"value":{"port":80}}"#).unwrap();
+        assert_eq!(value.declared_type().unwrap(), ValueType::Object);
+        let value: ConfigurationValue =
+            serde_json::from_str(r#"{"value":"s3cr3t","type":"secret"}"#).unwrap();
+        assert_eq!(value.declared_type().unwrap(), ValueType::Secret);
+        let value: ConfigurationValue =
+            serde_json::from_str(r#"{"value":80,"type":"string"}"#).unwrap();
+        assert_eq!(value.declared_type().unwrap_err().0, StatusCode::BAD_REQUEST);
+        let value: ConfigurationValue = serde_json::from_str(r#"{"value":null}"#).unwrap();
+        assert!(value.declared_type().is_err());
+    }
+
+    #[tokio::test]
+    async fn secret_test() {
+        let state = State(
+            AppState::memory(Arc::new(MemoryMailer::default()), "http://cf")
+                .with_master_key(MasterKey::new(&[9u8; 32]).unwrap()),
+        );
+        let headers_of = |profile: &UserProfile| {
+            let mut headers = HeaderMap::new();
+            let token = generate_token(profile, 60).unwrap();
+            headers.insert("authorization", token.parse().unwrap());
+            headers
+        };
+        let admin = headers_of(&UserProfile::default_super());
+        let path = || Path("app/db.password".to_string());
+        let query = |reveal: bool| {
+            Query(ConfigurationQuery {
+                reveal: Some(reveal),
+                ..Default::default()
+            })
+        };
+        let value = ConfigurationValue {
+            value: json!("hunter2"),
+            value_type: Some(ValueType::Secret),
+        };
+        let put = put_configuration(admin.clone(), path(), state.clone(), query(false), Json(value))
+            .await
+            .unwrap();
+        assert!(!put.contains("hunter2"));
+        let stored = find_item(&state, "default", "app", "db.password").await.unwrap().unwrap();
+        assert!(!stored.value.to_string().contains("hunter2"));
+        let invalid = ConfigurationValue {
+            value: json!(80),
+            value_type: Some(ValueType::Secret),
+        };
+        let rejected =
+            put_configuration(admin.clone(), path(), state.clone(), query(false), Json(invalid))
+                .await;
+        assert!(rejected.is_err());
+
+        let got = get_configuration(admin.clone(), path(), state.clone(), query(false))
+            .await
+            .unwrap();
+        assert!(got.contains(REDACTED));
+        let listed = list_configurations(admin.clone(), state.clone(), query(false))
+            .await
+            .unwrap();
+        assert!(listed.contains(REDACTED) && !listed.contains(&stored.value.to_string()));
+        let history = get_configuration_history(admin.clone(), path(), state.clone(), query(false))
+            .await
+            .unwrap();
+        assert!(history.contains(REDACTED));
+
+        // admins need an explicit grant to reveal
+        let denied = get_configuration(admin.clone(), path(), state.clone(), query(true)).await;
+        assert_eq!(denied.unwrap_err().0, StatusCode::FORBIDDEN);
+        let revealer = UserBase {
+            name: "revealer".to_string(),
+            roles: vec!["admin".to_string()],
+            permissions: vec!["config:reveal:app/*".to_string()],
+            ..Default::default()
+        };
+        let revealer = stored_headers(&state, revealer).await;
+        let revealed = get_configuration(revealer, path(), state.clone(), query(true))
+            .await
+            .unwrap();
+        let item: ConfigurationItems = serde_json::from_str(&revealed).unwrap();
+        assert_eq!(item.value, json!("hunter2"));
+
+        let records = state.audit.search_audit(&AuditQuery::default(), None).await.unwrap();
+        assert!(records.iter().any(|r| r.action == "reveal_configuration"));
+        let puts = records.iter().filter(|r| r.action == "put_configuration");
+        assert_eq!(puts.clone().count(), 2, "rejected puts are audited too");
+        assert!(puts.clone().any(|r| r.outcome == Outcome::Failure));
+        assert!(!serde_json::to_string(&records).unwrap().contains("hunter2"));
+
+        let delete = || delete_configuration(admin.clone(), path(), state.clone(), query(false));
+        delete().await.unwrap();
+        assert_eq!(delete().await.unwrap_err().0, StatusCode::NOT_FOUND);
+        let query = AuditQuery {
+            action: Some("delete_configuration".to_string()),
+            ..Default::default()
+        };
+        let deletes = state.audit.search_audit(&query, None).await.unwrap();
+        assert_eq!(deletes.len(), 2, "refused deletes are audited too");
+        assert!(deletes.iter().any(|r| r.outcome == Outcome::Failure && r.before.is_none()));
+        assert!(deletes.iter().any(|r| r.outcome == Outcome::Success && r.before.is_some()));
+    }
+}
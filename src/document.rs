@@ -0,0 +1,391 @@
+//! Admin only REST access to schemaless JSON documents of the collections allowlisted
+//! in the `documents` section of the configuration, each checked by its collection's JSON Schema
+use crate::audit::{snapshot, AuditContext};
+use crate::repository::internal;
+use crate::schema::Schema;
+use crate::state::AppState;
+use crate::validation::{into_response, FieldError};
+use crate::admin_check;
+use anyhow::anyhow;
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fs::read_to_string;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+/// A collection open to the document API
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DocumentCollection {
+    /// documents must be valid against it, any object is accepted without
+    pub schema: Option<Schema>,
+}
+
+impl DocumentCollection {
+    /// Load the JSON Schema of the collection `name` from the file `schema`
+    pub fn load(name: &str, schema: Option<&str>) -> anyhow::Result<Self> {
+        if !is_collection_name(name) {
+            return Err(anyhow!("Invalid document collection name: {}", name));
+        }
+        let schema = match schema {
+            Some(path) => {
+                let text = read_to_string(path)?;
+                let schema = Schema::parse(&text)
+                    .map_err(|e| anyhow!("Invalid schema {} of {}: {}", path, name, e.1))?;
+                Some(schema)
+            }
+            None => None,
+        };
+        Ok(DocumentCollection { schema })
+    }
+}
+
+fn is_collection_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 64
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[derive(Debug, Serialize)]
+struct CollectionInfo<'a> {
+    name: &'a str,
+    schema: Option<&'a Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct DocumentQuery {
+    pub skip: Option<u64>,
+    pub limit: Option<i64>,
+    /// a JSON object of the values documents must hold, keyed by dotted field paths
+    pub filter: Option<String>,
+}
+
+fn find_collection<'a>(
+    state: &'a AppState,
+    name: &str,
+) -> Result<&'a DocumentCollection, (StatusCode, String)> {
+    state
+        .collections
+        .get(name)
+        .ok_or((StatusCode::NOT_FOUND, format!("Unknown collection: {}", name)))
+}
+
+/// Field names starting with `$` are operators to MongoDB, refuse them at any depth
+pub(crate) fn check_field_names(value: &Value, path: &str, errors: &mut Vec<FieldError>) {
+    match value {
+        Value::Object(fields) => {
+            for (name, v) in fields {
+                let at = format!("{}/{}", path, name);
+                if name.starts_with('$') {
+                    errors.push(FieldError {
+                        field: at,
+                        message: "must not start with $".to_string(),
+                    });
+                } else {
+                    check_field_names(v, &at, errors);
+                }
+            }
+        }
+        Value::Array(items) => {
+            for (i, v) in items.iter().enumerate() {
+                check_field_names(v, &format!("{}/{}", path, i), errors);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Check a submitted document, dropping its `_id` which is never written
+fn prepare(
+    collection: &DocumentCollection,
+    payload: Value,
+) -> Result<Map<String, Value>, (StatusCode, String)> {
+    let Value::Object(mut document) = payload else {
+        return Err(into_response(vec![FieldError {
+            field: "/".to_string(),
+            message: "must be an object".to_string(),
+        }]));
+    };
+    document.remove("_id");
+    let document = Value::Object(document);
+    let mut errors = Vec::new();
+    check_field_names(&document, "", &mut errors);
+    if let Some(schema) = &collection.schema {
+        errors.extend(schema.validate(&document));
+    }
+    if !errors.is_empty() {
+        return Err(into_response(errors));
+    }
+    match document {
+        Value::Object(document) => Ok(document),
+        _ => unreachable!(),
+    }
+}
+
+/// Parse the filter of a listing, an object of scalar values
+pub(crate) fn parse_filter(filter: Option<&str>) -> Result<Map<String, Value>, (StatusCode, String)> {
+    let Some(filter) = filter else {
+        return Ok(Map::new());
+    };
+    let bad = |message: String| (StatusCode::BAD_REQUEST, message);
+    let fields: Map<String, Value> = serde_json::from_str(filter)
+        .map_err(|e| bad(format!("Filter is not a JSON object: {}", e)))?;
+    for (name, value) in &fields {
+        if name.is_empty() || name.starts_with('$') {
+            return Err(bad(format!("Invalid filter field: {}", name)));
+        }
+        if value.is_object() || value.is_array() {
+            return Err(bad(format!("Filter value of {} must be a scalar", name)));
+        }
+    }
+    Ok(fields)
+}
+
+/// The allowlisted collections and their schemas
+pub async fn list_collections(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    admin_check(&headers, &state, "list_collections", "").await?;
+    let collections: Vec<CollectionInfo> = state
+        .collections
+        .iter()
+        .map(|(name, c)| CollectionInfo {
+            name,
+            schema: c.schema.as_ref().map(Schema::as_value),
+        })
+        .collect();
+    Ok(serde_json::to_string(&collections).unwrap())
+}
+
+pub async fn list_documents(
+    headers: HeaderMap,
+    Path(collection): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<DocumentQuery>,
+) -> Result<String, (StatusCode, String)> {
+    admin_check(&headers, &state, "list_documents", &collection).await?;
+    find_collection(&state, &collection)?;
+    let filter = parse_filter(query.filter.as_deref())?;
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let documents = state
+        .documents
+        .list_documents(&collection, &filter, query.skip.unwrap_or(0), limit)
+        .await
+        .map_err(internal)?;
+    Ok(serde_json::to_string(&documents).unwrap())
+}
+
+pub async fn get_document(
+    headers: HeaderMap,
+    Path((collection, id)): Path<(String, String)>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    admin_check(&headers, &state, "get_document", &collection).await?;
+    find_collection(&state, &collection)?;
+    match state
+        .documents
+        .find_document(&collection, &id)
+        .await
+        .map_err(internal)?
+    {
+        Some(document) => Ok(serde_json::to_string(&document).unwrap()),
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+    }
+}
+
+/// Store a new document, returning its id
+pub async fn create_document(
+    headers: HeaderMap,
+    Path(collection): Path<String>,
+    state: State<AppState>,
+    Json(payload): Json<Value>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "create_document", &collection).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_document", &collection);
+    let document = prepare(find_collection(&state, &collection)?, payload)?;
+    let res = state
+        .documents
+        .insert_document(&collection, &document)
+        .await
+        .map_err(internal);
+    audit.record(&state, &res, None, snapshot(&document)).await;
+    res
+}
+
+/// Replace every field of a document
+pub async fn replace_document(
+    headers: HeaderMap,
+    Path((collection, id)): Path<(String, String)>,
+    state: State<AppState>,
+    Json(payload): Json<Value>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "replace_document", &collection).await?;
+    let target = format!("{}/{}", collection, id);
+    let audit = AuditContext::new(&headers, &p.profile, "replace_document", &target);
+    let document = prepare(find_collection(&state, &collection)?, payload)?;
+    let before = state
+        .documents
+        .find_document(&collection, &id)
+        .await
+        .map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => state
+            .documents
+            .replace_document(&collection, &id, &document)
+            .await
+            .map(|r| serde_json::to_string(&r).unwrap())
+            .map_err(internal),
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), snapshot(&document))
+        .await;
+    res
+}
+
+pub async fn delete_document(
+    headers: HeaderMap,
+    Path((collection, id)): Path<(String, String)>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "delete_document", &collection).await?;
+    let target = format!("{}/{}", collection, id);
+    let audit = AuditContext::new(&headers, &p.profile, "delete_document", &target);
+    find_collection(&state, &collection)?;
+    let before = state
+        .documents
+        .find_document(&collection, &id)
+        .await
+        .map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => state
+            .documents
+            .delete_document(&collection, &id)
+            .await
+            .map(|r| serde_json::to_string(&r).unwrap())
+            .map_err(internal),
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use serde_json::json;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn document_api_test() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": {"name": {"type": "string"}, "weight": {"type": "integer"}}
+        }))
+        .unwrap();
+        let collections = BTreeMap::from([(
+            "feature".to_string(),
+            DocumentCollection {
+                schema: Some(schema),
+            },
+        )]);
+        let state = State(
+            AppState::memory(Arc::new(MemoryMailer::default()), "http://cf")
+                .with_collections(collections),
+        );
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let path = |s: &str| Path(s.to_string());
+
+        let invalid = create_document(
+            headers.clone(),
+            path("feature"),
+            state.clone(),
+            Json(json!({"weight": "heavy"})),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(invalid.0, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors: Vec<FieldError> = serde_json::from_str(&invalid.1).unwrap();
+        assert_eq!(errors.len(), 2);
+        let unknown =
+            create_document(headers.clone(), path("user"), state.clone(), Json(json!({}))).await;
+        assert_eq!(unknown.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let id = create_document(
+            headers.clone(),
+            path("feature"),
+            state.clone(),
+            Json(json!({"name": "dark-mode", "weight": 1})),
+        )
+        .await
+        .unwrap();
+        let ids = || Path(("feature".to_string(), id.clone()));
+        replace_document(
+            headers.clone(),
+            ids(),
+            state.clone(),
+            Json(json!({"_id": "ignored", "name": "dark-mode", "weight": 2})),
+        )
+        .await
+        .unwrap();
+        let found = get_document(headers.clone(), ids(), state.clone()).await.unwrap();
+        let found: Value = serde_json::from_str(&found).unwrap();
+        assert_eq!(found, json!({"_id": id, "name": "dark-mode", "weight": 2}));
+
+        let query = |filter: &str| {
+            Query(DocumentQuery {
+                filter: Some(filter.to_string()),
+                ..Default::default()
+            })
+        };
+        let listed = list_documents(
+            headers.clone(),
+            path("feature"),
+            state.clone(),
+            query(r#"{"weight": 2}"#),
+        )
+        .await
+        .unwrap();
+        assert_eq!(serde_json::from_str::<Vec<Value>>(&listed).unwrap().len(), 1);
+        let operator = list_documents(
+            headers.clone(),
+            path("feature"),
+            state.clone(),
+            query(r#"{"weight": {"$gt": 1}}"#),
+        )
+        .await;
+        assert_eq!(operator.unwrap_err().0, StatusCode::BAD_REQUEST);
+
+        delete_document(headers.clone(), ids(), state.clone()).await.unwrap();
+        let gone = get_document(headers.clone(), ids(), state.clone()).await;
+        assert_eq!(gone.unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[test]
+    fn prepare_test() {
+        let open = DocumentCollection::default();
+        let errors = prepare(&open, json!({"a": [{"$where": "1"}]})).unwrap_err();
+        assert!(errors.1.contains("/a/0/$where"));
+        assert!(prepare(&open, json!([1])).is_err());
+        assert!(is_collection_name("feature_flags"));
+        assert!(!is_collection_name("a.b"));
+    }
+}
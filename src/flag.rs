@@ -0,0 +1,465 @@
+//! Feature flags, stored as object values of the namespace `flags/<app>`, one key per flag.
+//! They are written through the configuration API like any value, and so have history,
+//! review and scheduling. A flag serves one of its variations: the `off` one when disabled,
+//! else the one of the first rule matching the evaluation context, else its `default`.
+//! A rule or the default may roll variations out by weight, the user of the context
+//! landing in the same bucket of a flag every time
+use crate::configuration::target_tenant;
+use crate::principal;
+use crate::repository::internal;
+use crate::resource::{glob_match, resource_path, Access};
+use crate::state::AppState;
+use crate::validation::FieldError;
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// The prefix of the namespaces holding flags, followed by the application
+pub const FLAG_PREFIX: &str = "flags/";
+
+/// Rollout buckets, weights being percentages this gives them a precision of 0.01
+const BUCKETS: u64 = 10_000;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Flag {
+    #[serde(default)]
+    pub description: String,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// the values served, `[false, true]` for a boolean flag
+    #[serde(default = "default_variations")]
+    pub variations: Vec<Value>,
+    /// the index of the variation served while the flag is disabled
+    #[serde(default)]
+    pub off: usize,
+    /// tried in order, the first matching rule serves
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+    /// served when no rule matches
+    pub default: Serve,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_variations() -> Vec<Value> {
+    vec![Value::Bool(false), Value::Bool(true)]
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Rule {
+    /// all must hold for the rule to match
+    pub conditions: Vec<Condition>,
+    pub serve: Serve,
+}
+
+/// A variation, or variations by weight like `{"rollout": [{"variation": 1, "weight": 10}, ...]}`
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Serve {
+    Variation(usize),
+    Rollout(Vec<Weighted>),
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Weighted {
+    pub variation: usize,
+    /// the percentage of users served, the weights of a rollout sum to 100
+    pub weight: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Condition {
+    /// `user`, `tenant`, `role`, `group` or the name of a custom attribute
+    pub attribute: String,
+    pub op: Operator,
+    pub values: Vec<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Operator {
+    /// one value of the attribute is one of `values`
+    In,
+    /// no value of the attribute is one of `values`
+    NotIn,
+    /// one string value of the attribute matches one of the patterns of `values`,
+    /// `*` matching any sequence of characters
+    Matches,
+}
+
+/// Whom flags are evaluated for
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EvaluationContext {
+    /// the id rollouts hash, the same user getting the same variation
+    pub user: String,
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// custom attributes, a list holding several values of an attribute
+    #[serde(default)]
+    pub attributes: Map<String, Value>,
+}
+
+impl EvaluationContext {
+    /// The values of an attribute
+    fn values(&self, attribute: &str) -> Vec<Value> {
+        let strings = |v: &[String]| v.iter().cloned().map(Value::String).collect();
+        match attribute {
+            "user" => vec![Value::String(self.user.clone())],
+            "tenant" => self.tenant.iter().cloned().map(Value::String).collect(),
+            "role" => strings(&self.roles),
+            "group" => strings(&self.groups),
+            name => match self.attributes.get(name) {
+                Some(Value::Array(values)) => values.clone(),
+                Some(value) => vec![value.clone()],
+                None => Vec::new(),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    /// the flag is disabled
+    Off,
+    /// a rule matched
+    Rule,
+    /// no rule matched
+    Default,
+    /// the stored flag is invalid, nothing is served
+    Error,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FlagValue {
+    pub value: Value,
+    pub variation: Option<usize>,
+    pub reason: Reason,
+    /// the index of the matching rule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rule: Option<usize>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct FlagQuery {
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+impl Condition {
+    fn holds(&self, context: &EvaluationContext) -> bool {
+        let actual = context.values(&self.attribute);
+        let one_of = |v: &Value| self.values.contains(v);
+        match self.op {
+            Operator::In => actual.iter().any(one_of),
+            Operator::NotIn => !actual.iter().any(one_of),
+            Operator::Matches => actual.iter().filter_map(Value::as_str).any(|s| {
+                self.values
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|p| glob_match(p, s))
+            }),
+        }
+    }
+}
+
+/// The rollout bucket of a user for a flag, stable as long as neither changes
+fn bucket(flag: &str, user: &str) -> u64 {
+    let digest = Sha256::digest(format!("{}/{}", flag, user).as_bytes());
+    let mut head = [0u8; 8];
+    head.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(head) % BUCKETS
+}
+
+impl Serve {
+    fn variation(&self, flag: &str, user: &str) -> usize {
+        match self {
+            Serve::Variation(v) => *v,
+            Serve::Rollout(weights) => {
+                let bucket = bucket(flag, user) as f64;
+                let mut upper = 0.0;
+                for w in weights {
+                    upper += w.weight * (BUCKETS as f64) / 100.0;
+                    if bucket < upper {
+                        return w.variation;
+                    }
+                }
+                // rounding left the last buckets out
+                weights.last().map_or(0, |w| w.variation)
+            }
+        }
+    }
+}
+
+impl Flag {
+    /// The value of the flag named `name` for `context`
+    pub fn evaluate(&self, name: &str, context: &EvaluationContext) -> FlagValue {
+        let (variation, reason, rule) = if !self.enabled {
+            (self.off, Reason::Off, None)
+        } else {
+            match self
+                .rules
+                .iter()
+                .position(|r| r.conditions.iter().all(|c| c.holds(context)))
+            {
+                Some(i) => (
+                    self.rules[i].serve.variation(name, &context.user),
+                    Reason::Rule,
+                    Some(i),
+                ),
+                None => (
+                    self.default.variation(name, &context.user),
+                    Reason::Default,
+                    None,
+                ),
+            }
+        };
+        FlagValue {
+            value: self.variations[variation].clone(),
+            variation: Some(variation),
+            reason,
+            rule,
+        }
+    }
+
+    fn errors(&self) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let mut error = |field: String, message: &str| {
+            errors.push(FieldError {
+                field,
+                message: message.to_string(),
+            })
+        };
+        let len = self.variations.len();
+        if len == 0 {
+            error("/variations".to_string(), "must not be empty");
+        }
+        if self.off >= len {
+            error("/off".to_string(), "is not a variation");
+        }
+        let serves = self
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, r)| (format!("/rules/{}/serve", i), &r.serve))
+            .chain([("/default".to_string(), &self.default)]);
+        for (field, serve) in serves {
+            match serve {
+                Serve::Variation(v) if *v >= len => error(field, "is not a variation"),
+                Serve::Variation(_) => {}
+                Serve::Rollout(weights) => {
+                    if weights.iter().any(|w| w.variation >= len) {
+                        error(field.clone(), "serves an unknown variation");
+                    }
+                    if weights.iter().any(|w| w.weight.is_nan() || w.weight < 0.0) {
+                        error(field, "weights must not be negative");
+                    } else if (weights.iter().map(|w| w.weight).sum::<f64>() - 100.0).abs() > 1e-6 {
+                        error(field, "weights must sum to 100");
+                    }
+                }
+            }
+        }
+        for (i, r) in self.rules.iter().enumerate() {
+            for (j, c) in r.conditions.iter().enumerate() {
+                if c.values.is_empty() {
+                    error(
+                        format!("/rules/{}/conditions/{}/values", i, j),
+                        "must not be empty",
+                    );
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// The problems of a value stored as a flag in `namespace`, none outside flag namespaces
+pub(crate) fn flag_errors(namespace: &str, value: &Value) -> Vec<FieldError> {
+    if !namespace.starts_with(FLAG_PREFIX) {
+        return Vec::new();
+    }
+    match serde_json::from_value::<Flag>(value.clone()) {
+        Ok(flag) => flag.errors(),
+        Err(e) => vec![FieldError {
+            field: "/".to_string(),
+            message: format!("is not a flag: {}", e),
+        }],
+    }
+}
+
+/// Every flag of an application the caller may read evaluated for one context, by name
+pub async fn evaluate_flags(
+    headers: HeaderMap,
+    Path(app): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<FlagQuery>,
+    Json(context): Json<EvaluationContext>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "evaluate_flags", &app).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let namespace = format!("{}{}", FLAG_PREFIX, app);
+    let items = state
+        .configs
+        .list_items(&tenant, Some(&namespace), &Map::new())
+        .await
+        .map_err(internal)?;
+    let values: BTreeMap<String, FlagValue> = items
+        .into_iter()
+        .filter(|i| p.can_access(Access::Read, &resource_path(&i.namespace, &i.key)))
+        .map(|i| {
+            let value = match serde_json::from_value::<Flag>(i.value) {
+                Ok(flag) if flag.errors().is_empty() => flag.evaluate(&i.key, &context),
+                _ => FlagValue {
+                    value: Value::Null,
+                    variation: None,
+                    reason: Reason::Error,
+                    rule: None,
+                },
+            };
+            (i.key, value)
+        })
+        .collect();
+    Ok(serde_json::to_string(&values).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::{put_configuration, ConfigurationQuery, ConfigurationValue};
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn context(user: &str) -> EvaluationContext {
+        EvaluationContext {
+            user: user.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rollout_test() {
+        let flag: Flag = serde_json::from_value(json!({
+            "default": {"rollout": [{"variation": 1, "weight": 20}, {"variation": 0, "weight": 80}]}
+        }))
+        .unwrap();
+        let on = |flag: &Flag| {
+            (0..1000)
+                .filter(|i| {
+                    flag.evaluate("checkout", &context(&format!("u{}", i)))
+                        .value
+                        == json!(true)
+                })
+                .map(|i| format!("u{}", i))
+                .collect::<Vec<_>>()
+        };
+        let twenty = on(&flag);
+        assert!((150..250).contains(&twenty.len()));
+        // growing a rollout keeps the users already in
+        let flag: Flag = serde_json::from_value(json!({
+            "default": {"rollout": [{"variation": 1, "weight": 50}, {"variation": 0, "weight": 50}]}
+        }))
+        .unwrap();
+        let fifty = on(&flag);
+        assert!(twenty.iter().all(|u| fifty.contains(u)));
+        assert!((400..600).contains(&fifty.len()));
+    }
+
+    #[test]
+    fn rule_test() {
+        let flag: Flag = serde_json::from_value(json!({
+            "variations": ["blue", "green", "red"],
+            "rules": [
+                {"conditions": [{"attribute": "role", "op": "in", "values": ["beta"]}], "serve": {"variation": 1}},
+                {"conditions": [
+                    {"attribute": "tenant", "op": "not_in", "values": ["acme"]},
+                    {"attribute": "email", "op": "matches", "values": ["*@example.com"]}
+                ], "serve": {"variation": 2}}
+            ],
+            "default": {"variation": 0}
+        }))
+        .unwrap();
+        let mut ctx = context("u1");
+        assert_eq!(flag.evaluate("color", &ctx).reason, Reason::Default);
+        ctx.roles = vec!["dev".to_string(), "beta".to_string()];
+        let value = flag.evaluate("color", &ctx);
+        assert_eq!((value.value, value.rule), (json!("green"), Some(0)));
+        ctx.roles.clear();
+        ctx.attributes
+            .insert("email".to_string(), json!("ann@example.com"));
+        assert_eq!(flag.evaluate("color", &ctx).value, json!("red"));
+        ctx.tenant = Some("acme".to_string());
+        assert_eq!(flag.evaluate("color", &ctx).value, json!("blue"));
+
+        let off = Flag {
+            enabled: false,
+            off: 2,
+            ..flag
+        };
+        assert_eq!(off.evaluate("color", &ctx).reason, Reason::Off);
+        assert_eq!(off.evaluate("color", &ctx).value, json!("red"));
+    }
+
+    #[tokio::test]
+    async fn evaluate_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let put = |key: &str, value: Value| {
+            put_configuration(
+                headers.clone(),
+                Path(format!("flags/shop/{}", key)),
+                state.clone(),
+                Query(ConfigurationQuery::default()),
+                Json(ConfigurationValue {
+                    value,
+                    value_type: None,
+                }),
+            )
+        };
+        put("dark-mode", json!({"default": {"variation": 1}}))
+            .await
+            .unwrap();
+        let invalid = put(
+            "banner",
+            json!({"variations": ["a"], "default": {"rollout": [{"variation": 1, "weight": 90}]}}),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(invalid.0, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors: Vec<FieldError> = serde_json::from_str(&invalid.1).unwrap();
+        assert_eq!(errors.len(), 2);
+        assert!(put("banner", json!(true)).await.is_err());
+
+        let values = evaluate_flags(
+            headers.clone(),
+            Path("shop".to_string()),
+            state.clone(),
+            Query(FlagQuery::default()),
+            Json(context("u1")),
+        )
+        .await
+        .unwrap();
+        let values: BTreeMap<String, FlagValue> = serde_json::from_str(&values).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["dark-mode"].value, json!(true));
+    }
+}
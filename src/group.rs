@@ -0,0 +1,352 @@
+//! Groups of users holding roles, and resolution of effective grants
+use crate::audit::{snapshot, AuditContext};
+use crate::repository::internal;
+use crate::role::{check_grantable, list_roles, validate_roles, Role};
+use crate::state::AppState;
+use crate::tenant::{check_scope, default_tenant};
+use crate::user::UserBase;
+use crate::{active_profile, authorize, tenant_admin_check};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+
+/// A group holds users and other groups as members,
+/// every member inherits the roles of the group and of the groups containing it
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Group {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    /// names of member users
+    #[serde(default)]
+    pub members: Vec<String>,
+    /// names of member groups
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+}
+
+/// The union of direct and inherited grants of a user
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct EffectiveGrants {
+    pub groups: BTreeSet<String>,
+    pub roles: BTreeSet<String>,
+    pub permissions: BTreeSet<String>,
+}
+
+impl EffectiveGrants {
+    pub fn is_admin(&self) -> bool {
+        self.roles.contains("super") || self.roles.contains("admin")
+    }
+
+    pub fn is_tenant_admin(&self) -> bool {
+        self.roles.contains("tenant_admin")
+    }
+}
+
+/// Resolve the grants of `user_base`: its direct roles and permissions,
+/// plus the roles of every group it belongs to directly or through nesting
+pub fn resolve(user_base: &UserBase, groups: &[Group], roles: &[Role]) -> EffectiveGrants {
+    let mut grants = EffectiveGrants::default();
+    let mut pending: Vec<&Group> = groups
+        .iter()
+        .filter(|g| g.members.contains(&user_base.name))
+        .collect();
+    while let Some(g) = pending.pop() {
+        if !grants.groups.insert(g.name.clone()) {
+            continue;
+        }
+        grants.roles.extend(g.roles.iter().cloned());
+        pending.extend(groups.iter().filter(|parent| parent.groups.contains(&g.name)));
+    }
+    grants.roles.extend(user_base.roles.iter().cloned());
+    grants.permissions.extend(user_base.permissions.iter().cloned());
+    for role in roles.iter().filter(|r| grants.roles.contains(&r.name)) {
+        grants.permissions.extend(role.permissions.iter().cloned());
+    }
+    grants
+}
+
+/// All groups of the tenant of `scope`, of every tenant for `None`
+pub async fn list_groups(
+    state: &AppState,
+    scope: Option<&str>,
+) -> Result<Vec<Group>, (StatusCode, String)> {
+    state.groups.list_groups(scope).await.map_err(internal)
+}
+
+/// Load the groups of the user's tenant and the roles then resolve the grants of `user_base`
+pub async fn effective_grants(
+    state: &AppState,
+    user_base: &UserBase,
+) -> Result<EffectiveGrants, (StatusCode, String)> {
+    let groups = list_groups(state, Some(&user_base.tenant)).await?;
+    let roles = list_roles(state).await?;
+    Ok(resolve(user_base, &groups, &roles))
+}
+
+/// Check the member groups exist in the same tenant and the group is not nested in itself
+async fn validate_group(state: &AppState, group: &Group) -> Result<(), (StatusCode, String)> {
+    validate_roles(state, &group.roles).await?;
+    let groups = list_groups(state, Some(&group.tenant)).await?;
+    for sub in &group.groups {
+        if !groups.iter().any(|g| &g.name == sub) {
+            return Err((StatusCode::BAD_REQUEST, format!("Unknown group: {}", sub)));
+        }
+    }
+    let mut others: Vec<Group> = groups.into_iter().filter(|g| g.name != group.name).collect();
+    others.push(group.clone());
+    if contains_cycle(&others, &group.name) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Group {} would contain itself", group.name),
+        ));
+    }
+    Ok(())
+}
+
+/// Whether `name` can be reached again by following member groups from itself
+fn contains_cycle(groups: &[Group], name: &str) -> bool {
+    let mut seen = BTreeSet::new();
+    let mut pending: Vec<&str> = vec![name];
+    while let Some(current) = pending.pop() {
+        for g in groups.iter().filter(|g| g.name == current) {
+            for sub in &g.groups {
+                if sub == name {
+                    return true;
+                }
+                if seen.insert(sub.as_str()) {
+                    pending.push(sub);
+                }
+            }
+        }
+    }
+    false
+}
+
+pub async fn get_groups(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = authorize(&headers, &state, "get_groups", "").await?;
+    let groups = list_groups(&state, p.tenant_scope()).await?;
+    Ok(serde_json::to_string(&groups).unwrap())
+}
+
+pub async fn get_group(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = authorize(&headers, &state, "get_group", &name).await?;
+    match state
+        .groups
+        .find_group(&name, p.tenant_scope())
+        .await
+        .map_err(internal)?
+    {
+        Some(group) => Ok(serde_json::to_string(&group).unwrap()),
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+    }
+}
+
+pub async fn create_group(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Group>,
+) -> Result<String, (StatusCode, String)> {
+    let p = tenant_admin_check(&headers, &state, "create_group", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_group", &payload.name);
+    check_scope(p.tenant_scope(), &payload.tenant)?;
+    check_grantable(&p, &payload.roles)?;
+    let res = match state.groups.find_group(&payload.name, None).await {
+        Ok(Some(_)) => Err((StatusCode::CONFLICT, "Group Name esists".to_string())),
+        Ok(None) => match validate_group(&state, &payload).await {
+            Ok(()) => state
+                .groups
+                .insert_group(&payload)
+                .await
+                .map(|_| payload.name.clone())
+                .map_err(internal),
+            Err(e) => Err(e),
+        },
+        Err(e) => Err(internal(e)),
+    };
+    audit.record(&state, &res, None, snapshot(&payload)).await;
+    res
+}
+
+pub async fn update_group(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Group>,
+) -> Result<String, (StatusCode, String)> {
+    let p = tenant_admin_check(&headers, &state, "update_group", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "update_group", &payload.name);
+    check_scope(p.tenant_scope(), &payload.tenant)?;
+    check_grantable(&p, &payload.roles)?;
+    let before = state
+        .groups
+        .find_group(&payload.name, p.tenant_scope())
+        .await
+        .map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => match validate_group(&state, &payload).await {
+            Ok(()) => state
+                .groups
+                .replace_group(&payload)
+                .await
+                .map(|r| serde_json::to_string(&r).unwrap())
+                .map_err(internal),
+            Err(e) => Err(e),
+        },
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), snapshot(&payload))
+        .await;
+    res
+}
+
+/// Delete a group, it is also removed from the groups containing it
+pub async fn delete_group(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = tenant_admin_check(&headers, &state, "delete_group", &name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_group", &name);
+    let before = state
+        .groups
+        .find_group(&name, p.tenant_scope())
+        .await
+        .map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => state
+            .groups
+            .delete_group(&name)
+            .await
+            .map(|r| serde_json::to_string(&r).unwrap())
+            .map_err(internal),
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+/// Effective groups, roles and permissions of a user.
+/// Users may always look up their own.
+pub async fn get_effective_permissions(
+    headers: HeaderMap,
+    Path(user_name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = active_profile(&headers, &state, "get_effective_permissions", &user_name).await?;
+    let scope = if p.user_base.name != user_name {
+        let p = authorize(&headers, &state, "get_effective_permissions", &user_name).await?;
+        p.tenant_scope().map(|t| t.to_string())
+    } else {
+        None
+    };
+    let user = state
+        .users
+        .find_user_by_name(&user_name, scope.as_deref())
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Not Found".to_string()))?;
+    let grants = effective_grants(&state, &user.user_base).await?;
+    Ok(serde_json::to_string(&grants).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn group(name: &str, members: &[&str], groups: &[&str], roles: &[&str]) -> Group {
+        Group {
+            name: name.to_string(),
+            description: "".to_string(),
+            members: members.iter().map(|s| s.to_string()).collect(),
+            groups: groups.iter().map(|s| s.to_string()).collect(),
+            roles: roles.iter().map(|s| s.to_string()).collect(),
+            tenant: default_tenant(),
+        }
+    }
+
+    #[test]
+    fn resolve_test() {
+        let user = UserBase {
+            name: "u1".to_string(),
+            phone: "123".to_string(),
+            roles: vec!["viewer".to_string()],
+            permissions: vec!["direct".to_string()],
+            ..Default::default()
+        };
+        let groups = vec![
+            group("dev", &["u1"], &[], &["developer"]),
+            group("eng", &[], &["dev"], &["engineer"]),
+            group("org", &[], &["eng"], &[]),
+            group("ops", &["u2"], &[], &["operator"]),
+        ];
+        let roles = vec![
+            Role {
+                name: "viewer".to_string(),
+                description: "".to_string(),
+                permissions: vec!["find_user_by_id".to_string()],
+            },
+            Role {
+                name: "engineer".to_string(),
+                description: "".to_string(),
+                permissions: vec!["get_roles".to_string()],
+            },
+            Role {
+                name: "operator".to_string(),
+                description: "".to_string(),
+                permissions: vec!["delete_user".to_string()],
+            },
+        ];
+        let g = resolve(&user, &groups, &roles);
+        assert_eq!(
+            g.groups.into_iter().collect::<Vec<_>>(),
+            vec!["dev", "eng", "org"]
+        );
+        assert_eq!(
+            g.roles.into_iter().collect::<Vec<_>>(),
+            vec!["developer", "engineer", "viewer"]
+        );
+        assert_eq!(
+            g.permissions.into_iter().collect::<Vec<_>>(),
+            vec!["direct", "find_user_by_id", "get_roles"]
+        );
+    }
+
+    #[test]
+    fn cycle_test() {
+        let groups = vec![
+            group("a", &[], &["b"], &[]),
+            group("b", &[], &["c"], &[]),
+            group("c", &[], &[], &[]),
+        ];
+        assert!(!contains_cycle(&groups, "a"));
+        let mut cyclic = groups.clone();
+        cyclic[2].groups.push("a".to_string());
+        assert!(contains_cycle(&cyclic, "a"));
+        // resolution terminates on cyclic data as well
+        let user = UserBase {
+            name: "u".to_string(),
+            ..Default::default()
+        };
+        cyclic[0].members.push("u".to_string());
+        assert_eq!(resolve(&user, &cyclic, &[]).groups.len(), 3);
+    }
+}
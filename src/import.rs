@@ -0,0 +1,583 @@
+//! Import of existing configuration files into a namespace.
+//! Nested tables are flattened into keys joined by a separator, `db.host` by default,
+//! lists and scalars become values. Keys already holding another value are conflicts,
+//! the import is refused unless they are overwritten. The keys are written at once, each
+//! pinned to the revision the import saw, and share one change id in their history
+use crate::audit::{snapshot, AuditContext};
+use crate::batch::ItemWrite;
+use crate::changeset::check_unreviewed;
+use crate::config_schema::value_errors;
+use crate::configuration::{append_history, reveal, seal, target_tenant, ChangeOp, ValueType};
+use crate::document::check_field_names;
+use crate::repository::internal;
+use crate::resource::{resource_path, Access};
+use crate::secret::REDACTED;
+use crate::state::AppState;
+use crate::tenant::validate_tenant;
+use crate::validation::{into_response, FieldError};
+use crate::{principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImportFormat {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+    Dotenv,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct ImportQuery {
+    #[serde(default)]
+    pub format: ImportFormat,
+    /// joins the names of nested tables into keys, `.` when unset
+    pub separator: Option<String>,
+    /// report what the import would do without writing anything
+    pub dry_run: Option<bool>,
+    /// replace the keys holding another value instead of refusing the import
+    pub overwrite: Option<bool>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+/// A key holding another value than the imported one, secrets redacted
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ImportConflict {
+    pub key: String,
+    pub existing: Value,
+    pub imported: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Default)]
+pub struct ImportReport {
+    pub namespace: String,
+    /// the change id of the written revisions, none when nothing was written
+    pub change: Option<String>,
+    pub dry_run: bool,
+    /// keys not in the namespace yet
+    pub added: Vec<String>,
+    /// keys already holding the imported value, never written
+    pub unchanged: Vec<String>,
+    pub conflicts: Vec<ImportConflict>,
+}
+
+fn bad_request(message: String) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, message)
+}
+
+fn from_toml(value: toml::Value) -> Result<Value, String> {
+    Ok(match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Number::from_f64(f)
+            .map(Value::Number)
+            .ok_or_else(|| format!("{} is not a JSON number", f))?,
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(d) => Value::String(d.to_string()),
+        toml::Value::Array(items) => {
+            Value::Array(items.into_iter().map(from_toml).collect::<Result<_, _>>()?)
+        }
+        toml::Value::Table(fields) => Value::Object(
+            fields
+                .into_iter()
+                .map(|(k, v)| from_toml(v).map(|v| (k, v)))
+                .collect::<Result<_, _>>()?,
+        ),
+    })
+}
+
+/// Strip the quotes of a dotenv value, `"` quoted values may hold escapes
+fn dotenv_value(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    if let Some(quoted) = raw.strip_prefix('\'') {
+        return quoted
+            .strip_suffix('\'')
+            .map(str::to_string)
+            .ok_or_else(|| format!("unterminated quote in {}", raw));
+    }
+    if let Some(quoted) = raw.strip_prefix('"') {
+        let quoted = quoted
+            .strip_suffix('"')
+            .ok_or_else(|| format!("unterminated quote in {}", raw))?;
+        let mut out = String::new();
+        let mut chars = quoted.chars();
+        while let Some(c) = chars.next() {
+            if c != '\\' {
+                out.push(c);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => out.push('\n'),
+                Some('r') => out.push('\r'),
+                Some('t') => out.push('\t'),
+                Some(c) => out.push(c),
+                None => out.push('\\'),
+            }
+        }
+        return Ok(out);
+    }
+    // unquoted values end at a comment
+    let end = raw.find(" #").unwrap_or(raw.len());
+    Ok(raw[..end].trim_end().to_string())
+}
+
+/// `KEY=value` lines, blank lines, comments and `export` prefixes are skipped
+fn parse_dotenv(text: &str) -> Result<Value, String> {
+    let mut fields = Map::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        let (name, value) = line
+            .split_once('=')
+            .ok_or_else(|| format!("line {} is not NAME=value", n + 1))?;
+        let value = dotenv_value(value).map_err(|e| format!("line {}: {}", n + 1, e))?;
+        fields.insert(name.trim().to_string(), Value::String(value));
+    }
+    Ok(Value::Object(fields))
+}
+
+fn parse(text: &str, format: ImportFormat) -> Result<Value, String> {
+    match format {
+        ImportFormat::Json => serde_json::from_str(text).map_err(|e| e.to_string()),
+        ImportFormat::Toml => {
+            from_toml(toml::from_str::<toml::Value>(text).map_err(|e| e.to_string())?)
+        }
+        ImportFormat::Yaml => serde_yaml::from_str(text).map_err(|e| e.to_string()),
+        ImportFormat::Dotenv => parse_dotenv(text),
+    }
+}
+
+fn flatten_into(
+    prefix: &str,
+    fields: Map<String, Value>,
+    separator: &str,
+    keys: &mut BTreeMap<String, Value>,
+    errors: &mut Vec<FieldError>,
+) {
+    for (name, value) in fields {
+        let key = if prefix.is_empty() {
+            name
+        } else {
+            format!("{}{}{}", prefix, separator, name)
+        };
+        let error = |message: &str| FieldError {
+            field: key.clone(),
+            message: message.to_string(),
+        };
+        match value {
+            Value::Object(nested) if !nested.is_empty() => {
+                flatten_into(&key, nested, separator, keys, errors)
+            }
+            Value::Null => errors.push(error("must not be null")),
+            _ if key.is_empty() || key.contains('/') => {
+                errors.push(error("must be a non empty key without /"))
+            }
+            _ if keys.contains_key(&key) => errors.push(error("is defined twice")),
+            value => {
+                keys.insert(key, value);
+            }
+        }
+    }
+}
+
+/// The keys of a parsed file and their values, sorted by key
+fn flatten(value: Value, separator: &str) -> Result<BTreeMap<String, Value>, Vec<FieldError>> {
+    let Value::Object(fields) = value else {
+        return Err(vec![FieldError {
+            field: "/".to_string(),
+            message: "must be a table of keys".to_string(),
+        }]);
+    };
+    let mut keys = BTreeMap::new();
+    let mut errors = Vec::new();
+    flatten_into("", fields, separator, &mut keys, &mut errors);
+    if errors.is_empty() {
+        Ok(keys)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Import a TOML, YAML, JSON or dotenv file into a namespace, the file is the request body.
+/// Answers 409 with the report when keys conflict and are not overwritten, dry runs are audited too
+pub async fn import_configuration(
+    headers: HeaderMap,
+    Path(namespace): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<ImportQuery>,
+    body: String,
+) -> Result<String, (StatusCode, String)> {
+    let namespace = namespace.trim_matches('/').to_string();
+    let p = principal(&headers, &state, "import_configuration", &namespace).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "import_configuration", &namespace);
+    let res = import_keys(&state, &p, &namespace, &query, &body).await;
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|report| serde_json::to_string(&report).unwrap());
+    audit.record(&state, &res, None, after).await;
+    res
+}
+
+/// Check, compare and write the file of `import_configuration`, giving the report
+async fn import_keys(
+    state: &AppState,
+    p: &Principal,
+    namespace: &str,
+    query: &ImportQuery,
+    body: &str,
+) -> Result<ImportReport, (StatusCode, String)> {
+    if namespace.is_empty() {
+        return Err(bad_request("Missing namespace".to_string()));
+    }
+    check_unreviewed(state, namespace)?;
+    let separator = query.separator.as_deref().unwrap_or(".");
+    if separator.is_empty() || separator.contains('/') {
+        return Err(bad_request(format!("Invalid separator: {}", separator)));
+    }
+    let tenant = target_tenant(p, query.tenant.as_deref())?;
+    validate_tenant(state, &tenant).await?;
+    let parsed = parse(body, query.format)
+        .map_err(|e| bad_request(format!("Invalid {:?} file: {}", query.format, e)))?;
+    let imported = flatten(parsed, separator).map_err(into_response)?;
+
+    let mut errors = Vec::new();
+    for (key, value) in &imported {
+        let path = resource_path(namespace, key);
+        if !p.can_access(Access::Write, &path) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Missing {:?} access on {}", Access::Write, path),
+            ));
+        }
+        check_field_names(value, &format!("{}#", key), &mut errors);
+        for e in value_errors(state, &tenant, namespace, key, value).await? {
+            errors.push(FieldError {
+                field: format!("{}#{}", key, e.field),
+                message: e.message,
+            });
+        }
+    }
+    if !errors.is_empty() {
+        return Err(into_response(errors));
+    }
+
+    let existing = state
+        .configs
+        .list_items(&tenant, Some(namespace), &Map::new())
+        .await
+        .map_err(internal)?;
+    let mut existing: BTreeMap<String, _> =
+        existing.into_iter().map(|i| (i.key.clone(), i)).collect();
+    let mut report = ImportReport {
+        namespace: namespace.to_string(),
+        dry_run: query.dry_run == Some(true),
+        ..Default::default()
+    };
+    // the type each written key gets, imported strings stay secret over secrets
+    let mut writes = Vec::new();
+    for (key, value) in imported {
+        let Some(mut item) = existing.remove(&key) else {
+            report.added.push(key.clone());
+            writes.push((key, ValueType::of(&value).unwrap_or_default(), value, 0));
+            continue;
+        };
+        let secret = item.value_type == ValueType::Secret;
+        // comparing to a secret tells its plaintext, only to those who may reveal it
+        let comparable = !secret || p.can_access(Access::Reveal, &resource_path(namespace, &key));
+        if secret && comparable && state.master_key.is_some() {
+            reveal(state, &mut item)?;
+        }
+        if comparable && item.value == value {
+            report.unchanged.push(key);
+            continue;
+        }
+        let value_type = if secret && value.is_string() {
+            ValueType::Secret
+        } else {
+            ValueType::of(&value).unwrap_or_default()
+        };
+        let (existing, imported) = if secret {
+            let redacted = Value::String(REDACTED.to_string());
+            (redacted.clone(), redacted)
+        } else {
+            (item.value, value.clone())
+        };
+        report.conflicts.push(ImportConflict {
+            key: key.clone(),
+            existing,
+            imported,
+        });
+        writes.push((key, value_type, value, item.revision));
+    }
+    if report.dry_run {
+        return Ok(report);
+    }
+    if !report.conflicts.is_empty() && query.overwrite != Some(true) {
+        return Err((
+            StatusCode::CONFLICT,
+            serde_json::to_string(&report).unwrap(),
+        ));
+    }
+
+    let mut items = Vec::new();
+    for (key, value_type, value, revision) in writes {
+        let value = match value_type {
+            ValueType::Secret => seal(state, &tenant, namespace, &key, &value)?,
+            _ => value,
+        };
+        items.push(ItemWrite {
+            key,
+            op: ChangeOp::Put,
+            value,
+            value_type,
+            revision: Some(revision),
+        });
+    }
+    if items.is_empty() {
+        return Ok(report);
+    }
+    let outcome = state
+        .configs
+        .write_items(&tenant, namespace, &items, &p.profile.user_base.name)
+        .await
+        .map_err(internal)?;
+    match outcome {
+        Ok(revisions) => {
+            let change = ObjectId::new().to_hex();
+            for h in &revisions {
+                append_history(state, h.op.clone(), &h.item, Some(&change)).await;
+            }
+            report.change = Some(change);
+            Ok(report)
+        }
+        // changed since the keys were compared, nothing is written
+        Err(conflicts) => Err((
+            StatusCode::CONFLICT,
+            serde_json::to_string(&conflicts).unwrap(),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::{AuditQuery, Outcome};
+    use crate::configuration::{
+        get_configuration_change, ConfigurationHistory, ConfigurationQuery,
+    };
+    use crate::mail::MemoryMailer;
+    use crate::secret::MasterKey;
+    use crate::token::generate_token;
+    use crate::user::{stored_headers, UserBase, UserProfile};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn flatten_test() {
+        let toml = "name = \"shop\"\n[db]\nhost = \"db.local\"\nports = [5432, 5433]\n[db.pool]\nsize = 4\n";
+        let keys = flatten(parse(toml, ImportFormat::Toml).unwrap(), "__").unwrap();
+        let view: Vec<(&str, &Value)> = keys.iter().map(|(k, v)| (k.as_str(), v)).collect();
+        assert_eq!(
+            view,
+            vec![
+                ("db__host", &json!("db.local")),
+                ("db__pool__size", &json!(4)),
+                ("db__ports", &json!([5432, 5433])),
+                ("name", &json!("shop")),
+            ]
+        );
+        let yaml = parse("db:\n  host: a\ndb.host: b\n", ImportFormat::Yaml).unwrap();
+        assert_eq!(
+            flatten(yaml, ".").unwrap_err()[0].message,
+            "is defined twice"
+        );
+        assert!(flatten(json!({"a": null}), ".").is_err());
+        assert!(flatten(json!([1]), ".").is_err());
+
+        let env = "# db\nexport DB_HOST=db.local # primary\nDB_PASSWORD=\"a \\\"b\\\"\\n\"\nNAME='x # y'\n";
+        assert_eq!(
+            parse(env, ImportFormat::Dotenv).unwrap(),
+            json!({"DB_HOST": "db.local", "DB_PASSWORD": "a \"b\"\n", "NAME": "x # y"})
+        );
+        assert!(parse("DB_HOST", ImportFormat::Dotenv).is_err());
+    }
+
+    #[tokio::test]
+    async fn import_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        state
+            .configs
+            .put_item(
+                "default",
+                "shop",
+                "db.port",
+                &json!(5432),
+                ValueType::Number,
+                "u",
+            )
+            .await
+            .unwrap();
+        state
+            .configs
+            .put_item(
+                "default",
+                "shop",
+                "db.host",
+                &json!("a"),
+                ValueType::String,
+                "u",
+            )
+            .await
+            .unwrap();
+        let file = "{\"db\": {\"host\": \"b\", \"port\": 5432, \"user\": \"app\"}}".to_string();
+        let query = |dry_run: bool, overwrite: bool| {
+            Query(ImportQuery {
+                dry_run: Some(dry_run),
+                overwrite: Some(overwrite),
+                ..Default::default()
+            })
+        };
+        let ns = || Path("shop".to_string());
+        let import = |dry_run, overwrite| {
+            import_configuration(
+                headers.clone(),
+                ns(),
+                state.clone(),
+                query(dry_run, overwrite),
+                file.clone(),
+            )
+        };
+
+        let report: ImportReport =
+            serde_json::from_str(&import(true, false).await.unwrap()).unwrap();
+        assert_eq!(report.added, vec!["db.user"]);
+        assert_eq!(report.unchanged, vec!["db.port"]);
+        assert_eq!(report.conflicts[0].key, "db.host");
+        assert_eq!(report.change, None);
+        let conflict = import(false, false).await.unwrap_err();
+        assert_eq!(conflict.0, StatusCode::CONFLICT);
+        let user = state
+            .configs
+            .find_item("default", "shop", "db.user")
+            .await
+            .unwrap();
+        assert!(user.is_none());
+
+        let report: ImportReport =
+            serde_json::from_str(&import(false, true).await.unwrap()).unwrap();
+        let change = report.change.unwrap();
+        let host = state
+            .configs
+            .find_item("default", "shop", "db.host")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((host.value, host.revision), (json!("b"), 2));
+        let changed = get_configuration_change(
+            headers.clone(),
+            Path(change.clone()),
+            state.clone(),
+            Query(ConfigurationQuery::default()),
+        )
+        .await
+        .unwrap();
+        let changed: Vec<ConfigurationHistory> = serde_json::from_str(&changed).unwrap();
+        let keys: Vec<&str> = changed.iter().map(|h| h.item.key.as_str()).collect();
+        assert_eq!(keys, vec!["db.host", "db.user"]);
+        assert!(changed
+            .iter()
+            .all(|h| h.change.as_deref() == Some(change.as_str())));
+
+        let invalid = import_configuration(
+            headers.clone(),
+            ns(),
+            state.clone(),
+            Query(ImportQuery {
+                format: ImportFormat::Toml,
+                ..Default::default()
+            }),
+            "db = ".to_string(),
+        )
+        .await;
+        assert_eq!(invalid.unwrap_err().0, StatusCode::BAD_REQUEST);
+        let query = AuditQuery {
+            action: Some("import_configuration".to_string()),
+            ..Default::default()
+        };
+        let records = state.audit.search_audit(&query, None).await.unwrap();
+        let failed = records.iter().filter(|r| r.outcome == Outcome::Failure);
+        assert_eq!(failed.count(), 2, "refused imports are audited too");
+    }
+
+    #[tokio::test]
+    async fn secret_test() {
+        let state = State(
+            AppState::memory(Arc::new(MemoryMailer::default()), "http://cf")
+                .with_master_key(MasterKey::new(&[7u8; 32]).unwrap()),
+        );
+        let password = seal(&state, "default", "shop", "db.password", &json!("hunter2")).unwrap();
+        state
+            .configs
+            .put_item(
+                "default",
+                "shop",
+                "db.password",
+                &password,
+                ValueType::Secret,
+                "u",
+            )
+            .await
+            .unwrap();
+        let import_as = |name: &str, permissions: Vec<&str>| {
+            let state = state.clone();
+            let user = UserBase {
+                name: name.to_string(),
+                permissions: permissions.into_iter().map(String::from).collect(),
+                ..Default::default()
+            };
+            async move {
+                let headers = stored_headers(&state, user).await;
+                let query = Query(ImportQuery {
+                    dry_run: Some(true),
+                    ..Default::default()
+                });
+                let file = r#"{"db": {"password": "hunter2"}}"#.to_string();
+                let report =
+                    import_configuration(headers, Path("shop".to_string()), state, query, file)
+                        .await
+                        .unwrap();
+                serde_json::from_str::<ImportReport>(&report).unwrap()
+            }
+        };
+        // a right guess is not told to who may not reveal
+        let report = import_as("writer", vec!["config:write:shop/*"]).await;
+        assert!(report.unchanged.is_empty());
+        assert_eq!(report.conflicts[0].key, "db.password");
+        assert_eq!(report.conflicts[0].existing, json!(REDACTED));
+        let report = import_as(
+            "revealer",
+            vec!["config:write:shop/*", "config:reveal:shop/*"],
+        )
+        .await;
+        assert_eq!(report.unchanged, vec!["db.password"]);
+        assert!(report.conflicts.is_empty());
+    }
+}
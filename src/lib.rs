@@ -2,22 +2,54 @@ use axum::http::{HeaderMap, StatusCode};
 use chrono::Utc;
 use token::verify_token;
 use tracing::{error, info};
+use audit::AuditContext;
+use group::EffectiveGrants;
+use resource::Access;
+use state::AppState;
+use user::UserProfile;
 
+pub mod account;
+pub mod audit;
 pub mod auth;
+pub mod batch;
+pub mod changeset;
+#[cfg(feature = "client")]
+pub mod client;
 pub mod config;
+pub mod config_schema;
+pub mod configuration;
+pub mod document;
+pub mod flag;
+pub mod group;
+pub mod import;
+pub mod interpolate;
+pub mod mail;
+pub mod memory_repository;
 pub mod mongo_api;
+pub mod mongo_repository;
+pub mod overlay;
+pub mod render;
+pub mod repository;
+pub mod resource;
+pub mod role;
+pub mod schedule;
+pub mod schema;
+pub mod secret;
+pub mod state;
+pub mod tenant;
 pub mod token;
 pub mod user;
 pub mod utils;
 pub mod user_config;
+pub mod validation;
 
 /// valid the auth token
-/// if invalid return status code 401
+/// if invalid return status code 401, otherwise return the caller's profile
 fn permission_check(
     headers: &HeaderMap,
     fn_name: &str,
     arg: &str,
-) -> Result<(), (StatusCode, String)> {
+) -> Result<UserProfile, (StatusCode, String)> {
     if let Some(auth) = headers.get("authorization") {
         let token = auth.to_str().unwrap();
         match verify_token(token) {
@@ -30,7 +62,7 @@ fn permission_check(
                     arg,
                     Utc::now()
                 );
-                Ok(())
+                Ok(p)
             }
             Err(e) => {
                 error!("verify_token failed, {:?}", e);
@@ -44,4 +76,180 @@ fn permission_check(
             "missing authecication information".to_string(),
         ))
     }
-}
\ No newline at end of file
+}
+
+/// valid the auth token like `permission_check` and return the caller as stored, so that
+/// changes to its roles, permissions, tenant or status apply at once. Return status code 401
+/// when the user no longer exists and 403 when disabled. `super` is not stored and only has its token
+async fn active_profile(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<UserProfile, (StatusCode, String)> {
+    let profile = permission_check(headers, fn_name, arg)?;
+    let super_user = UserProfile::default_super();
+    if profile._id == super_user._id {
+        return Ok(super_user);
+    }
+    let stored = state
+        .users
+        .find_user_by_id(&profile._id, None)
+        .await
+        .map_err(|e| {
+            error!("Failed to look up {}, {:?}", profile.user_base.name, e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+        })?;
+    let Some(stored) = stored else {
+        error!("Deleted user {} tried to invoke {}", profile.user_base.name, fn_name);
+        return Err((StatusCode::UNAUTHORIZED, "User no longer exists".to_string()));
+    };
+    if stored.user_base.disabled {
+        error!("Disabled user {} tried to invoke {}", profile.user_base.name, fn_name);
+        return Err((StatusCode::FORBIDDEN, "User is disabled".to_string()));
+    }
+    Ok(UserProfile::from(stored))
+}
+
+/// The authenticated caller and its effective grants
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub profile: UserProfile,
+    pub grants: EffectiveGrants,
+}
+
+impl Principal {
+    /// The tenant the caller is confined to, `None` for admins who see every tenant
+    pub fn tenant_scope(&self) -> Option<&str> {
+        if self.grants.is_admin() {
+            None
+        } else {
+            Some(&self.profile.user_base.tenant)
+        }
+    }
+
+    /// Whether the caller may access the configuration `resource`,
+    /// admins and tenant admins may access every resource of their scope
+    /// but need an explicit grant to reveal secrets like anyone else
+    pub fn can_access(&self, access: Access, resource: &str) -> bool {
+        (access != Access::Reveal && (self.grants.is_admin() || self.grants.is_tenant_admin()))
+            || resource::allowed(&self.grants.permissions, access, resource)
+    }
+}
+
+async fn principal(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let profile = active_profile(headers, state, fn_name, arg).await?;
+    let grants = group::effective_grants(state, &profile.user_base).await?;
+    Ok(Principal { profile, grants })
+}
+
+/// `principal` for the privileged actions, a refused token is audited
+async fn checked_principal(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let res = principal(headers, state, fn_name, arg).await;
+    if res.is_err() {
+        AuditContext::anonymous(headers, fn_name, arg)
+            .record(state, &res, None, None)
+            .await;
+    }
+    res
+}
+
+/// Audit the refusal of `fn_name` to the caller and give it back
+async fn refuse(
+    headers: &HeaderMap,
+    state: &AppState,
+    p: &Principal,
+    fn_name: &str,
+    arg: &str,
+    refusal: (StatusCode, String),
+) -> (StatusCode, String) {
+    error!("{} is not allowed to invoke {} on {}", p.profile.user_base.name, fn_name, arg);
+    AuditContext::new(headers, &p.profile, fn_name, arg)
+        .record(state, &Err::<(), _>(refusal.clone()), None, None)
+        .await;
+    refusal
+}
+
+/// valid the auth token and check the caller is granted `fn_name`,
+/// either directly, through its roles or through its groups.
+/// Admins and tenant admins are granted everything, the latter only
+/// within their tenant. Return status code 403 when not granted
+async fn authorize(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let p = checked_principal(headers, state, fn_name, arg).await?;
+    if p.grants.is_admin() || p.grants.is_tenant_admin() || p.grants.permissions.contains(fn_name) {
+        Ok(p)
+    } else {
+        let refusal = (StatusCode::FORBIDDEN, format!("Missing permission {}", fn_name));
+        Err(refuse(headers, state, &p, fn_name, arg, refusal).await)
+    }
+}
+
+/// valid the auth token and check the caller may access the configuration key
+/// `namespace/key` through its resource permissions. Return status code 403 when not granted
+async fn authorize_resource(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    access: Access,
+    namespace: &str,
+    key: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let path = resource::resource_path(namespace, key);
+    let p = checked_principal(headers, state, fn_name, &path).await?;
+    if p.can_access(access, &path) {
+        Ok(p)
+    } else {
+        let refusal = (
+            StatusCode::FORBIDDEN,
+            format!("Missing {:?} access on {}", access, path),
+        );
+        Err(refuse(headers, state, &p, fn_name, &path, refusal).await)
+    }
+}
+
+/// valid the auth token and require the caller to be an admin,
+/// holding the `super` or `admin` role directly or through its groups
+/// return status code 403 for non admins
+async fn admin_check(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let p = checked_principal(headers, state, fn_name, arg).await?;
+    if !p.grants.is_admin() {
+        let refusal = (StatusCode::FORBIDDEN, "Admin Only".to_string());
+        return Err(refuse(headers, state, &p, fn_name, arg, refusal).await);
+    }
+    Ok(p)
+}
+
+/// like `admin_check`, but also accept tenant admins, confined to their tenant
+async fn tenant_admin_check(
+    headers: &HeaderMap,
+    state: &AppState,
+    fn_name: &str,
+    arg: &str,
+) -> Result<Principal, (StatusCode, String)> {
+    let p = checked_principal(headers, state, fn_name, arg).await?;
+    if !p.grants.is_admin() && !p.grants.is_tenant_admin() {
+        let refusal = (StatusCode::FORBIDDEN, "Admin Only".to_string());
+        return Err(refuse(headers, state, &p, fn_name, arg, refusal).await);
+    }
+    Ok(p)
+}
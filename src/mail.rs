@@ -0,0 +1,181 @@
+//! Outgoing mail, behind the pluggable `Mailer` trait
+use async_trait::async_trait;
+use chrono::Utc;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tracing::info;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+#[async_trait]
+pub trait Mailer: Send + Sync {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()>;
+}
+
+/// Deliver through a plain SMTP relay (no TLS, no authentication),
+/// such as a local MTA or a sidecar relay
+pub struct SmtpMailer {
+    host: String,
+    port: u16,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(host: &str, port: u16, from: &str) -> Self {
+        SmtpMailer {
+            host: host.to_string(),
+            port,
+            from: from.to_string(),
+        }
+    }
+
+    fn message(&self, mail: &Mail) -> String {
+        let mut data = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n",
+            self.from,
+            mail.to,
+            mail.subject,
+            Utc::now().to_rfc2822()
+        );
+        for line in mail.body.lines() {
+            // dot stuffing, RFC 5321 4.5.2
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+            data.push_str("\r\n");
+        }
+        data.push_str(".\r\n");
+        data
+    }
+}
+
+/// Read one possibly multi line reply and check its code
+async fn expect_reply<R>(reader: &mut R, code: &str) -> anyhow::Result<()>
+where
+    R: AsyncBufReadExt + Unpin,
+{
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            anyhow::bail!("SMTP connection closed");
+        }
+        if !line.starts_with(code) {
+            anyhow::bail!("unexpected SMTP reply: {}", line.trim_end());
+        }
+        // "250-" continues, "250 " ends the reply
+        if line.as_bytes().get(3) != Some(&b'-') {
+            return Ok(());
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for SmtpMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        let stream = TcpStream::connect((self.host.as_str(), self.port)).await?;
+        let (read, mut write) = stream.into_split();
+        let mut reader = BufReader::new(read);
+        expect_reply(&mut reader, "220").await?;
+        let commands = [
+            ("EHLO cf\r\n".to_string(), "250"),
+            (format!("MAIL FROM:<{}>\r\n", self.from), "250"),
+            (format!("RCPT TO:<{}>\r\n", mail.to), "250"),
+            ("DATA\r\n".to_string(), "354"),
+            (self.message(&mail), "250"),
+            ("QUIT\r\n".to_string(), "221"),
+        ];
+        for (command, code) in commands {
+            write.write_all(command.as_bytes()).await?;
+            expect_reply(&mut reader, code).await?;
+        }
+        info!("mail '{}' sent to {}", mail.subject, mail.to);
+        Ok(())
+    }
+}
+
+/// Write every mail as a file into a directory, for development
+pub struct FileMailer {
+    dir: PathBuf,
+}
+
+impl FileMailer {
+    pub fn new(dir: &str) -> Self {
+        FileMailer {
+            dir: PathBuf::from(dir),
+        }
+    }
+}
+
+#[async_trait]
+impl Mailer for FileMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let file = self.dir.join(format!(
+            "{}-{}.txt",
+            Utc::now().format("%Y%m%dT%H%M%S%.3f"),
+            mail.to
+        ));
+        let content = format!("To: {}\nSubject: {}\n\n{}\n", mail.to, mail.subject, mail.body);
+        tokio::fs::write(&file, content).await?;
+        info!("mail '{}' written to {:?}", mail.subject, file);
+        Ok(())
+    }
+}
+
+/// Keep every mail in memory, for tests
+#[derive(Default, Clone)]
+pub struct MemoryMailer {
+    sent: Arc<Mutex<Vec<Mail>>>,
+}
+
+impl MemoryMailer {
+    pub fn sent(&self) -> Vec<Mail> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Mailer for MemoryMailer {
+    async fn send(&self, mail: Mail) -> anyhow::Result<()> {
+        self.sent.lock().unwrap().push(mail);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn memory_mailer_test() {
+        let mailer = MemoryMailer::default();
+        let m: Arc<dyn Mailer> = Arc::new(mailer.clone());
+        let mail = Mail {
+            to: "zs@example.com".to_string(),
+            subject: "hi".to_string(),
+            body: "hello".to_string(),
+        };
+        m.send(mail.clone()).await.unwrap();
+        assert_eq!(mailer.sent(), vec![mail]);
+    }
+
+    #[test]
+    fn smtp_message_test() {
+        let mailer = SmtpMailer::new("127.0.0.1", 25, "cf@example.com");
+        let data = mailer.message(&Mail {
+            to: "zs@example.com".to_string(),
+            subject: "hi".to_string(),
+            body: "line\n.dot".to_string(),
+        });
+        assert!(data.contains("To: zs@example.com\r\n"));
+        assert!(data.ends_with("line\r\n..dot\r\n.\r\n"));
+    }
+}
@@ -1,10 +1,45 @@
-use axum::routing::{get, post};
+use axum::middleware;
+use axum::routing::{delete, get, post};
 use axum::Router;
+use clap::Parser;
+use cf::account::{
+    request_email_verification, request_password_reset, reset_password, verify_email,
+};
+use cf::audit::{client_address, search_audit};
 use cf::auth;
-use cf::config::CfConfig;
-use cf::user::{create_user, delete_user, find_user_by_id, find_user_by_name, get_number_of_all_users, get_user_in_page, update_user};
+use cf::batch::write_configuration_batch;
+use cf::changeset::{
+    act_on_changeset, create_changeset, get_changeset, list_changesets, update_changeset,
+};
+use cf::config::{CfConfig, Storage};
+use cf::config_schema::{delete_config_schema, get_config_schemas, put_config_schema};
+use cf::configuration::{
+    delete_configuration, get_configuration, get_configuration_change, get_configuration_history,
+    list_configurations, put_configuration,
+};
+use cf::document::{
+    create_document, delete_document, get_document, list_collections, list_documents,
+    replace_document,
+};
+use cf::flag::evaluate_flags;
+use cf::group::{
+    create_group, delete_group, get_effective_permissions, get_group, get_groups, update_group,
+};
+use cf::import::import_configuration;
+use cf::mongo_api::{self, MongoStore};
+use cf::overlay::{get_configuration_diff, get_resolved_configuration};
+use cf::render::render_configuration;
+use cf::role::{
+    create_permission, create_role, delete_permission, delete_role, get_permissions, get_role,
+    get_roles, seed_roles, update_role,
+};
+use cf::schedule::{cancel_job, create_job, get_job, list_jobs, run_scheduler};
+use cf::state::AppState;
+use cf::tenant::{create_tenant, delete_tenant, get_tenants};
+use cf::user::{create_user, delete_user, find_user_by_id, find_user_by_name, get_number_of_all_users, get_user_in_page, update_user, get_me, update_me};
 use cf::user_config::get_user_cfg_data;
-use mongodb::{Client, Database};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use tower_http::cors::Any;
 use tower_http::trace::{DefaultMakeSpan, TraceLayer};
 use tracing::info;
@@ -13,8 +48,40 @@ use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
 use tracing_subscriber::{fmt, EnvFilter, Registry};
 
+/// The configuration and user management service
+#[derive(Parser)]
+#[command(name = "cf")]
+struct Cli {
+    /// the configuration file
+    #[arg(long, env = "CF_CONFIG", default_value = "/etc/cf/config.toml")]
+    config: String,
+    /// serve on this address instead of the one of the `http` section, like `0.0.0.0:8081`
+    #[arg(long)]
+    http_addr: Option<String>,
+    /// the MongoDB connection string, in place of the `db` host and port
+    #[arg(long)]
+    db_uri: Option<String>,
+    /// print the effective configuration, secrets redacted, and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    // before logging starts, `--print-config` leaves no log file behind
+    let mut config = CfConfig::load_with_env(&cli.config, std::env::vars())?;
+    if let Some(addr) = &cli.http_addr {
+        config = config.with_http_addr(addr)?;
+    }
+    if let Some(uri) = &cli.db_uri {
+        config = config.with_db_uri(uri);
+    }
+    if cli.print_config {
+        print!("{}", config.redacted()?);
+        return Ok(());
+    }
+
     let env_filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
     let file_appender = tracing_appender::rolling::daily("logs", "cf.log");
     let (non_blocking, _guard) = tracing_appender::non_blocking(file_appender);
@@ -33,15 +100,42 @@ async fn main() -> anyhow::Result<()> {
 
     info!("Start from {:?}", std::env::current_dir().unwrap());
 
-    let config = CfConfig::load("src/config/config.toml")?;
+    let state = match config.storage() {
+        Storage::Mongodb => {
+            let client = mongo_api::connect(mongo_api::client_options(&config).await?)?;
+            let store = MongoStore::new(client.database(&config.db().database));
+            AppState::mongo(store, config.mailer(), &config.mail().link_base)
+        }
+        Storage::Memory => {
+            info!("Keep data in memory, it is lost on restart");
+            AppState::memory(config.mailer(), &config.mail().link_base)
+        }
+    };
+    let mut state = state
+        .with_collections(config.document_collections()?)
+        .with_review(config.review().clone());
+    match config.master_key()? {
+        Some(master_key) => state = state.with_master_key(master_key),
+        None => info!("No keyfile configured, secrets are disabled"),
+    }
 
-    let client = Client::with_uri_str(config.db_url()).await?;
-    let user_db = client.database("user");
+    state.configs.create_indexes().await?;
+    seed_roles(&state).await?;
+    tokio::spawn(run_scheduler(state.clone(), config.scheduler_interval()));
 
     let mut app = create_app();
-    app = user_router(app, &user_db);
-    app = auth_router(app, &user_db);
-    app = app_layer(app);
+    app = user_router(app, &state);
+    app = auth_router(app, &state);
+    app = audit_router(app, &state);
+    app = role_router(app, &state);
+    app = group_router(app, &state);
+    app = tenant_router(app, &state);
+    app = configuration_router(app, &state);
+    app = changeset_router(app, &state);
+    app = job_router(app, &state);
+    app = account_router(app, &state);
+    app = document_router(app, &state);
+    app = app_layer(app, config.trusted_proxies());
 
     //start http server
     let http_service_url = config.service_url();
@@ -50,7 +144,10 @@ async fn main() -> anyhow::Result<()> {
         .await
         .unwrap();
     info!("Listening on {}", http_service_url);
-    axum::serve(listener, app).await.unwrap();
+    // the peer address is the client's unless it is a trusted proxy
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
 
     Ok(())
 }
@@ -58,44 +155,253 @@ async fn main() -> anyhow::Result<()> {
 fn create_app() -> Router {
     Router::new().route("/cf/v1", get(|| async { "Hello" }))
 }
-fn user_router(app: Router, user_db: &Database) -> Router {
+fn user_router(app: Router, state: &AppState) -> Router {
     app.route(
+        "/cf/v1/me",
+        get(get_me)
+            .with_state(state.clone())
+            .put(update_me)
+            .with_state(state.clone()),
+    )
+    .route(
         "/cf/user",
         post(create_user)
-            .with_state(user_db.clone())
+            .with_state(state.clone())
             .put(update_user)
-            .with_state(user_db.clone())
+            .with_state(state.clone())
             .delete(delete_user)
-            .with_state(user_db.clone())
+            .with_state(state.clone())
     )
     .route(
         "/cf/user/id/:id",
-        get(find_user_by_id).with_state(user_db.clone()),
+        get(find_user_by_id).with_state(state.clone()),
     )
     .route(
         "/cf/user/name/:name",
-        get(find_user_by_name).with_state(user_db.clone()),
+        get(find_user_by_name).with_state(state.clone()),
     )
     .route(
         "/cf/user/num",
-        get(get_number_of_all_users).with_state(user_db.clone()),
+        get(get_number_of_all_users).with_state(state.clone()),
     )
     .route(
         "/cf/user/pagi",
-        post(get_user_in_page).with_state(user_db.clone()),
+        post(get_user_in_page).with_state(state.clone()),
     )
     .route(
         "/cf/user/cfg",
-        get(get_user_cfg_data).with_state(user_db.clone()),
+        get(get_user_cfg_data).with_state(state.clone()),
     )
 }
-fn auth_router(app: Router, user_db: &Database) -> Router {
+fn auth_router(app: Router, state: &AppState) -> Router {
     app.route(
-        "/cf/auth", post(auth::authenticate).with_state(user_db.clone())
+        "/cf/auth", post(auth::authenticate).with_state(state.clone())
     )
 }
-fn app_layer(app: Router) -> Router {
-    app.layer(
+fn audit_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/audit", get(search_audit).with_state(state.clone())
+    )
+}
+fn role_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/role",
+        get(get_roles)
+            .with_state(state.clone())
+            .post(create_role)
+            .with_state(state.clone())
+            .put(update_role)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/role/:name",
+        get(get_role)
+            .with_state(state.clone())
+            .delete(delete_role)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/permission",
+        get(get_permissions)
+            .with_state(state.clone())
+            .post(create_permission)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/permission/:name",
+        delete(delete_permission).with_state(state.clone()),
+    )
+}
+fn group_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/group",
+        get(get_groups)
+            .with_state(state.clone())
+            .post(create_group)
+            .with_state(state.clone())
+            .put(update_group)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/group/:name",
+        get(get_group)
+            .with_state(state.clone())
+            .delete(delete_group)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/user/effective/:name",
+        get(get_effective_permissions).with_state(state.clone()),
+    )
+}
+fn tenant_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/tenant",
+        get(get_tenants)
+            .with_state(state.clone())
+            .post(create_tenant)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/tenant/:name",
+        delete(delete_tenant).with_state(state.clone()),
+    )
+}
+fn configuration_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/config",
+        get(list_configurations).with_state(state.clone()),
+    )
+    .route(
+        "/cf/config/*path",
+        get(get_configuration)
+            .with_state(state.clone())
+            .put(put_configuration)
+            .with_state(state.clone())
+            .delete(delete_configuration)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/history/*path",
+        get(get_configuration_history).with_state(state.clone()),
+    )
+    .route(
+        "/cf/resolved/:app/:env",
+        get(get_resolved_configuration).with_state(state.clone()),
+    )
+    .route(
+        "/cf/diff/:app",
+        get(get_configuration_diff).with_state(state.clone()),
+    )
+    .route(
+        "/cf/render/:app/:env",
+        get(render_configuration).with_state(state.clone()),
+    )
+    .route(
+        "/cf/import/*namespace",
+        post(import_configuration).with_state(state.clone()),
+    )
+    .route(
+        "/cf/batch/*namespace",
+        post(write_configuration_batch).with_state(state.clone()),
+    )
+    .route(
+        "/cf/flags/:app/evaluate",
+        post(evaluate_flags).with_state(state.clone()),
+    )
+    .route(
+        "/cf/changes/:change",
+        get(get_configuration_change).with_state(state.clone()),
+    )
+    .route(
+        "/cf/schema/*namespace",
+        get(get_config_schemas)
+            .with_state(state.clone())
+            .put(put_config_schema)
+            .with_state(state.clone())
+            .delete(delete_config_schema)
+            .with_state(state.clone()),
+    )
+}
+fn changeset_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/changesets",
+        get(list_changesets)
+            .with_state(state.clone())
+            .post(create_changeset)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/changesets/:id",
+        get(get_changeset)
+            .with_state(state.clone())
+            .put(update_changeset)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/changesets/:id/:action",
+        post(act_on_changeset).with_state(state.clone()),
+    )
+}
+fn job_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/jobs",
+        get(list_jobs)
+            .with_state(state.clone())
+            .post(create_job)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/jobs/:id",
+        get(get_job)
+            .with_state(state.clone())
+            .delete(cancel_job)
+            .with_state(state.clone()),
+    )
+}
+fn account_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/account/verify",
+        get(verify_email)
+            .with_state(state.clone())
+            .post(request_email_verification)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/account/reset",
+        post(reset_password).with_state(state.clone()),
+    )
+    .route(
+        "/cf/account/reset/request",
+        post(request_password_reset).with_state(state.clone()),
+    )
+}
+fn document_router(app: Router, state: &AppState) -> Router {
+    app.route(
+        "/cf/doc",
+        get(list_collections).with_state(state.clone()),
+    )
+    .route(
+        "/cf/doc/:collection",
+        get(list_documents)
+            .with_state(state.clone())
+            .post(create_document)
+            .with_state(state.clone()),
+    )
+    .route(
+        "/cf/doc/:collection/:id",
+        get(get_document)
+            .with_state(state.clone())
+            .put(replace_document)
+            .with_state(state.clone())
+            .delete(delete_document)
+            .with_state(state.clone()),
+    )
+}
+fn app_layer(app: Router, trusted_proxies: &[IpAddr]) -> Router {
+    let trusted_proxies = Arc::new(trusted_proxies.to_vec());
+    app.layer(middleware::from_fn_with_state(trusted_proxies, client_address))
+    .layer(
         tower_http::cors::CorsLayer::new()
             .allow_methods(Any)
             .allow_headers(Any)
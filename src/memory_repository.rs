@@ -0,0 +1,919 @@
+//! The repositories kept in memory, for tests and for running without a database
+use crate::audit::{AuditQuery, AuditRecord};
+use crate::batch::{check_revisions, revision_of, BatchOutcome, ItemWrite};
+use crate::changeset::{Changeset, ChangesetStatus};
+use crate::config_schema::ConfigSchema;
+use crate::configuration::{ChangeOp, ConfigurationHistory, ConfigurationItems, ValueType};
+use crate::group::Group;
+use crate::repository::{
+    AuditRepository, ChangesetRepository, ConfigRepository, DeleteResult, DocumentRepository, GroupRepository,
+    JobRepository, RoleRepository, TenantRepository, UpdateResult, UserRepository,
+};
+use crate::role::{Permission, Role};
+use crate::schedule::{JobStatus, ScheduledJob};
+use crate::tenant::Tenant;
+use crate::user::{UserBase, UserCreationDB, UserInDB};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use mongodb::bson::{oid::ObjectId, Bson};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Default)]
+struct Store {
+    users: Vec<UserInDB>,
+    roles: Vec<Role>,
+    permissions: Vec<Permission>,
+    groups: Vec<Group>,
+    tenants: Vec<Tenant>,
+    audit: Vec<AuditRecord>,
+    items: Vec<ConfigurationItems>,
+    history: Vec<ConfigurationHistory>,
+    schemas: Vec<ConfigSchema>,
+    documents: BTreeMap<String, Vec<Map<String, Value>>>,
+    changesets: Vec<Changeset>,
+    jobs: Vec<ScheduledJob>,
+}
+
+#[derive(Default)]
+pub struct MemoryRepository {
+    store: Mutex<Store>,
+}
+
+impl MemoryRepository {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn store(&self) -> MutexGuard<'_, Store> {
+        self.store.lock().unwrap()
+    }
+}
+
+fn in_scope(tenant: &str, scope: Option<&str>) -> bool {
+    scope.is_none_or(|s| s == tenant)
+}
+
+fn is_user(u: &UserInDB, id: &str, scope: Option<&str>) -> bool {
+    u._id.as_object_id().map(|oid| oid.to_hex()).as_deref() == Some(id)
+        && in_scope(&u.user_base.tenant, scope)
+}
+
+fn is_item(i: &ConfigurationItems, tenant: &str, namespace: &str, key: &str) -> bool {
+    i.tenant == tenant && i.namespace == namespace && i.key == key
+}
+
+/// The revision a key was last deleted at, `0` when it never was
+fn deleted_revision(store: &Store, tenant: &str, namespace: &str, key: &str) -> i64 {
+    store
+        .history
+        .iter()
+        .filter(|h| is_item(&h.item, tenant, namespace, key))
+        .map(|h| h.item.revision)
+        .max()
+        .unwrap_or(0)
+}
+
+fn is_schema(s: &ConfigSchema, tenant: &str, namespace: &str, key: Option<&str>) -> bool {
+    s.tenant == tenant && s.namespace == namespace && s.key.as_deref() == key
+}
+
+fn is_document(d: &Map<String, Value>, id: &str) -> bool {
+    d.get("_id").and_then(Value::as_str) == Some(id)
+}
+
+/// Whether the fields of `d` at the dotted paths of `filter` equal its values
+fn matches_fields(d: &Map<String, Value>, filter: &Map<String, Value>) -> bool {
+    filter.iter().all(|(path, expected)| {
+        let mut parts = path.split('.');
+        let first = parts.next().and_then(|p| d.get(p));
+        parts.fold(first, |v, p| v.and_then(|v| v.get(p))) == Some(expected)
+    })
+}
+
+/// Remove the entries matching `f`, returning how many were removed
+fn remove<T>(v: &mut Vec<T>, f: impl Fn(&T) -> bool) -> DeleteResult {
+    let len = v.len();
+    v.retain(|t| !f(t));
+    DeleteResult {
+        deleted_count: (len - v.len()) as u64,
+    }
+}
+
+/// Replace the first entry matching `f`
+fn replace<T: Clone + PartialEq>(v: &mut [T], f: impl Fn(&T) -> bool, new: &T) -> UpdateResult {
+    match v.iter_mut().find(|t| f(t)) {
+        Some(t) => {
+            let modified = *t != *new;
+            *t = new.clone();
+            UpdateResult {
+                matched_count: 1,
+                modified_count: modified as u64,
+            }
+        }
+        None => UpdateResult::default(),
+    }
+}
+
+#[async_trait]
+impl UserRepository for MemoryRepository {
+    async fn insert_user(&self, user: UserCreationDB) -> anyhow::Result<String> {
+        let id = ObjectId::new();
+        self.store().users.push(user.into_stored(Bson::ObjectId(id)));
+        Ok(id.to_hex())
+    }
+
+    async fn find_user_by_id(
+        &self,
+        id: &str,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Option<UserInDB>> {
+        Ok(self.store().users.iter().find(|u| is_user(u, id, scope)).cloned())
+    }
+
+    async fn find_user_by_name(
+        &self,
+        name: &str,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Option<UserInDB>> {
+        Ok(self
+            .store()
+            .users
+            .iter()
+            .find(|u| u.user_base.name == name && in_scope(&u.user_base.tenant, scope))
+            .cloned())
+    }
+
+    async fn find_verified_user(&self, login: &str) -> anyhow::Result<Option<UserInDB>> {
+        Ok(self
+            .store()
+            .users
+            .iter()
+            .find(|u| {
+                u.user_base.email_verified
+                    && (u.user_base.name == login || u.user_base.email.as_deref() == Some(login))
+            })
+            .cloned())
+    }
+
+    async fn update_user_base(
+        &self,
+        id: &str,
+        scope: Option<&str>,
+        user_base: &UserBase,
+    ) -> anyhow::Result<UpdateResult> {
+        let mut store = self.store();
+        Ok(match store.users.iter_mut().find(|u| is_user(u, id, scope)) {
+            Some(u) => {
+                let modified = u.user_base != *user_base;
+                u.user_base = user_base.clone();
+                UpdateResult {
+                    matched_count: 1,
+                    modified_count: modified as u64,
+                }
+            }
+            None => UpdateResult::default(),
+        })
+    }
+
+    async fn set_password(&self, id: &str, password: &str) -> anyhow::Result<UpdateResult> {
+        let mut store = self.store();
+        Ok(match store.users.iter_mut().find(|u| is_user(u, id, None)) {
+            Some(u) => {
+                u.password = password.to_string();
+                UpdateResult {
+                    matched_count: 1,
+                    modified_count: 1,
+                }
+            }
+            None => UpdateResult::default(),
+        })
+    }
+
+    async fn delete_user(&self, id: &str, scope: Option<&str>) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().users, |u| is_user(u, id, scope)))
+    }
+
+    async fn count_users(&self, scope: Option<&str>) -> anyhow::Result<u64> {
+        Ok(self
+            .store()
+            .users
+            .iter()
+            .filter(|u| in_scope(&u.user_base.tenant, scope))
+            .count() as u64)
+    }
+
+    async fn count_users_with_role(&self, role: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .store()
+            .users
+            .iter()
+            .filter(|u| u.user_base.roles.iter().any(|r| r == role))
+            .count() as u64)
+    }
+
+    async fn count_users_with_permission(&self, permission: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .store()
+            .users
+            .iter()
+            .filter(|u| u.user_base.permissions.iter().any(|p| p == permission))
+            .count() as u64)
+    }
+
+    async fn list_users(
+        &self,
+        scope: Option<&str>,
+        skip: u64,
+        limit: i64,
+        sort_by_name: i8,
+    ) -> anyhow::Result<Vec<UserInDB>> {
+        let mut users: Vec<UserInDB> = self
+            .store()
+            .users
+            .iter()
+            .filter(|u| in_scope(&u.user_base.tenant, scope))
+            .cloned()
+            .collect();
+        match sort_by_name {
+            -1 => users.sort_by(|a, b| b.user_base.name.cmp(&a.user_base.name)),
+            1 => users.sort_by(|a, b| a.user_base.name.cmp(&b.user_base.name)),
+            _ => {}
+        }
+        // like MongoDB, a limit of 0 means no limit
+        let limit = if limit == 0 {
+            usize::MAX
+        } else {
+            limit.unsigned_abs() as usize
+        };
+        Ok(users.into_iter().skip(skip as usize).take(limit).collect())
+    }
+}
+
+#[async_trait]
+impl RoleRepository for MemoryRepository {
+    async fn list_roles(&self) -> anyhow::Result<Vec<Role>> {
+        Ok(self.store().roles.clone())
+    }
+
+    async fn find_role(&self, name: &str) -> anyhow::Result<Option<Role>> {
+        Ok(self.store().roles.iter().find(|r| r.name == name).cloned())
+    }
+
+    async fn insert_role(&self, role: &Role) -> anyhow::Result<()> {
+        self.store().roles.push(role.clone());
+        Ok(())
+    }
+
+    async fn replace_role(&self, role: &Role) -> anyhow::Result<UpdateResult> {
+        Ok(replace(&mut self.store().roles, |r| r.name == role.name, role))
+    }
+
+    async fn delete_role(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().roles, |r| r.name == name))
+    }
+
+    async fn count_roles_with_permission(&self, permission: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .store()
+            .roles
+            .iter()
+            .filter(|r| r.permissions.iter().any(|p| p == permission))
+            .count() as u64)
+    }
+
+    async fn list_permissions(&self) -> anyhow::Result<Vec<Permission>> {
+        Ok(self.store().permissions.clone())
+    }
+
+    async fn find_permission(&self, name: &str) -> anyhow::Result<Option<Permission>> {
+        Ok(self.store().permissions.iter().find(|p| p.name == name).cloned())
+    }
+
+    async fn insert_permission(&self, permission: &Permission) -> anyhow::Result<()> {
+        self.store().permissions.push(permission.clone());
+        Ok(())
+    }
+
+    async fn delete_permission(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().permissions, |p| p.name == name))
+    }
+
+    async fn legacy_catalog(&self) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        Ok((vec![], vec![]))
+    }
+}
+
+#[async_trait]
+impl GroupRepository for MemoryRepository {
+    async fn list_groups(&self, scope: Option<&str>) -> anyhow::Result<Vec<Group>> {
+        Ok(self
+            .store()
+            .groups
+            .iter()
+            .filter(|g| in_scope(&g.tenant, scope))
+            .cloned()
+            .collect())
+    }
+
+    async fn find_group(&self, name: &str, scope: Option<&str>) -> anyhow::Result<Option<Group>> {
+        Ok(self
+            .store()
+            .groups
+            .iter()
+            .find(|g| g.name == name && in_scope(&g.tenant, scope))
+            .cloned())
+    }
+
+    async fn insert_group(&self, group: &Group) -> anyhow::Result<()> {
+        self.store().groups.push(group.clone());
+        Ok(())
+    }
+
+    async fn replace_group(&self, group: &Group) -> anyhow::Result<UpdateResult> {
+        Ok(replace(&mut self.store().groups, |g| g.name == group.name, group))
+    }
+
+    async fn delete_group(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        let mut store = self.store();
+        for g in store.groups.iter_mut() {
+            g.groups.retain(|sub| sub != name);
+        }
+        Ok(remove(&mut store.groups, |g| g.name == name))
+    }
+
+    async fn count_groups_with_role(&self, role: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .store()
+            .groups
+            .iter()
+            .filter(|g| g.roles.iter().any(|r| r == role))
+            .count() as u64)
+    }
+}
+
+#[async_trait]
+impl TenantRepository for MemoryRepository {
+    async fn list_tenants(&self, scope: Option<&str>) -> anyhow::Result<Vec<Tenant>> {
+        Ok(self
+            .store()
+            .tenants
+            .iter()
+            .filter(|t| in_scope(&t.name, scope))
+            .cloned()
+            .collect())
+    }
+
+    async fn find_tenant(&self, name: &str) -> anyhow::Result<Option<Tenant>> {
+        Ok(self.store().tenants.iter().find(|t| t.name == name).cloned())
+    }
+
+    async fn insert_tenant(&self, tenant: &Tenant) -> anyhow::Result<()> {
+        self.store().tenants.push(tenant.clone());
+        Ok(())
+    }
+
+    async fn delete_tenant(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().tenants, |t| t.name == name))
+    }
+
+    async fn tenant_entries(&self, name: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let store = self.store();
+        let of = |tenant: &str| tenant == name;
+        let counts = [
+            ("users", store.users.iter().filter(|u| of(&u.user_base.tenant)).count()),
+            ("groups", store.groups.iter().filter(|g| of(&g.tenant)).count()),
+            ("configuration items", store.items.iter().filter(|i| of(&i.tenant)).count()),
+            (
+                "configuration revisions",
+                store.history.iter().filter(|h| of(&h.item.tenant)).count(),
+            ),
+            ("configuration schemas", store.schemas.iter().filter(|s| of(&s.tenant)).count()),
+            ("changesets", store.changesets.iter().filter(|c| of(&c.tenant)).count()),
+            ("scheduled jobs", store.jobs.iter().filter(|j| of(&j.tenant)).count()),
+        ];
+        Ok(counts
+            .into_iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(kind, n)| (kind.to_string(), n as u64))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MemoryRepository {
+    async fn insert_audit(&self, record: AuditRecord) -> anyhow::Result<()> {
+        self.store().audit.push(record);
+        Ok(())
+    }
+
+    async fn search_audit(
+        &self,
+        query: &AuditQuery,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Vec<AuditRecord>> {
+        let (skip, limit) = query.page();
+        let mut records: Vec<AuditRecord> = self
+            .store()
+            .audit
+            .iter()
+            .filter(|r| in_scope(&r.tenant, scope) && query.matches(r))
+            .cloned()
+            .collect();
+        records.sort_by_key(|r| std::cmp::Reverse(r.at));
+        Ok(records
+            .into_iter()
+            .skip(skip as usize)
+            .take(limit.unsigned_abs() as usize)
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ConfigRepository for MemoryRepository {
+    async fn create_indexes(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    async fn find_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<ConfigurationItems>> {
+        Ok(self
+            .store()
+            .items
+            .iter()
+            .find(|i| is_item(i, tenant, namespace, key))
+            .cloned())
+    }
+
+    async fn list_items(
+        &self,
+        tenant: &str,
+        namespace: Option<&str>,
+        filter: &Map<String, Value>,
+    ) -> anyhow::Result<Vec<ConfigurationItems>> {
+        let mut items: Vec<ConfigurationItems> = self
+            .store()
+            .items
+            .iter()
+            .filter(|i| i.tenant == tenant && namespace.is_none_or(|n| i.namespace == n))
+            .filter(|i| match &i.value {
+                Value::Object(value) => matches_fields(value, filter),
+                _ => filter.is_empty(),
+            })
+            .cloned()
+            .collect();
+        items.sort_by(|a, b| (&a.namespace, &a.key).cmp(&(&b.namespace, &b.key)));
+        Ok(items)
+    }
+
+    async fn put_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+        value: &Value,
+        value_type: ValueType,
+        update_by: &str,
+    ) -> anyhow::Result<ConfigurationItems> {
+        let mut store = self.store();
+        let index = match store
+            .items
+            .iter()
+            .position(|i| is_item(i, tenant, namespace, key))
+        {
+            Some(index) => index,
+            None => {
+                let revision = deleted_revision(&store, tenant, namespace, key);
+                store.items.push(ConfigurationItems {
+                    namespace: namespace.to_string(),
+                    key: key.to_string(),
+                    value: Value::Null,
+                    value_type,
+                    tenant: tenant.to_string(),
+                    revision,
+                    update_at: Utc::now(),
+                    update_by: String::new(),
+                });
+                store.items.len() - 1
+            }
+        };
+        let item = &mut store.items[index];
+        item.value = value.clone();
+        item.value_type = value_type;
+        item.revision += 1;
+        item.update_at = Utc::now();
+        item.update_by = update_by.to_string();
+        Ok(item.clone())
+    }
+
+    async fn delete_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().items, |i| {
+            is_item(i, tenant, namespace, key)
+        }))
+    }
+
+    async fn write_items(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        writes: &[ItemWrite],
+        update_by: &str,
+    ) -> anyhow::Result<BatchOutcome> {
+        let mut store = self.store();
+        let current: BTreeMap<String, ConfigurationItems> = store
+            .items
+            .iter()
+            .filter(|i| i.tenant == tenant && i.namespace == namespace)
+            .map(|i| (i.key.clone(), i.clone()))
+            .collect();
+        let conflicts = check_revisions(writes, &current);
+        if !conflicts.is_empty() {
+            return Ok(Err(conflicts));
+        }
+        let mut revisions = Vec::new();
+        for w in writes {
+            let deleted = deleted_revision(&store, tenant, namespace, &w.key);
+            let before = current.get(&w.key);
+            let revision = revision_of(tenant, namespace, w, before, deleted, update_by);
+            store.items.retain(|i| !is_item(i, tenant, namespace, &w.key));
+            if w.op == ChangeOp::Put {
+                store.items.push(revision.item.clone());
+            }
+            revisions.push(revision);
+        }
+        Ok(Ok(revisions))
+    }
+
+    async fn append_history(&self, history: &ConfigurationHistory) -> anyhow::Result<()> {
+        self.store().history.push(history.clone());
+        Ok(())
+    }
+
+    async fn list_history(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Vec<ConfigurationHistory>> {
+        let mut history: Vec<ConfigurationHistory> = self
+            .store()
+            .history
+            .iter()
+            .filter(|h| is_item(&h.item, tenant, namespace, key))
+            .cloned()
+            .collect();
+        history.sort_by_key(|h| std::cmp::Reverse(h.item.revision));
+        Ok(history)
+    }
+
+    async fn list_change(
+        &self,
+        tenant: &str,
+        change: &str,
+    ) -> anyhow::Result<Vec<ConfigurationHistory>> {
+        let mut history: Vec<ConfigurationHistory> = self
+            .store()
+            .history
+            .iter()
+            .filter(|h| h.item.tenant == tenant && h.change.as_deref() == Some(change))
+            .cloned()
+            .collect();
+        history.sort_by(|a, b| {
+            (&a.item.namespace, &a.item.key).cmp(&(&b.item.namespace, &b.item.key))
+        });
+        Ok(history)
+    }
+
+    async fn list_schemas(
+        &self,
+        tenant: &str,
+        namespace: &str,
+    ) -> anyhow::Result<Vec<ConfigSchema>> {
+        Ok(self
+            .store()
+            .schemas
+            .iter()
+            .filter(|s| s.tenant == tenant && s.namespace == namespace)
+            .cloned()
+            .collect())
+    }
+
+    async fn put_schema(&self, schema: &ConfigSchema) -> anyhow::Result<()> {
+        let mut store = self.store();
+        let key = schema.key.as_deref();
+        remove(&mut store.schemas, |s| {
+            is_schema(s, &schema.tenant, &schema.namespace, key)
+        });
+        store.schemas.push(schema.clone());
+        Ok(())
+    }
+
+    async fn delete_schema(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: Option<&str>,
+    ) -> anyhow::Result<DeleteResult> {
+        Ok(remove(&mut self.store().schemas, |s| {
+            is_schema(s, tenant, namespace, key)
+        }))
+    }
+}
+
+#[async_trait]
+impl DocumentRepository for MemoryRepository {
+    async fn insert_document(
+        &self,
+        collection: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<String> {
+        let id = ObjectId::new().to_hex();
+        let mut stored = document.clone();
+        stored.insert("_id".to_string(), Value::String(id.clone()));
+        self.store()
+            .documents
+            .entry(collection.to_string())
+            .or_default()
+            .push(stored);
+        Ok(id)
+    }
+
+    async fn find_document(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> anyhow::Result<Option<Map<String, Value>>> {
+        Ok(self
+            .store()
+            .documents
+            .get(collection)
+            .and_then(|v| v.iter().find(|d| is_document(d, id)).cloned()))
+    }
+
+    async fn list_documents(
+        &self,
+        collection: &str,
+        filter: &Map<String, Value>,
+        skip: u64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Map<String, Value>>> {
+        Ok(self
+            .store()
+            .documents
+            .get(collection)
+            .map(|v| {
+                v.iter()
+                    .filter(|d| matches_fields(d, filter))
+                    .skip(skip as usize)
+                    .take(limit.unsigned_abs() as usize)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default())
+    }
+
+    async fn replace_document(
+        &self,
+        collection: &str,
+        id: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<UpdateResult> {
+        let mut stored = document.clone();
+        stored.insert("_id".to_string(), Value::String(id.to_string()));
+        let mut store = self.store();
+        Ok(match store.documents.get_mut(collection) {
+            Some(v) => replace(v, |d| is_document(d, id), &stored),
+            None => UpdateResult::default(),
+        })
+    }
+
+    async fn delete_document(&self, collection: &str, id: &str) -> anyhow::Result<DeleteResult> {
+        let mut store = self.store();
+        Ok(match store.documents.get_mut(collection) {
+            Some(v) => remove(v, |d| is_document(d, id)),
+            None => DeleteResult::default(),
+        })
+    }
+}
+
+#[async_trait]
+impl ChangesetRepository for MemoryRepository {
+    async fn insert_changeset(&self, changeset: &Changeset) -> anyhow::Result<()> {
+        self.store().changesets.push(changeset.clone());
+        Ok(())
+    }
+
+    async fn find_changeset(&self, tenant: &str, id: &str) -> anyhow::Result<Option<Changeset>> {
+        Ok(self
+            .store()
+            .changesets
+            .iter()
+            .find(|c| c.tenant == tenant && c.id == id)
+            .cloned())
+    }
+
+    async fn list_changesets(
+        &self,
+        tenant: &str,
+        status: Option<ChangesetStatus>,
+        namespace: Option<&str>,
+    ) -> anyhow::Result<Vec<Changeset>> {
+        Ok(self
+            .store()
+            .changesets
+            .iter()
+            .rev()
+            .filter(|c| c.tenant == tenant)
+            .filter(|c| status.is_none_or(|s| c.status == s))
+            .filter(|c| namespace.is_none_or(|n| c.namespace == n))
+            .cloned()
+            .collect())
+    }
+
+    async fn replace_changeset(&self, changeset: &Changeset) -> anyhow::Result<bool> {
+        let mut store = self.store();
+        let stored = store.changesets.iter_mut().find(|c| {
+            c.tenant == changeset.tenant
+                && c.id == changeset.id
+                && c.revision == changeset.revision - 1
+        });
+        Ok(match stored {
+            Some(stored) => {
+                *stored = changeset.clone();
+                true
+            }
+            None => false,
+        })
+    }
+}
+
+#[async_trait]
+impl JobRepository for MemoryRepository {
+    async fn insert_job(&self, job: &ScheduledJob) -> anyhow::Result<()> {
+        self.store().jobs.push(job.clone());
+        Ok(())
+    }
+
+    async fn find_job(&self, tenant: &str, id: &str) -> anyhow::Result<Option<ScheduledJob>> {
+        Ok(self
+            .store()
+            .jobs
+            .iter()
+            .find(|j| j.tenant == tenant && j.id == id)
+            .cloned())
+    }
+
+    async fn list_jobs(
+        &self,
+        tenant: &str,
+        status: Option<JobStatus>,
+    ) -> anyhow::Result<Vec<ScheduledJob>> {
+        let mut jobs: Vec<ScheduledJob> = self
+            .store()
+            .jobs
+            .iter()
+            .filter(|j| j.tenant == tenant)
+            .filter(|j| status.is_none_or(|s| j.status == s))
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|j| j.apply_at);
+        Ok(jobs)
+    }
+
+    async fn replace_job(&self, job: &ScheduledJob) -> anyhow::Result<bool> {
+        let mut store = self.store();
+        let stored = store.jobs.iter_mut().find(|j| {
+            j.tenant == job.tenant && j.id == job.id && j.revision == job.revision - 1
+        });
+        Ok(match stored {
+            Some(stored) => {
+                *stored = job.clone();
+                true
+            }
+            None => false,
+        })
+    }
+
+    async fn due_jobs(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<ScheduledJob>> {
+        let mut jobs: Vec<ScheduledJob> = self
+            .store()
+            .jobs
+            .iter()
+            .filter(|j| j.due().is_some_and(|d| d <= now))
+            .cloned()
+            .collect();
+        jobs.sort_by_key(|j| j.due());
+        Ok(jobs)
+    }
+
+    async fn running_jobs(&self) -> anyhow::Result<Vec<ScheduledJob>> {
+        Ok(self
+            .store()
+            .jobs
+            .iter()
+            .filter(|j| j.is_running())
+            .cloned()
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn config_revision_test() {
+        let repo = MemoryRepository::new();
+        let number = ValueType::Number;
+        let first = repo.put_item("acme", "app", "port", &json!(80), number, "u1").await.unwrap();
+        assert_eq!(first.revision, 1);
+        let second = repo.put_item("acme", "app", "port", &json!(81), number, "u2").await.unwrap();
+        assert_eq!(second.revision, 2);
+        assert_eq!(second.update_by, "u2");
+        repo.put_item("other", "app", "port", &json!(82), number, "u3").await.unwrap();
+
+        let items = repo.list_items("acme", Some("app"), &Map::new()).await.unwrap();
+        assert_eq!(items, vec![second]);
+        let deleted = repo.delete_item("acme", "app", "port").await.unwrap();
+        assert_eq!(deleted.deleted_count, 1);
+        assert!(repo.find_item("acme", "app", "port").await.unwrap().is_none());
+        assert!(repo.find_item("other", "app", "port").await.unwrap().is_some());
+
+        // a recreated key continues after the revision of its deletion
+        let deleted_at = |revision: i64| {
+            let mut item = items[0].clone();
+            item.revision = revision;
+            ConfigurationHistory {
+                op: ChangeOp::Delete,
+                change: None,
+                item,
+            }
+        };
+        repo.append_history(&deleted_at(3)).await.unwrap();
+        let third = repo.put_item("acme", "app", "port", &json!(83), number, "u1").await.unwrap();
+        assert_eq!(third.revision, 4);
+        repo.delete_item("acme", "app", "port").await.unwrap();
+        repo.append_history(&deleted_at(5)).await.unwrap();
+        let put = ItemWrite {
+            key: "port".to_string(),
+            op: ChangeOp::Put,
+            value: json!(84),
+            value_type: number,
+            revision: Some(0),
+        };
+        let written = repo.write_items("acme", "app", &[put], "u1").await.unwrap().unwrap();
+        assert_eq!(written[0].item.revision, 6);
+    }
+
+    #[tokio::test]
+    async fn value_filter_test() {
+        let repo = MemoryRepository::new();
+        let object = ValueType::Object;
+        let db = json!({"host": "db", "pool": {"size": 10}});
+        repo.put_item("acme", "app", "db", &db, object, "u1").await.unwrap();
+        let cache = json!({"host": "cache", "pool": {"size": 2}});
+        repo.put_item("acme", "app", "cache", &cache, object, "u1").await.unwrap();
+        repo.put_item("acme", "app", "port", &json!(80), ValueType::Number, "u1").await.unwrap();
+
+        let filter = |f: Value| f.as_object().cloned().unwrap();
+        let items = repo
+            .list_items("acme", None, &filter(json!({"pool.size": 10})))
+            .await
+            .unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!(items[0].value, db);
+        let all = repo.list_items("acme", None, &Map::new()).await.unwrap();
+        assert_eq!(all.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn delete_group_test() {
+        let repo = MemoryRepository::new();
+        let group = |name: &str, groups: &[&str]| Group {
+            name: name.to_string(),
+            description: "".to_string(),
+            members: vec![],
+            groups: groups.iter().map(|s| s.to_string()).collect(),
+            roles: vec![],
+            tenant: "acme".to_string(),
+        };
+        repo.insert_group(&group("dev", &[])).await.unwrap();
+        repo.insert_group(&group("eng", &["dev"])).await.unwrap();
+        assert!(repo.find_group("dev", Some("other")).await.unwrap().is_none());
+
+        repo.delete_group("dev").await.unwrap();
+        let groups = repo.list_groups(Some("acme")).await.unwrap();
+        assert_eq!(groups, vec![group("eng", &[])]);
+    }
+}
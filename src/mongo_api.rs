@@ -1,106 +1,141 @@
-//! The module to test MongoDB features
+//! Generic, typed access to the documents of the service's MongoDB. `AppState::mongo` is
+//! handed a `MongoStore` and builds its repositories on it, the clones of a store share one
+//! client and its pool of connections
 
+use crate::config::CfConfig;
 use futures::stream::TryStreamExt;
 use mongodb::{
     bson::{doc, Bson, Document},
-    options::FindOptions,
-    results::{InsertOneResult, UpdateResult},
-    Client, Collection,
+    options::{ClientOptions, FindOptions},
+    results::{DeleteResult, InsertOneResult, UpdateResult},
+    Client, Collection, Database,
 };
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::time::Duration;
+
+/// Client options of the MongoDB configured in `db`, with its pool settings
+pub async fn client_options(config: &CfConfig) -> anyhow::Result<ClientOptions> {
+    let mut options = ClientOptions::parse(config.db_url()).await?;
+    let db = config.db();
+    options.app_name = Some("cf".to_string());
+    options.max_pool_size = db.max_pool_size.or(options.max_pool_size);
+    options.min_pool_size = db.min_pool_size.or(options.min_pool_size);
+    if let Some(secs) = db.max_idle_time_secs {
+        options.max_idle_time = Some(Duration::from_secs(secs));
+    }
+    if let Some(secs) = db.connect_timeout_secs {
+        options.connect_timeout = Some(Duration::from_secs(secs));
+    }
+    Ok(options)
+}
 
+/// Make a client, its clones share one pool of connections
+pub fn connect(options: ClientOptions) -> anyhow::Result<Client> {
+    Ok(Client::with_options(options)?)
+}
 
-static mut CLIENT: Option<Client> = None;
-
-pub fn init(client: Client) {
-    unsafe { CLIENT = Some(client) };
+/// The documents of one database, each collection typed by its caller
+#[derive(Debug, Clone)]
+pub struct MongoStore {
+    db: Database,
 }
 
-pub fn check_init() -> anyhow::Result<&'static Client> {
-    match unsafe { &CLIENT } {
-        Some(c) => Ok(c),
-        None => Err(anyhow::Error::msg("Client is None")),
+impl MongoStore {
+    pub fn new(db: Database) -> Self {
+        MongoStore { db }
     }
-}
 
-pub async fn insert_doc(
-    db: &'_ str,
-    collection: &'_ str,
-    doc: Document,
-) -> anyhow::Result<InsertOneResult> {
-    let client = check_init()?;
-    let db = client.database(db);
-    let c = db.collection(collection);
-    let r = c.insert_one(doc, None).await?;
-    Ok(r)
-}
+    /// The database, for the commands that are not about one collection
+    pub fn db(&self) -> &Database {
+        &self.db
+    }
 
-pub async fn delete_doc(db: &'_ str, collection: &'_ str, filter: Document) -> anyhow::Result<()> {
-    let client = check_init()?;
-    let db = client.database(db);
-    let c: Collection<Document> = db.collection(collection);
-    c.delete_one(filter, None).await?;
-    Ok(())
-}
+    /// The collection `collection`, holding documents of type `T`
+    pub fn collection<T>(&self, collection: &str) -> Collection<T> {
+        self.db.collection(collection)
+    }
 
-pub async fn update_doc(
-    db: &'_ str,
-    collection: &'_ str,
-    id: Bson,
-    doc: Document,
-) -> anyhow::Result<UpdateResult> {
-    let client = check_init()?;
-    let db = client.database(db);
-    let c: Collection<Document> = db.collection(collection);
-    let filter = doc! {"_id" : id};
-    let res = c.update_one(filter.clone(), doc, None).await?;
-    Ok(res)
-}
+    pub async fn insert_doc<T>(&self, collection: &str, doc: &T) -> anyhow::Result<InsertOneResult>
+    where
+        T: Serialize + Send + Sync,
+    {
+        let c: Collection<T> = self.collection(collection);
+        Ok(c.insert_one(doc, None).await?)
+    }
+
+    pub async fn delete_doc(
+        &self,
+        collection: &str,
+        filter: Document,
+    ) -> anyhow::Result<DeleteResult> {
+        let c: Collection<Document> = self.collection(collection);
+        Ok(c.delete_one(filter, None).await?)
+    }
+
+    /// Apply the update document `doc` to the document `id`
+    pub async fn update_doc(
+        &self,
+        collection: &str,
+        id: Bson,
+        doc: Document,
+    ) -> anyhow::Result<UpdateResult> {
+        let c: Collection<Document> = self.collection(collection);
+        Ok(c.update_one(doc! {"_id": id}, doc, None).await?)
+    }
 
-pub async fn find_docs(
-    db: &'_ str,
-    collection: &'_ str,
-    filter: Document,
-    size: u32,
-) -> anyhow::Result<Vec<Document>> {
-    let client = check_init()?;
-    let db = client.database(db);
-    let c: Collection<Document> = db.collection(collection);
-    let option = FindOptions::builder().batch_size(size).build();
-    let mut cursor = c.find(filter, option).await?;
-
-    let mut docs = Vec::<Document>::new();
-    while let Some(d) = cursor.try_next().await? {
-        docs.push(d);
-    }
-    Ok(docs)
+    pub async fn find_docs<T>(
+        &self,
+        collection: &str,
+        filter: Document,
+        size: u32,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: DeserializeOwned + Unpin + Send + Sync,
+    {
+        let c: Collection<T> = self.collection(collection);
+        let option = FindOptions::builder().batch_size(size).build();
+        let cursor = c.find(filter, option).await?;
+        Ok(cursor.try_collect().await?)
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use mongodb::bson::doc;
+    use futures::stream::TryStreamExt;
+    use mongodb::bson;
 
     use super::*;
 
+    #[tokio::test]
+    async fn pool_options_test() {
+        let cf = CfConfig::load("src/config/config.toml").expect("load configration file");
+        let options = client_options(&cf).await.unwrap();
+        assert_eq!(options.max_pool_size, Some(20));
+        assert_eq!(options.app_name.as_deref(), Some("cf"));
+        assert!(connect(options).is_ok());
+    }
+
     #[tokio::test]
     async fn test() {
         let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
             .await
             .expect("connect to db");
-        init(client);
+        let store = MongoStore::new(client.database("db"));
         let doc = doc! {"a": 1};
         let doc2 = doc! {"$set":{"a": 2}};
-        let res = insert_doc("db", "config", doc).await.expect("insert db");
+        let res = store.insert_doc("config", &doc).await.expect("insert db");
         println!("{:?}", res.inserted_id);
-        let res2 = update_doc("db", "config", res.inserted_id.clone(), doc2)
+        let res2 = store.update_doc("config", res.inserted_id.clone(), doc2)
             .await
             .expect("update");
         println!("update result: {:?}", res2);
         assert_eq!(res2.matched_count, 1);
-        let docs = find_docs("db", "config", doc! {"_id":res.inserted_id.clone()}, 2)
+        let docs: Vec<Document> = store.find_docs("config", doc! {"_id":res.inserted_id.clone()}, 2)
             .await
             .expect("find");
         println!("docs: {:?}", docs);
-        delete_doc("db", "config", doc! {"_id":res.inserted_id})
+        store.delete_doc("config", doc! {"_id":res.inserted_id})
             .await
             .expect("delete");
     }
@@ -110,7 +145,7 @@ mod test {
     #[derive(Debug, Serialize, Deserialize, Clone)]
     struct Configurations {
         key: String,
-        value: String, //json string
+        value: Value,
         ts: DateTime<Utc>,
     }
     #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -125,17 +160,15 @@ mod test {
             host: "localhost".to_string(),
             port: 3333,
         };
-        let val_json = serde_json::to_string(&val).unwrap();
         let doc = Configurations {
             key: "a".to_string(),
-            value: val_json,
+            value: val,
             ts: Utc::now()
         };
         let val2 = Value {
             host: "localhost".to_string(),
             port: 1111,
         };
-        let val2_json = serde_json::to_string(&val2).unwrap();
         let client = Client::with_uri_str("mongodb://127.0.0.1:27017")
             .await
             .expect("connect to db");
@@ -143,13 +176,11 @@ mod test {
         let db = client.database("db");
         let c: Collection<Configurations> = db.collection("config");
         let _r = c.insert_one(doc.clone(), None).await.expect("1");
-        let f = c.find_one(doc! {"key":"a"}, None).await.expect("2");
-        println!(
-            "sss {:?}",
-            serde_json::from_str::<Value>(&*f.unwrap().value).unwrap()
-        );
+        let f = c.find_one(doc! {"key":"a", "value.port": 3333}, None).await.expect("2");
+        println!("sss {:?}", f.unwrap().value);
+        let val2 = bson::to_bson(&val2).unwrap();
         let _u = c
-            .update_many(doc! {"key":"a"}, doc! {"$set": {"value":val2_json}}, None)
+            .update_many(doc! {"key":"a"}, doc! {"$set": {"value":val2}}, None)
             .await
             .expect("2");
         let f2 = c.find_one(doc! {"key":"a"}, None).await.expect("2");
@@ -0,0 +1,955 @@
+//! The repositories stored in MongoDB
+use crate::audit::{AuditQuery, AuditRecord, AuditRecordDB};
+use crate::batch::{check_revisions, revision_of, BatchOutcome, ItemWrite, RevisionConflict};
+use crate::changeset::{Changeset, ChangesetStatus};
+use crate::config_schema::{ConfigSchema, ConfigSchemaDB};
+use crate::configuration::{ChangeOp, ConfigurationHistory, ConfigurationItems, ValueType};
+use crate::group::Group;
+use crate::mongo_api::MongoStore;
+use crate::repository::{
+    AuditRepository, ChangesetRepository, ConfigRepository, DeleteResult, DocumentRepository, GroupRepository,
+    JobRepository, RoleRepository, TenantRepository, UpdateResult, UserRepository,
+};
+use crate::role::{Permission, Role};
+use crate::schedule::{JobStatus, ScheduledJob, ScheduledJobDB};
+use crate::tenant::{scoped, tenant_filter, Tenant};
+use crate::user::{UserBase, UserCreationDB, UserInDB};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, Document};
+use mongodb::error::{ErrorKind, WriteFailure};
+use mongodb::options::{
+    FindOneAndUpdateOptions, FindOneOptions, FindOptions, IndexOptions, ReturnDocument,
+};
+use mongodb::{results, ClientSession, Collection, IndexModel};
+use serde::de::DeserializeOwned;
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use tracing::error;
+
+const USER_COLLECTION: &str = "user";
+const ROLE_COLLECTION: &str = "role";
+const PERMISSION_COLLECTION: &str = "permission";
+/// Where roles and permissions were kept before the role collection, as `{key, values}`
+const LEGACY_COLLECTION: &str = "data";
+const GROUP_COLLECTION: &str = "group";
+const TENANT_COLLECTION: &str = "tenant";
+const AUDIT_COLLECTION: &str = "audit";
+const CONFIG_COLLECTION: &str = "config";
+const HISTORY_COLLECTION: &str = "config_history";
+const SCHEMA_COLLECTION: &str = "config_schema";
+const CHANGESET_COLLECTION: &str = "changeset";
+const JOB_COLLECTION: &str = "config_job";
+/// prefix of the physical collections holding documents, keeping them apart from ours
+const DOCUMENT_PREFIX: &str = "doc_";
+
+impl From<results::UpdateResult> for UpdateResult {
+    fn from(value: results::UpdateResult) -> Self {
+        UpdateResult {
+            matched_count: value.matched_count,
+            modified_count: value.modified_count,
+        }
+    }
+}
+
+impl From<results::DeleteResult> for DeleteResult {
+    fn from(value: results::DeleteResult) -> Self {
+        DeleteResult {
+            deleted_count: value.deleted_count,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MongoRepository {
+    store: MongoStore,
+}
+
+impl MongoRepository {
+    pub fn new(store: MongoStore) -> Self {
+        MongoRepository { store }
+    }
+
+    fn users(&self) -> Collection<UserInDB> {
+        self.store.collection(USER_COLLECTION)
+    }
+
+    fn items(&self) -> Collection<ConfigurationItems> {
+        self.store.collection(CONFIG_COLLECTION)
+    }
+
+    /// The revision a key was last deleted at, `0` when it never was
+    async fn deleted_revision(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<i64> {
+        let c: Collection<ConfigurationHistory> = self.store.collection(HISTORY_COLLECTION);
+        let options = FindOneOptions::builder().sort(doc! {"revision": -1}).build();
+        let last = c.find_one(item_filter(tenant, namespace, key), options).await?;
+        Ok(last.map_or(0, |h| h.item.revision))
+    }
+
+    fn documents(&self, collection: &str) -> Collection<Document> {
+        self.store.collection(&document_collection(collection))
+    }
+
+    /// Whether the server runs transactions, only replica set members and mongos do
+    async fn supports_transactions(&self) -> bool {
+        match self.store.db().run_command(doc! {"hello": 1}, None).await {
+            Ok(reply) => {
+                reply.contains_key("setName") || reply.get_str("msg").ok() == Some("isdbgrid")
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+async fn find_all<T>(
+    c: &Collection<T>,
+    filter: Document,
+    options: Option<FindOptions>,
+) -> anyhow::Result<Vec<T>>
+where
+    T: DeserializeOwned + Unpin + Send + Sync,
+{
+    Ok(c.find(filter, options).await?.try_collect().await?)
+}
+
+/// Filter on the id of a user, `None` when `id` is not an object id
+fn user_filter(id: &str, scope: Option<&str>) -> Option<Document> {
+    ObjectId::parse_str(id)
+        .ok()
+        .map(|oid| scoped(doc! {"_id": Bson::ObjectId(oid)}, scope))
+}
+
+fn schema_filter(tenant: &str, namespace: &str, key: Option<&str>) -> Document {
+    let key = key.map_or(Bson::Null, |k| Bson::String(k.to_string()));
+    scoped(doc! {"namespace": namespace, "key": key}, Some(tenant))
+}
+
+/// The MongoDB collection of the document API collection `collection`
+fn document_collection(collection: &str) -> String {
+    format!("{}{}", DOCUMENT_PREFIX, collection)
+}
+
+/// Filter on the id of a document, `None` when `id` is not an object id
+fn document_filter(id: &str) -> Option<Document> {
+    ObjectId::parse_str(id)
+        .ok()
+        .map(|oid| doc! {"_id": Bson::ObjectId(oid)})
+}
+
+/// A stored document as JSON, its object id as a hex string
+fn into_json(mut d: Document) -> Map<String, Value> {
+    let id = d.remove("_id");
+    let mut m = match Bson::Document(d).into_relaxed_extjson() {
+        Value::Object(m) => m,
+        _ => Map::new(),
+    };
+    if let Some(Bson::ObjectId(oid)) = id {
+        m.insert("_id".to_string(), Value::String(oid.to_hex()));
+    }
+    m
+}
+
+/// Whether a write failed on a unique index
+fn is_duplicate_key(e: &mongodb::error::Error) -> bool {
+    match e.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(w)) => w.code == 11000,
+        ErrorKind::Command(c) => c.code == 11000,
+        _ => false,
+    }
+}
+
+fn item_filter(tenant: &str, namespace: &str, key: &str) -> Document {
+    scoped(doc! {"namespace": namespace, "key": key}, Some(tenant))
+}
+
+/// Filter on an item at the revision it was read at
+fn revision_filter(item: &ConfigurationItems) -> Document {
+    let mut filter = item_filter(&item.tenant, &item.namespace, &item.key);
+    filter.insert("revision", item.revision);
+    filter
+}
+
+/// An item as it was before and after a write of a batch, `None` when missing
+struct Applied {
+    before: Option<ConfigurationItems>,
+    after: Option<ConfigurationItems>,
+}
+
+/// Apply the writes of a batch within `session` when given, each write conditioned on
+/// the revision its key was read at. `applied` receives the writes that went through
+async fn apply_writes(
+    repo: &MongoRepository,
+    mut session: Option<&mut ClientSession>,
+    tenant: &str,
+    namespace: &str,
+    writes: &[ItemWrite],
+    update_by: &str,
+    applied: &mut Vec<Applied>,
+) -> anyhow::Result<BatchOutcome> {
+    let c = &repo.items();
+    let keys: Vec<&str> = writes.iter().map(|w| w.key.as_str()).collect();
+    let filter = scoped(doc! {"namespace": namespace, "key": {"$in": keys}}, Some(tenant));
+    let found: Vec<ConfigurationItems> = match session.as_deref_mut() {
+        Some(s) => c.find_with_session(filter, None, s).await?.stream(s).try_collect().await?,
+        None => find_all(c, filter, None).await?,
+    };
+    let current: BTreeMap<String, ConfigurationItems> =
+        found.into_iter().map(|i| (i.key.clone(), i)).collect();
+    let conflicts = check_revisions(writes, &current);
+    if !conflicts.is_empty() {
+        return Ok(Err(conflicts));
+    }
+    let mut revisions = Vec::new();
+    for w in writes {
+        let before = current.get(&w.key);
+        let deleted = match before {
+            Some(_) => 0,
+            None => repo.deleted_revision(tenant, namespace, &w.key).await?,
+        };
+        let revision = revision_of(tenant, namespace, w, before, deleted, update_by);
+        let written = match (before, &w.op) {
+            (Some(b), ChangeOp::Put) => {
+                let r = match session.as_deref_mut() {
+                    Some(s) => {
+                        c.replace_one_with_session(revision_filter(b), &revision.item, None, s)
+                            .await?
+                    }
+                    None => c.replace_one(revision_filter(b), &revision.item, None).await?,
+                };
+                r.matched_count == 1
+            }
+            (None, ChangeOp::Put) => {
+                let r = match session.as_deref_mut() {
+                    Some(s) => c.insert_one_with_session(&revision.item, None, s).await,
+                    None => c.insert_one(&revision.item, None).await,
+                };
+                match r {
+                    Ok(_) => true,
+                    // inserted by someone else since it was read
+                    Err(e) if is_duplicate_key(&e) => false,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            (Some(b), ChangeOp::Delete) => {
+                let r = match session.as_deref_mut() {
+                    Some(s) => c.delete_one_with_session(revision_filter(b), None, s).await?,
+                    None => c.delete_one(revision_filter(b), None).await?,
+                };
+                r.deleted_count == 1
+            }
+            (None, ChangeOp::Delete) => false,
+        };
+        if !written {
+            // written by someone else since it was read
+            let actual = c.find_one(item_filter(tenant, namespace, &w.key), None).await?;
+            let conflict = RevisionConflict {
+                key: w.key.clone(),
+                expected: w.revision,
+                actual: actual.map_or(0, |i| i.revision),
+            };
+            return Ok(Err(vec![conflict]));
+        }
+        applied.push(Applied {
+            before: before.cloned(),
+            after: (w.op == ChangeOp::Put).then(|| revision.item.clone()),
+        });
+        revisions.push(revision);
+    }
+    Ok(Ok(revisions))
+}
+
+/// Undo the writes of a batch that failed without a transaction, newest first
+async fn compensate(c: &Collection<ConfigurationItems>, applied: Vec<Applied>) {
+    for a in applied.into_iter().rev() {
+        let res = match (a.before, a.after) {
+            (Some(before), Some(after)) => c
+                .replace_one(revision_filter(&after), before, None)
+                .await
+                .map(|_| ()),
+            (None, Some(after)) => c.delete_one(revision_filter(&after), None).await.map(|_| ()),
+            (Some(before), None) => c.insert_one(before, None).await.map(|_| ()),
+            (None, None) => Ok(()),
+        };
+        if let Err(e) = res {
+            error!("undo of a configuration batch failed, {:?}", e);
+        }
+    }
+}
+
+#[async_trait]
+impl UserRepository for MongoRepository {
+    async fn insert_user(&self, user: UserCreationDB) -> anyhow::Result<String> {
+        let c: Collection<UserCreationDB> = self.store.collection(USER_COLLECTION);
+        let r = c.insert_one(&user, None).await?;
+        Ok(match r.inserted_id {
+            Bson::ObjectId(id) => id.to_hex(),
+            _ => "".to_owned(),
+        })
+    }
+
+    async fn find_user_by_id(
+        &self,
+        id: &str,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Option<UserInDB>> {
+        match user_filter(id, scope) {
+            Some(filter) => Ok(self.users().find_one(filter, None).await?),
+            None => Ok(None),
+        }
+    }
+
+    async fn find_user_by_name(
+        &self,
+        name: &str,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Option<UserInDB>> {
+        Ok(self
+            .users()
+            .find_one(scoped(doc! {"name": name}, scope), None)
+            .await?)
+    }
+
+    async fn find_verified_user(&self, login: &str) -> anyhow::Result<Option<UserInDB>> {
+        let filter =
+            doc! {"$or": [{"name": login}, {"email": login}], "email_verified": true};
+        Ok(self.users().find_one(filter, None).await?)
+    }
+
+    async fn update_user_base(
+        &self,
+        id: &str,
+        scope: Option<&str>,
+        user_base: &UserBase,
+    ) -> anyhow::Result<UpdateResult> {
+        let Some(filter) = user_filter(id, scope) else {
+            return Ok(UpdateResult::default());
+        };
+        let update = doc! {"$set": bson::to_document(user_base)?};
+        Ok(self.users().update_one(filter, update, None).await?.into())
+    }
+
+    async fn set_password(&self, id: &str, password: &str) -> anyhow::Result<UpdateResult> {
+        let Some(filter) = user_filter(id, None) else {
+            return Ok(UpdateResult::default());
+        };
+        let update = doc! {"$set": {"password": password}};
+        Ok(self.users().update_one(filter, update, None).await?.into())
+    }
+
+    async fn delete_user(&self, id: &str, scope: Option<&str>) -> anyhow::Result<DeleteResult> {
+        let Some(filter) = user_filter(id, scope) else {
+            return Ok(DeleteResult::default());
+        };
+        Ok(self.users().delete_one(filter, None).await?.into())
+    }
+
+    async fn count_users(&self, scope: Option<&str>) -> anyhow::Result<u64> {
+        Ok(self
+            .users()
+            .count_documents(scoped(doc! {}, scope), None)
+            .await?)
+    }
+
+    async fn count_users_with_role(&self, role: &str) -> anyhow::Result<u64> {
+        Ok(self.users().count_documents(doc! {"roles": role}, None).await?)
+    }
+
+    async fn count_users_with_permission(&self, permission: &str) -> anyhow::Result<u64> {
+        Ok(self
+            .users()
+            .count_documents(doc! {"permissions": permission}, None)
+            .await?)
+    }
+
+    async fn list_users(
+        &self,
+        scope: Option<&str>,
+        skip: u64,
+        limit: i64,
+        sort_by_name: i8,
+    ) -> anyhow::Result<Vec<UserInDB>> {
+        let sort = match sort_by_name {
+            -1 => doc! {"name": -1},
+            1 => doc! {"name": 1},
+            _ => doc! {},
+        };
+        let options = FindOptions::builder()
+            .skip(Some(skip))
+            .limit(Some(limit))
+            .sort(sort)
+            .build();
+        find_all(&self.users(), scoped(doc! {}, scope), Some(options)).await
+    }
+}
+
+#[async_trait]
+impl RoleRepository for MongoRepository {
+    async fn list_roles(&self) -> anyhow::Result<Vec<Role>> {
+        find_all(&self.store.collection(ROLE_COLLECTION), doc! {}, None).await
+    }
+
+    async fn find_role(&self, name: &str) -> anyhow::Result<Option<Role>> {
+        let c: Collection<Role> = self.store.collection(ROLE_COLLECTION);
+        Ok(c.find_one(doc! {"name": name}, None).await?)
+    }
+
+    async fn insert_role(&self, role: &Role) -> anyhow::Result<()> {
+        let c: Collection<Role> = self.store.collection(ROLE_COLLECTION);
+        c.insert_one(role, None).await?;
+        Ok(())
+    }
+
+    async fn replace_role(&self, role: &Role) -> anyhow::Result<UpdateResult> {
+        let c: Collection<Role> = self.store.collection(ROLE_COLLECTION);
+        Ok(c.replace_one(doc! {"name": &role.name}, role, None).await?.into())
+    }
+
+    async fn delete_role(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        let c: Collection<Role> = self.store.collection(ROLE_COLLECTION);
+        Ok(c.delete_one(doc! {"name": name}, None).await?.into())
+    }
+
+    async fn count_roles_with_permission(&self, permission: &str) -> anyhow::Result<u64> {
+        let c: Collection<Role> = self.store.collection(ROLE_COLLECTION);
+        Ok(c.count_documents(doc! {"permissions": permission}, None).await?)
+    }
+
+    async fn list_permissions(&self) -> anyhow::Result<Vec<Permission>> {
+        find_all(&self.store.collection(PERMISSION_COLLECTION), doc! {}, None).await
+    }
+
+    async fn find_permission(&self, name: &str) -> anyhow::Result<Option<Permission>> {
+        let c: Collection<Permission> = self.store.collection(PERMISSION_COLLECTION);
+        Ok(c.find_one(doc! {"name": name}, None).await?)
+    }
+
+    async fn insert_permission(&self, permission: &Permission) -> anyhow::Result<()> {
+        let c: Collection<Permission> = self.store.collection(PERMISSION_COLLECTION);
+        c.insert_one(permission, None).await?;
+        Ok(())
+    }
+
+    async fn delete_permission(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        let c: Collection<Permission> = self.store.collection(PERMISSION_COLLECTION);
+        Ok(c.delete_one(doc! {"name": name}, None).await?.into())
+    }
+
+    async fn legacy_catalog(&self) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let docs: Vec<Document> =
+            find_all(&self.store.collection(LEGACY_COLLECTION), doc! {}, None).await?;
+        let values = |key: &str| -> Vec<String> {
+            docs.iter()
+                .filter(|d| d.get_str("key").ok() == Some(key))
+                .filter_map(|d| d.get_array("values").ok())
+                .flatten()
+                .filter_map(|v| v.as_str().map(|s| s.to_string()))
+                .collect()
+        };
+        Ok((values("roles"), values("permissions")))
+    }
+}
+
+#[async_trait]
+impl GroupRepository for MongoRepository {
+    async fn list_groups(&self, scope: Option<&str>) -> anyhow::Result<Vec<Group>> {
+        find_all(
+            &self.store.collection(GROUP_COLLECTION),
+            scoped(doc! {}, scope),
+            None,
+        )
+        .await
+    }
+
+    async fn find_group(&self, name: &str, scope: Option<&str>) -> anyhow::Result<Option<Group>> {
+        let c: Collection<Group> = self.store.collection(GROUP_COLLECTION);
+        Ok(c.find_one(scoped(doc! {"name": name}, scope), None).await?)
+    }
+
+    async fn insert_group(&self, group: &Group) -> anyhow::Result<()> {
+        let c: Collection<Group> = self.store.collection(GROUP_COLLECTION);
+        c.insert_one(group, None).await?;
+        Ok(())
+    }
+
+    async fn replace_group(&self, group: &Group) -> anyhow::Result<UpdateResult> {
+        let c: Collection<Group> = self.store.collection(GROUP_COLLECTION);
+        Ok(c.replace_one(doc! {"name": &group.name}, group, None).await?.into())
+    }
+
+    async fn delete_group(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        let c: Collection<Group> = self.store.collection(GROUP_COLLECTION);
+        c.update_many(doc! {"groups": name}, doc! {"$pull": {"groups": name}}, None)
+            .await?;
+        Ok(c.delete_one(doc! {"name": name}, None).await?.into())
+    }
+
+    async fn count_groups_with_role(&self, role: &str) -> anyhow::Result<u64> {
+        let c: Collection<Group> = self.store.collection(GROUP_COLLECTION);
+        Ok(c.count_documents(doc! {"roles": role}, None).await?)
+    }
+}
+
+#[async_trait]
+impl TenantRepository for MongoRepository {
+    async fn list_tenants(&self, scope: Option<&str>) -> anyhow::Result<Vec<Tenant>> {
+        let filter = match scope {
+            Some(t) => doc! {"name": t},
+            None => doc! {},
+        };
+        find_all(&self.store.collection(TENANT_COLLECTION), filter, None).await
+    }
+
+    async fn find_tenant(&self, name: &str) -> anyhow::Result<Option<Tenant>> {
+        let c: Collection<Tenant> = self.store.collection(TENANT_COLLECTION);
+        Ok(c.find_one(doc! {"name": name}, None).await?)
+    }
+
+    async fn insert_tenant(&self, tenant: &Tenant) -> anyhow::Result<()> {
+        let c: Collection<Tenant> = self.store.collection(TENANT_COLLECTION);
+        c.insert_one(tenant, None).await?;
+        Ok(())
+    }
+
+    async fn delete_tenant(&self, name: &str) -> anyhow::Result<DeleteResult> {
+        let c: Collection<Tenant> = self.store.collection(TENANT_COLLECTION);
+        Ok(c.delete_one(doc! {"name": name}, None).await?.into())
+    }
+
+    async fn tenant_entries(&self, name: &str) -> anyhow::Result<Vec<(String, u64)>> {
+        let kinds = [
+            ("users", USER_COLLECTION),
+            ("groups", GROUP_COLLECTION),
+            ("configuration items", CONFIG_COLLECTION),
+            ("configuration revisions", HISTORY_COLLECTION),
+            ("configuration schemas", SCHEMA_COLLECTION),
+            ("changesets", CHANGESET_COLLECTION),
+            ("scheduled jobs", JOB_COLLECTION),
+        ];
+        let mut entries = Vec::new();
+        for (kind, collection) in kinds {
+            let c: Collection<Document> = self.store.collection(collection);
+            let n = c.count_documents(scoped(doc! {}, Some(name)), None).await?;
+            if n > 0 {
+                entries.push((kind.to_string(), n));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+#[async_trait]
+impl AuditRepository for MongoRepository {
+    async fn insert_audit(&self, record: AuditRecord) -> anyhow::Result<()> {
+        let c: Collection<AuditRecordDB> = self.store.collection(AUDIT_COLLECTION);
+        c.insert_one(AuditRecordDB::from(record), None).await?;
+        Ok(())
+    }
+
+    async fn search_audit(
+        &self,
+        query: &AuditQuery,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Vec<AuditRecord>> {
+        let (skip, limit) = query.page();
+        let options = FindOptions::builder()
+            .sort(doc! {"at": -1})
+            .skip(Some(skip))
+            .limit(Some(limit))
+            .build();
+        let c: Collection<AuditRecordDB> = self.store.collection(AUDIT_COLLECTION);
+        let records = find_all(&c, scoped(query.filter(), scope), Some(options)).await?;
+        Ok(records.into_iter().map(AuditRecord::from).collect())
+    }
+}
+
+#[async_trait]
+impl ConfigRepository for MongoRepository {
+    async fn create_indexes(&self) -> anyhow::Result<()> {
+        let index = IndexModel::builder()
+            .keys(doc! {"tenant": 1, "namespace": 1, "key": 1})
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        self.items().create_index(index, None).await?;
+        Ok(())
+    }
+
+    async fn find_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<ConfigurationItems>> {
+        let c: Collection<ConfigurationItems> = self.store.collection(CONFIG_COLLECTION);
+        Ok(c.find_one(item_filter(tenant, namespace, key), None).await?)
+    }
+
+    async fn list_items(
+        &self,
+        tenant: &str,
+        namespace: Option<&str>,
+        value_filter: &Map<String, Value>,
+    ) -> anyhow::Result<Vec<ConfigurationItems>> {
+        let mut filter = tenant_filter(tenant);
+        if let Some(namespace) = namespace {
+            filter.insert("namespace", namespace);
+        }
+        for (path, value) in value_filter {
+            filter.insert(format!("value.{}", path), bson::to_bson(value)?);
+        }
+        let options = FindOptions::builder()
+            .sort(doc! {"namespace": 1, "key": 1})
+            .build();
+        find_all(&self.store.collection(CONFIG_COLLECTION), filter, Some(options)).await
+    }
+
+    async fn put_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+        value: &Value,
+        value_type: ValueType,
+        update_by: &str,
+    ) -> anyhow::Result<ConfigurationItems> {
+        let c = self.items();
+        let update = doc! {
+            "$set": {
+                "value": bson::to_bson(value)?,
+                "type": bson::to_bson(&value_type)?,
+                "update_at": bson::to_bson(&Utc::now())?,
+                "update_by": update_by,
+            },
+            "$inc": {"revision": 1_i64},
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let filter = item_filter(tenant, namespace, key);
+        if let Some(item) = c
+            .find_one_and_update(filter.clone(), update.clone(), options.clone())
+            .await?
+        {
+            return Ok(item);
+        }
+        let item = ConfigurationItems {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value: value.clone(),
+            value_type,
+            tenant: tenant.to_string(),
+            revision: self.deleted_revision(tenant, namespace, key).await? + 1,
+            update_at: Utc::now(),
+            update_by: update_by.to_string(),
+        };
+        match c.insert_one(&item, None).await {
+            Ok(_) => Ok(item),
+            // inserted by someone else meanwhile, update theirs
+            Err(e) if is_duplicate_key(&e) => c
+                .find_one_and_update(filter, update, options)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Configuration not written")),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<DeleteResult> {
+        let c: Collection<ConfigurationItems> = self.store.collection(CONFIG_COLLECTION);
+        Ok(c.delete_one(item_filter(tenant, namespace, key), None).await?.into())
+    }
+
+    async fn write_items(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        writes: &[ItemWrite],
+        update_by: &str,
+    ) -> anyhow::Result<BatchOutcome> {
+        let c = self.items();
+        let mut applied = Vec::new();
+        if !self.supports_transactions().await {
+            let outcome =
+                apply_writes(self, None, tenant, namespace, writes, update_by, &mut applied).await;
+            if !matches!(outcome, Ok(Ok(_))) {
+                compensate(&c, applied).await;
+            }
+            return outcome;
+        }
+        let mut session = c.client().start_session(None).await?;
+        session.start_transaction(None).await?;
+        let outcome = apply_writes(
+            self,
+            Some(&mut session),
+            tenant,
+            namespace,
+            writes,
+            update_by,
+            &mut applied,
+        )
+        .await;
+        if matches!(outcome, Ok(Ok(_))) {
+            session.commit_transaction().await?;
+        } else if let Err(e) = session.abort_transaction().await {
+            error!("abort of a configuration batch failed, {:?}", e);
+        }
+        outcome
+    }
+
+    async fn append_history(&self, history: &ConfigurationHistory) -> anyhow::Result<()> {
+        let c: Collection<ConfigurationHistory> = self.store.collection(HISTORY_COLLECTION);
+        c.insert_one(history, None).await?;
+        Ok(())
+    }
+
+    async fn list_history(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Vec<ConfigurationHistory>> {
+        let options = FindOptions::builder().sort(doc! {"revision": -1}).build();
+        find_all(
+            &self.store.collection(HISTORY_COLLECTION),
+            item_filter(tenant, namespace, key),
+            Some(options),
+        )
+        .await
+    }
+
+    async fn list_change(
+        &self,
+        tenant: &str,
+        change: &str,
+    ) -> anyhow::Result<Vec<ConfigurationHistory>> {
+        let options = FindOptions::builder()
+            .sort(doc! {"namespace": 1, "key": 1})
+            .build();
+        find_all(
+            &self.store.collection(HISTORY_COLLECTION),
+            scoped(doc! {"change": change}, Some(tenant)),
+            Some(options),
+        )
+        .await
+    }
+
+    async fn list_schemas(
+        &self,
+        tenant: &str,
+        namespace: &str,
+    ) -> anyhow::Result<Vec<ConfigSchema>> {
+        let schemas: Vec<ConfigSchemaDB> = find_all(
+            &self.store.collection(SCHEMA_COLLECTION),
+            scoped(doc! {"namespace": namespace}, Some(tenant)),
+            None,
+        )
+        .await?;
+        schemas.into_iter().map(ConfigSchema::try_from).collect()
+    }
+
+    async fn put_schema(&self, schema: &ConfigSchema) -> anyhow::Result<()> {
+        let c: Collection<ConfigSchemaDB> = self.store.collection(SCHEMA_COLLECTION);
+        let filter = schema_filter(&schema.tenant, &schema.namespace, schema.key.as_deref());
+        let options = mongodb::options::ReplaceOptions::builder().upsert(true).build();
+        c.replace_one(filter, ConfigSchemaDB::from(schema), options).await?;
+        Ok(())
+    }
+
+    async fn delete_schema(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: Option<&str>,
+    ) -> anyhow::Result<DeleteResult> {
+        let c: Collection<ConfigSchemaDB> = self.store.collection(SCHEMA_COLLECTION);
+        Ok(c.delete_one(schema_filter(tenant, namespace, key), None).await?.into())
+    }
+}
+
+#[async_trait]
+impl DocumentRepository for MongoRepository {
+    async fn insert_document(
+        &self,
+        collection: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<String> {
+        let res = self
+            .store
+            .insert_doc(&document_collection(collection), &bson::to_document(document)?)
+            .await?;
+        res.inserted_id
+            .as_object_id()
+            .map(|oid| oid.to_hex())
+            .ok_or_else(|| anyhow::anyhow!("Document id is not an object id"))
+    }
+
+    async fn find_document(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> anyhow::Result<Option<Map<String, Value>>> {
+        let Some(filter) = document_filter(id) else {
+            return Ok(None);
+        };
+        Ok(self
+            .documents(collection)
+            .find_one(filter, None)
+            .await?
+            .map(into_json))
+    }
+
+    async fn list_documents(
+        &self,
+        collection: &str,
+        filter: &Map<String, Value>,
+        skip: u64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Map<String, Value>>> {
+        let options = FindOptions::builder()
+            .sort(doc! {"_id": 1})
+            .skip(skip)
+            .limit(limit)
+            .build();
+        let documents = find_all(
+            &self.documents(collection),
+            bson::to_document(filter)?,
+            Some(options),
+        )
+        .await?;
+        Ok(documents.into_iter().map(into_json).collect())
+    }
+
+    async fn replace_document(
+        &self,
+        collection: &str,
+        id: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<UpdateResult> {
+        let Some(filter) = document_filter(id) else {
+            return Ok(UpdateResult::default());
+        };
+        Ok(self
+            .documents(collection)
+            .replace_one(filter, bson::to_document(document)?, None)
+            .await?
+            .into())
+    }
+
+    async fn delete_document(&self, collection: &str, id: &str) -> anyhow::Result<DeleteResult> {
+        let Some(filter) = document_filter(id) else {
+            return Ok(DeleteResult::default());
+        };
+        Ok(self
+            .store
+            .delete_doc(&document_collection(collection), filter)
+            .await?
+            .into())
+    }
+}
+
+#[async_trait]
+impl ChangesetRepository for MongoRepository {
+    async fn insert_changeset(&self, changeset: &Changeset) -> anyhow::Result<()> {
+        let c: Collection<Changeset> = self.store.collection(CHANGESET_COLLECTION);
+        c.insert_one(changeset, None).await?;
+        Ok(())
+    }
+
+    async fn find_changeset(&self, tenant: &str, id: &str) -> anyhow::Result<Option<Changeset>> {
+        let c: Collection<Changeset> = self.store.collection(CHANGESET_COLLECTION);
+        Ok(c.find_one(scoped(doc! {"id": id}, Some(tenant)), None).await?)
+    }
+
+    async fn list_changesets(
+        &self,
+        tenant: &str,
+        status: Option<ChangesetStatus>,
+        namespace: Option<&str>,
+    ) -> anyhow::Result<Vec<Changeset>> {
+        let mut filter = scoped(doc! {}, Some(tenant));
+        if let Some(status) = status {
+            filter.insert("status", bson::to_bson(&status)?);
+        }
+        if let Some(namespace) = namespace {
+            filter.insert("namespace", namespace);
+        }
+        let options = FindOptions::builder().sort(doc! {"_id": -1}).build();
+        find_all(&self.store.collection(CHANGESET_COLLECTION), filter, Some(options)).await
+    }
+
+    async fn replace_changeset(&self, changeset: &Changeset) -> anyhow::Result<bool> {
+        let c: Collection<Changeset> = self.store.collection(CHANGESET_COLLECTION);
+        let filter = scoped(
+            doc! {"id": &changeset.id, "revision": changeset.revision - 1},
+            Some(&changeset.tenant),
+        );
+        Ok(c.replace_one(filter, changeset, None).await?.matched_count == 1)
+    }
+}
+
+#[async_trait]
+impl JobRepository for MongoRepository {
+    async fn insert_job(&self, job: &ScheduledJob) -> anyhow::Result<()> {
+        let c: Collection<ScheduledJobDB> = self.store.collection(JOB_COLLECTION);
+        c.insert_one(ScheduledJobDB::from(job.clone()), None).await?;
+        Ok(())
+    }
+
+    async fn find_job(&self, tenant: &str, id: &str) -> anyhow::Result<Option<ScheduledJob>> {
+        let c: Collection<ScheduledJobDB> = self.store.collection(JOB_COLLECTION);
+        Ok(c
+            .find_one(scoped(doc! {"id": id}, Some(tenant)), None)
+            .await?
+            .map(|j| j.job))
+    }
+
+    async fn list_jobs(
+        &self,
+        tenant: &str,
+        status: Option<JobStatus>,
+    ) -> anyhow::Result<Vec<ScheduledJob>> {
+        let mut filter = scoped(doc! {}, Some(tenant));
+        if let Some(status) = status {
+            filter.insert("status", bson::to_bson(&status)?);
+        }
+        let options = FindOptions::builder().sort(doc! {"apply_at": 1}).build();
+        let jobs: Vec<ScheduledJobDB> =
+            find_all(&self.store.collection(JOB_COLLECTION), filter, Some(options)).await?;
+        Ok(jobs.into_iter().map(|j| j.job).collect())
+    }
+
+    async fn replace_job(&self, job: &ScheduledJob) -> anyhow::Result<bool> {
+        let c: Collection<ScheduledJobDB> = self.store.collection(JOB_COLLECTION);
+        let filter = scoped(
+            doc! {"id": &job.id, "revision": job.revision - 1},
+            Some(&job.tenant),
+        );
+        let record = ScheduledJobDB::from(job.clone());
+        Ok(c.replace_one(filter, record, None).await?.matched_count == 1)
+    }
+
+    async fn due_jobs(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<ScheduledJob>> {
+        let filter = doc! {"due": {"$lte": bson::DateTime::from_millis(now.timestamp_millis())}};
+        let options = FindOptions::builder().sort(doc! {"due": 1}).build();
+        let jobs: Vec<ScheduledJobDB> =
+            find_all(&self.store.collection(JOB_COLLECTION), filter, Some(options)).await?;
+        Ok(jobs.into_iter().map(|j| j.job).collect())
+    }
+
+    async fn running_jobs(&self) -> anyhow::Result<Vec<ScheduledJob>> {
+        let running = [JobStatus::Applying, JobStatus::Reverting];
+        let filter = doc! {"status": {"$in": bson::to_bson(&running)?}};
+        let jobs: Vec<ScheduledJobDB> =
+            find_all(&self.store.collection(JOB_COLLECTION), filter, None).await?;
+        Ok(jobs.into_iter().map(|j| j.job).collect())
+    }
+}
@@ -0,0 +1,320 @@
+//! Layered configuration. A key of an application in an environment resolves through
+//! the layers environment (namespace `<app>/<env>`) → application (namespace `<app>`)
+//! → global defaults (namespace `global`), the most specific layer holding the key wins
+use crate::configuration::{reveal, target_tenant, ConfigurationItems, ValueType};
+use crate::interpolate::interpolate;
+use crate::repository::internal;
+use crate::resource::{resource_path, Access};
+use crate::secret::REDACTED;
+use crate::state::AppState;
+use crate::{principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// The namespace of the defaults shared by every application
+pub const GLOBAL_NAMESPACE: &str = "global";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    Environment,
+    Application,
+    Global,
+}
+
+impl Layer {
+    /// The layers of an application, from the most specific
+    pub const ALL: [Layer; 3] = [Layer::Environment, Layer::Application, Layer::Global];
+
+    pub fn namespace(self, app: &str, env: &str) -> String {
+        match self {
+            Layer::Environment => format!("{}/{}", app, env),
+            Layer::Application => app.to_string(),
+            Layer::Global => GLOBAL_NAMESPACE.to_string(),
+        }
+    }
+}
+
+/// The value a key resolves to and where it comes from
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ResolvedItem {
+    pub key: String,
+    pub value: Value,
+    #[serde(rename = "type")]
+    pub value_type: ValueType,
+    /// the layer holding the value
+    pub source: Layer,
+    pub namespace: String,
+    pub revision: i64,
+    /// the paths of the secrets the interpolated value depends on
+    #[serde(skip)]
+    pub secrets: BTreeSet<String>,
+}
+
+impl ResolvedItem {
+    fn new(item: ConfigurationItems, source: Layer) -> Self {
+        ResolvedItem {
+            key: item.key,
+            value: item.value,
+            value_type: item.value_type,
+            source,
+            namespace: item.namespace,
+            revision: item.revision,
+            secrets: BTreeSet::new(),
+        }
+    }
+
+    pub fn redacted(mut self) -> Self {
+        if self.value_type == ValueType::Secret {
+            self.value = Value::String(REDACTED.to_string());
+        }
+        self
+    }
+}
+
+/// Resolve every key through `layers`, ordered from the most specific. Sorted by key
+pub fn resolve(layers: Vec<(Layer, Vec<ConfigurationItems>)>) -> Vec<ResolvedItem> {
+    let mut resolved: BTreeMap<String, ResolvedItem> = BTreeMap::new();
+    for (layer, items) in layers {
+        for item in items {
+            if !resolved.contains_key(&item.key) {
+                resolved.insert(item.key.clone(), ResolvedItem::new(item, layer));
+            }
+        }
+    }
+    resolved.into_values().collect()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DiffStatus {
+    /// only in the target environment
+    Added,
+    /// only in the source environment
+    Removed,
+    Changed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct DiffEntry {
+    pub key: String,
+    pub status: DiffStatus,
+    pub from: Option<ResolvedItem>,
+    pub to: Option<ResolvedItem>,
+}
+
+/// The keys resolving to different values in `from` and `to`, sorted by key.
+/// The layer a value comes from does not matter, only the value and its type
+pub fn diff(from: Vec<ResolvedItem>, to: Vec<ResolvedItem>) -> Vec<DiffEntry> {
+    let mut from: BTreeMap<String, ResolvedItem> =
+        from.into_iter().map(|i| (i.key.clone(), i)).collect();
+    let mut to: BTreeMap<String, ResolvedItem> =
+        to.into_iter().map(|i| (i.key.clone(), i)).collect();
+    let keys: BTreeSet<String> = from.keys().chain(to.keys()).cloned().collect();
+    keys.into_iter()
+        .filter_map(|key| {
+            let (f, t) = (from.remove(&key), to.remove(&key));
+            let status = match (&f, &t) {
+                (None, Some(_)) => DiffStatus::Added,
+                (Some(_), None) => DiffStatus::Removed,
+                (Some(f), Some(t)) if f.value != t.value || f.value_type != t.value_type => {
+                    DiffStatus::Changed
+                }
+                _ => return None,
+            };
+            Some(DiffEntry {
+                key,
+                status,
+                from: f,
+                to: t,
+            })
+        })
+        .collect()
+}
+
+/// Check an application or environment name, they are namespace segments
+fn check_name(name: &str) -> Result<(), (StatusCode, String)> {
+    if name.is_empty() || name.contains('/') || name == GLOBAL_NAMESPACE {
+        return Err((StatusCode::BAD_REQUEST, format!("Invalid name: {}", name)));
+    }
+    Ok(())
+}
+
+/// The configuration of `app` in `env` the caller can read.
+/// Secrets are decrypted when `plain` and a master key is configured, they are sealed otherwise
+pub(crate) async fn resolve_for(
+    state: &AppState,
+    p: &Principal,
+    tenant: &str,
+    app: &str,
+    env: &str,
+    plain: bool,
+) -> Result<Vec<ResolvedItem>, (StatusCode, String)> {
+    check_name(app)?;
+    check_name(env)?;
+    let mut layers = Vec::new();
+    for layer in Layer::ALL {
+        let mut items = state
+            .configs
+            .list_items(tenant, Some(&layer.namespace(app, env)), &Map::new())
+            .await
+            .map_err(internal)?;
+        if plain && state.master_key.is_some() {
+            for item in items.iter_mut() {
+                reveal(state, item)?;
+            }
+        }
+        layers.push((layer, items));
+    }
+    Ok(resolve(layers)
+        .into_iter()
+        .filter(|i| p.can_access(Access::Read, &resource_path(&i.namespace, &i.key)))
+        .collect())
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct OverlayQuery {
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DiffQuery {
+    pub from: String,
+    pub to: String,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+/// Every key of an application in an environment with its value and source layer,
+/// references between values interpolated and secrets redacted
+pub async fn get_resolved_configuration(
+    headers: HeaderMap,
+    Path((app, env)): Path<(String, String)>,
+    state: State<AppState>,
+    Query(query): Query<OverlayQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_resolved_configuration", &app).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let items = resolve_for(&state, &p, &tenant, &app, &env, true).await?;
+    let items: Vec<ResolvedItem> = interpolate(&state, &p, &tenant, items)
+        .await?
+        .into_iter()
+        .map(ResolvedItem::redacted)
+        .collect();
+    Ok(serde_json::to_string(&items).unwrap())
+}
+
+/// The keys of an application resolving differently in two environments.
+/// Secrets are compared by their plain values but returned redacted
+pub async fn get_configuration_diff(
+    headers: HeaderMap,
+    Path(app): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<DiffQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_configuration_diff", &app).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let from = resolve_for(&state, &p, &tenant, &app, &query.from, true).await?;
+    let from = interpolate(&state, &p, &tenant, from).await?;
+    let to = resolve_for(&state, &p, &tenant, &app, &query.to, true).await?;
+    let to = interpolate(&state, &p, &tenant, to).await?;
+    let entries: Vec<DiffEntry> = diff(from, to)
+        .into_iter()
+        .map(|e| DiffEntry {
+            from: e.from.map(ResolvedItem::redacted),
+            to: e.to.map(ResolvedItem::redacted),
+            ..e
+        })
+        .collect();
+    Ok(serde_json::to_string(&entries).unwrap())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::Utc;
+    use serde_json::json;
+
+    fn item(namespace: &str, key: &str, value: Value) -> ConfigurationItems {
+        ConfigurationItems {
+            namespace: namespace.to_string(),
+            key: key.to_string(),
+            value_type: ValueType::of(&value).unwrap(),
+            value,
+            tenant: "default".to_string(),
+            revision: 1,
+            update_at: Utc::now(),
+            update_by: "u".to_string(),
+        }
+    }
+
+    fn layers(env: &str, port: i64) -> Vec<(Layer, Vec<ConfigurationItems>)> {
+        vec![
+            (
+                Layer::Environment,
+                vec![item(&format!("shop/{}", env), "db.port", json!(port))],
+            ),
+            (
+                Layer::Application,
+                vec![
+                    item("shop", "db.port", json!(5432)),
+                    item("shop", "db.host", json!("db")),
+                ],
+            ),
+            (
+                Layer::Global,
+                vec![
+                    item("global", "db.host", json!("localhost")),
+                    item("global", "region", json!("eu")),
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn resolve_test() {
+        let resolved = resolve(layers("prod", 6432));
+        let view: Vec<(&str, &Value, Layer)> = resolved
+            .iter()
+            .map(|i| (i.key.as_str(), &i.value, i.source))
+            .collect();
+        assert_eq!(
+            view,
+            vec![
+                ("db.host", &json!("db"), Layer::Application),
+                ("db.port", &json!(6432), Layer::Environment),
+                ("region", &json!("eu"), Layer::Global),
+            ]
+        );
+        assert_eq!(Layer::Environment.namespace("shop", "prod"), "shop/prod");
+        assert!(check_name("global").is_err());
+        assert!(check_name("a/b").is_err());
+    }
+
+    #[test]
+    fn diff_test() {
+        let staging = resolve(layers("staging", 6432));
+        let mut prod_layers = layers("prod", 7432);
+        prod_layers[2].1.pop();
+        prod_layers[2].1.push(item("global", "zone", json!("a")));
+        let entries = diff(staging, resolve(prod_layers));
+        let view: Vec<(&str, DiffStatus)> =
+            entries.iter().map(|e| (e.key.as_str(), e.status)).collect();
+        assert_eq!(
+            view,
+            vec![
+                ("db.port", DiffStatus::Changed),
+                ("region", DiffStatus::Removed),
+                ("zone", DiffStatus::Added),
+            ]
+        );
+        assert_eq!(entries[0].to.as_ref().unwrap().value, json!(7432));
+    }
+}
@@ -0,0 +1,491 @@
+//! The resolved configuration of an application in an environment rendered as a file.
+//! Keys are nested on `.` for TOML, YAML and JSON, flattened for dotenv and properties,
+//! every format lists keys in order so the same configuration always renders the same bytes
+use crate::audit::AuditContext;
+use crate::configuration::{target_tenant, ValueType};
+use crate::interpolate::interpolate;
+use crate::overlay::{resolve_for, ResolvedItem};
+use crate::principal;
+use crate::resource::{resource_path, Access};
+use crate::state::AppState;
+use crate::validation::{into_response, FieldError};
+use axum::http::header::{HeaderMap, HeaderValue, CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::response::{IntoResponse, Response};
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Json,
+    Toml,
+    Yaml,
+    Dotenv,
+    Properties,
+}
+
+impl Format {
+    fn content_type(self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::Toml => "application/toml",
+            Format::Yaml => "application/yaml",
+            Format::Dotenv | Format::Properties => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct RenderQuery {
+    #[serde(default)]
+    pub format: Format,
+    /// render secrets in plain, needs reveal access on each secret a value is made of
+    pub reveal: Option<bool>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+fn conflict(key: &str) -> FieldError {
+    FieldError {
+        field: key.to_string(),
+        message: "conflicts with a key nested under it".to_string(),
+    }
+}
+
+/// Rebuild objects with their fields in order
+fn sorted(value: Value) -> Value {
+    match value {
+        Value::Object(fields) => {
+            let mut fields: Vec<(String, Value)> = fields.into_iter().collect();
+            fields.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(fields.into_iter().map(|(k, v)| (k, sorted(v))).collect())
+        }
+        Value::Array(items) => Value::Array(items.into_iter().map(sorted).collect()),
+        v => v,
+    }
+}
+
+/// Insert `value` at the path `segments`, false when a value is already there or above
+fn insert(map: &mut Map<String, Value>, segments: &[&str], value: &Value) -> bool {
+    match segments {
+        [] => false,
+        [last] if map.contains_key(*last) => false,
+        [last] => {
+            map.insert(last.to_string(), value.clone());
+            true
+        }
+        [first, rest @ ..] => match map
+            .entry(first.to_string())
+            .or_insert_with(|| Value::Object(Map::new()))
+        {
+            Value::Object(m) => insert(m, rest, value),
+            _ => false,
+        },
+    }
+}
+
+/// Nest the keys on `.`, `db.host` becoming the field `host` of the object `db`
+fn nest(items: &[ResolvedItem]) -> Result<Value, Vec<FieldError>> {
+    let mut root = Map::new();
+    let mut errors = Vec::new();
+    for item in items {
+        let segments: Vec<&str> = item.key.split('.').collect();
+        if !insert(&mut root, &segments, &item.value) {
+            errors.push(conflict(&item.key));
+        }
+    }
+    if errors.is_empty() {
+        Ok(sorted(Value::Object(root)))
+    } else {
+        Err(errors)
+    }
+}
+
+/// A value as flat text, strings as they are and the others as JSON
+fn text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        v => sorted(v.clone()).to_string(),
+    }
+}
+
+/// `db.host` as `DB_HOST`
+fn env_name(key: &str) -> String {
+    key.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_uppercase()
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// One variable per key, keys becoming the same variable, like `db.host` and `db_host`, are refused
+fn dotenv(items: &[ResolvedItem]) -> Result<String, Vec<FieldError>> {
+    let mut lines: BTreeMap<String, (&str, String)> = BTreeMap::new();
+    let mut errors = Vec::new();
+    for i in items {
+        let name = env_name(&i.key);
+        if let Some((other, _)) = lines.get(&name) {
+            errors.push(FieldError {
+                field: i.key.clone(),
+                message: format!("becomes {} like {}", name, other),
+            });
+            continue;
+        }
+        let value = text(&i.value)
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        lines.insert(name, (&i.key, format!("\"{}\"", value)));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(lines
+        .iter()
+        .map(|(k, (_, v))| format!("{}={}\n", k, v))
+        .collect())
+}
+
+fn escape_property(s: &str, key: bool) -> String {
+    let mut out = String::new();
+    for (i, c) in s.chars().enumerate() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            '=' | ':' | '#' | '!' if key => {
+                out.push('\\');
+                out.push(c);
+            }
+            ' ' if key || i == 0 => out.push_str("\\ "),
+            c if !c.is_ascii() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn properties(items: &[ResolvedItem]) -> String {
+    items
+        .iter()
+        .map(|i| {
+            format!(
+                "{}={}\n",
+                escape_property(&i.key, true),
+                escape_property(&text(&i.value), false)
+            )
+        })
+        .collect()
+}
+
+/// Render resolved items, sorted by key
+pub fn render(items: &[ResolvedItem], format: Format) -> Result<String, (StatusCode, String)> {
+    let unrenderable = |e: String| {
+        into_response(vec![FieldError {
+            field: "/".to_string(),
+            message: e,
+        }])
+    };
+    match format {
+        Format::Dotenv => dotenv(items).map_err(into_response),
+        Format::Properties => Ok(properties(items)),
+        Format::Json => {
+            let tree = nest(items).map_err(into_response)?;
+            Ok(serde_json::to_string_pretty(&tree).unwrap())
+        }
+        Format::Toml => {
+            let tree = nest(items).map_err(into_response)?;
+            toml::to_string(&tree).map_err(|e| unrenderable(e.to_string()))
+        }
+        Format::Yaml => {
+            let tree = nest(items).map_err(into_response)?;
+            serde_yaml::to_string(&tree).map_err(|e| unrenderable(e.to_string()))
+        }
+    }
+}
+
+/// A strong ETag of a rendered body
+fn etag(body: &str) -> String {
+    let digest = Sha256::digest(body.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("\"{}\"", hex)
+}
+
+/// The resolved configuration of an application in an environment as a file.
+/// Answers 304 when `If-None-Match` holds the ETag of the current rendering
+pub async fn render_configuration(
+    headers: HeaderMap,
+    Path((app, env)): Path<(String, String)>,
+    state: State<AppState>,
+    Query(query): Query<RenderQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let p = principal(&headers, &state, "render_configuration", &app).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let items = resolve_for(&state, &p, &tenant, &app, &env, true).await?;
+    let mut items = interpolate(&state, &p, &tenant, items).await?;
+    let reveal = query.reveal == Some(true);
+    let mut revealed = BTreeSet::new();
+    for item in items.iter_mut() {
+        if item.value_type != ValueType::Secret {
+            continue;
+        }
+        // a value is only as visible as the least visible secret it is made of
+        let mut sources = item.secrets.clone();
+        if sources.is_empty() {
+            sources.insert(resource_path(&item.namespace, &item.key));
+        }
+        if reveal && sources.iter().all(|s| p.can_access(Access::Reveal, s)) {
+            revealed.extend(sources);
+        } else {
+            *item = item.clone().redacted();
+        }
+    }
+    if !revealed.is_empty() {
+        let target = format!("{}/{}", app, env);
+        let audit = AuditContext::new(&headers, &p.profile, "render_configuration", &target);
+        let res: Result<(), (StatusCode, String)> = Ok(());
+        audit
+            .record(&state, &res, None, Some(serde_json::json!(revealed)))
+            .await;
+    }
+    let body = render(&items, query.format)?;
+    let tag = etag(&body);
+    let matches = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(',').any(|t| t.trim() == tag || t.trim() == "*"));
+    let tag = HeaderValue::from_str(&tag).unwrap();
+    if matches {
+        return Ok((StatusCode::NOT_MODIFIED, [(ETAG, tag)]).into_response());
+    }
+    let content_type = HeaderValue::from_static(query.format.content_type());
+    Ok(([(CONTENT_TYPE, content_type), (ETAG, tag)], body).into_response())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::audit::AuditQuery;
+    use crate::configuration::seal;
+    use crate::mail::MemoryMailer;
+    use crate::overlay::Layer;
+    use crate::secret::MasterKey;
+    use crate::token::generate_token;
+    use crate::user::{stored_headers, UserBase, UserProfile};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn item(key: &str, value: Value) -> ResolvedItem {
+        ResolvedItem {
+            key: key.to_string(),
+            value_type: ValueType::of(&value).unwrap(),
+            value,
+            source: Layer::Application,
+            namespace: "shop".to_string(),
+            revision: 1,
+            secrets: Default::default(),
+        }
+    }
+
+    fn items() -> Vec<ResolvedItem> {
+        vec![
+            item("db.host", json!("db.local")),
+            item("db.port", json!(5432)),
+            item("feature", json!({"b": true, "a": [1, 2]})),
+            item("name", json!("shop \"main\"")),
+        ]
+    }
+
+    #[test]
+    fn render_test() {
+        let toml = render(&items(), Format::Toml).unwrap();
+        assert_eq!(
+            toml,
+            "name = \"shop \\\"main\\\"\"\n\n[db]\nhost = \"db.local\"\nport = 5432\n\n[feature]\na = [1, 2]\nb = true\n"
+        );
+        let yaml = render(&items(), Format::Yaml).unwrap();
+        assert!(yaml.starts_with("db:\n  host: db.local\n  port: 5432\nfeature:\n  a:\n"));
+        let json: Value = serde_json::from_str(&render(&items(), Format::Json).unwrap()).unwrap();
+        assert_eq!(json["db"]["port"], json!(5432));
+        assert_eq!(
+            render(&items(), Format::Dotenv).unwrap(),
+            "DB_HOST=\"db.local\"\nDB_PORT=\"5432\"\nFEATURE=\"{\\\"a\\\":[1,2],\\\"b\\\":true}\"\nNAME=\"shop \\\"main\\\"\"\n"
+        );
+        assert_eq!(
+            render(&items()[..2], Format::Properties).unwrap(),
+            "db.host=db.local\ndb.port=5432\n"
+        );
+        assert_eq!(escape_property("a b=c", true), "a\\ b\\=c");
+
+        // deterministic whatever the order of the fields of values
+        let mut shuffled = items();
+        shuffled[2].value = json!({"a": [1, 2], "b": true});
+        assert_eq!(etag(&render(&shuffled, Format::Toml).unwrap()), etag(&toml));
+    }
+
+    #[test]
+    fn conflict_test() {
+        let items = vec![item("db", json!("x")), item("db.host", json!("y"))];
+        let err = render(&items, Format::Json).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(err.1.contains("db.host"));
+        assert!(render(&items, Format::Properties).is_ok());
+
+        let items = vec![item("db.host", json!("x")), item("db_host", json!("y"))];
+        let err = render(&items, Format::Dotenv).unwrap_err();
+        assert_eq!(err.0, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(err.1.contains("db_host") && err.1.contains("becomes DB_HOST like db.host"));
+        assert!(render(&items, Format::Json).is_ok());
+    }
+
+    #[tokio::test]
+    async fn etag_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let put = |key: &'static str, value: Value| {
+            let state = state.clone();
+            async move {
+                let value_type = ValueType::of(&value).unwrap();
+                state
+                    .configs
+                    .put_item("default", "shop", key, &value, value_type, "u")
+                    .await
+                    .unwrap();
+            }
+        };
+        put("db.port", json!(5432)).await;
+        put("db.url", json!("postgres://db:${db.port}")).await;
+        let path = || Path(("shop".to_string(), "prod".to_string()));
+        let query = || {
+            Query(RenderQuery {
+                format: Format::Dotenv,
+                ..Default::default()
+            })
+        };
+        let res = render_configuration(headers.clone(), path(), state.clone(), query())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let tag = res.headers()[ETAG].clone();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        assert_eq!(body, "DB_PORT=\"5432\"\nDB_URL=\"postgres://db:5432\"\n");
+
+        headers.insert(IF_NONE_MATCH, tag.clone());
+        let res = render_configuration(headers.clone(), path(), state.clone(), query())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_MODIFIED);
+
+        put("db.port", json!(6432)).await;
+        let res = render_configuration(headers.clone(), path(), state.clone(), query())
+            .await
+            .unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_ne!(res.headers()[ETAG], tag);
+    }
+
+    #[tokio::test]
+    async fn reveal_test() {
+        let state = State(
+            AppState::memory(Arc::new(MemoryMailer::default()), "http://cf")
+                .with_master_key(MasterKey::new(&[7u8; 32]).unwrap()),
+        );
+        let password = seal(
+            &state,
+            "default",
+            "shared",
+            "db.password",
+            &json!("hunter2"),
+        )
+        .unwrap();
+        state
+            .configs
+            .put_item(
+                "default",
+                "shared",
+                "db.password",
+                &password,
+                ValueType::Secret,
+                "u",
+            )
+            .await
+            .unwrap();
+        let url = json!("postgres://app:${shared/db.password}@db");
+        state
+            .configs
+            .put_item("default", "shop", "db.url", &url, ValueType::String, "u")
+            .await
+            .unwrap();
+        let render_as = |permissions: Vec<&str>| {
+            let state = state.clone();
+            let user = UserBase {
+                name: "renderer".to_string(),
+                permissions: permissions.into_iter().map(String::from).collect(),
+                ..Default::default()
+            };
+            async move {
+                let headers = stored_headers(&state, user).await;
+                let path = Path(("shop".to_string(), "prod".to_string()));
+                let query = Query(RenderQuery {
+                    format: Format::Dotenv,
+                    reveal: Some(true),
+                    ..Default::default()
+                });
+                let res = render_configuration(headers, path, state, query)
+                    .await
+                    .unwrap();
+                axum::body::to_bytes(res.into_body(), usize::MAX)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        // reveal on the key holding the reference is not enough
+        let body = render_as(vec![
+            "render_configuration",
+            "config:read:shared/*",
+            "config:reveal:shop/*",
+        ])
+        .await;
+        assert_eq!(body, "DB_URL=\"******\"\n");
+        assert!(state
+            .audit
+            .search_audit(&AuditQuery::default(), None)
+            .await
+            .unwrap()
+            .is_empty());
+
+        let body = render_as(vec![
+            "render_configuration",
+            "config:read:shop/*",
+            "config:reveal:shared/*",
+        ])
+        .await;
+        assert_eq!(body, "DB_URL=\"postgres://app:hunter2@db\"\n");
+        let records = state
+            .audit
+            .search_audit(&AuditQuery::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(records[0].after, Some(json!(["shared/db.password"])));
+    }
+}
@@ -0,0 +1,270 @@
+//! Storage behind the API, one repository trait per kind of entity.
+//! `MongoRepository` stores into MongoDB, `MemoryRepository` keeps everything in memory
+use crate::audit::{AuditQuery, AuditRecord};
+use crate::batch::{BatchOutcome, ItemWrite};
+use crate::changeset::{Changeset, ChangesetStatus};
+use crate::config_schema::ConfigSchema;
+use crate::configuration::{ConfigurationHistory, ConfigurationItems, ValueType};
+use crate::group::Group;
+use crate::role::{Permission, Role};
+use crate::schedule::{JobStatus, ScheduledJob};
+use crate::tenant::Tenant;
+use crate::user::{UserBase, UserCreationDB, UserInDB};
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use tracing::error;
+
+/// Counts of an update, serialized like MongoDB's own result
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateResult {
+    pub matched_count: u64,
+    pub modified_count: u64,
+}
+
+/// Count of a delete, serialized like MongoDB's own result
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeleteResult {
+    pub deleted_count: u64,
+}
+
+/// Turn a storage failure into a 500 response
+pub fn internal(e: anyhow::Error) -> (StatusCode, String) {
+    error!("storage failed, {:?}", e);
+    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+}
+
+/// Users. `scope` confines lookups to one tenant, `None` searches every tenant
+#[async_trait]
+pub trait UserRepository: Send + Sync {
+    /// Store a new user and return its id
+    async fn insert_user(&self, user: UserCreationDB) -> anyhow::Result<String>;
+    async fn find_user_by_id(&self, id: &str, scope: Option<&str>)
+        -> anyhow::Result<Option<UserInDB>>;
+    async fn find_user_by_name(
+        &self,
+        name: &str,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Option<UserInDB>>;
+    /// The user with a verified email whose name or email is `login`
+    async fn find_verified_user(&self, login: &str) -> anyhow::Result<Option<UserInDB>>;
+    /// Replace every field of the user but its id, password and creation time
+    async fn update_user_base(
+        &self,
+        id: &str,
+        scope: Option<&str>,
+        user_base: &UserBase,
+    ) -> anyhow::Result<UpdateResult>;
+    /// Store a new password hash
+    async fn set_password(&self, id: &str, password: &str) -> anyhow::Result<UpdateResult>;
+    async fn delete_user(&self, id: &str, scope: Option<&str>) -> anyhow::Result<DeleteResult>;
+    async fn count_users(&self, scope: Option<&str>) -> anyhow::Result<u64>;
+    /// Users holding `role` directly
+    async fn count_users_with_role(&self, role: &str) -> anyhow::Result<u64>;
+    /// Users granted `permission` directly
+    async fn count_users_with_permission(&self, permission: &str) -> anyhow::Result<u64>;
+    /// A page of users, sorted by name when `sort_by_name` is 1 (asc) or -1 (desc)
+    async fn list_users(
+        &self,
+        scope: Option<&str>,
+        skip: u64,
+        limit: i64,
+        sort_by_name: i8,
+    ) -> anyhow::Result<Vec<UserInDB>>;
+}
+
+/// Roles and the permission catalog
+#[async_trait]
+pub trait RoleRepository: Send + Sync {
+    async fn list_roles(&self) -> anyhow::Result<Vec<Role>>;
+    async fn find_role(&self, name: &str) -> anyhow::Result<Option<Role>>;
+    async fn insert_role(&self, role: &Role) -> anyhow::Result<()>;
+    async fn replace_role(&self, role: &Role) -> anyhow::Result<UpdateResult>;
+    async fn delete_role(&self, name: &str) -> anyhow::Result<DeleteResult>;
+    /// Roles granting `permission`
+    async fn count_roles_with_permission(&self, permission: &str) -> anyhow::Result<u64>;
+    async fn list_permissions(&self) -> anyhow::Result<Vec<Permission>>;
+    async fn find_permission(&self, name: &str) -> anyhow::Result<Option<Permission>>;
+    async fn insert_permission(&self, permission: &Permission) -> anyhow::Result<()>;
+    async fn delete_permission(&self, name: &str) -> anyhow::Result<DeleteResult>;
+    /// Role and permission names of the legacy `data` collection, empty when there is none
+    async fn legacy_catalog(&self) -> anyhow::Result<(Vec<String>, Vec<String>)>;
+}
+
+/// Groups of users
+#[async_trait]
+pub trait GroupRepository: Send + Sync {
+    async fn list_groups(&self, scope: Option<&str>) -> anyhow::Result<Vec<Group>>;
+    async fn find_group(&self, name: &str, scope: Option<&str>) -> anyhow::Result<Option<Group>>;
+    async fn insert_group(&self, group: &Group) -> anyhow::Result<()>;
+    async fn replace_group(&self, group: &Group) -> anyhow::Result<UpdateResult>;
+    /// Delete a group and remove it from the groups containing it
+    async fn delete_group(&self, name: &str) -> anyhow::Result<DeleteResult>;
+    /// Groups holding `role`
+    async fn count_groups_with_role(&self, role: &str) -> anyhow::Result<u64>;
+}
+
+/// Tenants, the default tenant is implicit and never stored
+#[async_trait]
+pub trait TenantRepository: Send + Sync {
+    /// Every tenant, or only the one named `scope`
+    async fn list_tenants(&self, scope: Option<&str>) -> anyhow::Result<Vec<Tenant>>;
+    async fn find_tenant(&self, name: &str) -> anyhow::Result<Option<Tenant>>;
+    async fn insert_tenant(&self, tenant: &Tenant) -> anyhow::Result<()>;
+    async fn delete_tenant(&self, name: &str) -> anyhow::Result<DeleteResult>;
+    /// How many entries of each kind still belong to the tenant `name`, the kinds without any left out
+    async fn tenant_entries(&self, name: &str) -> anyhow::Result<Vec<(String, u64)>>;
+}
+
+/// The audit trail, append only
+#[async_trait]
+pub trait AuditRepository: Send + Sync {
+    async fn insert_audit(&self, record: AuditRecord) -> anyhow::Result<()>;
+    /// Records matching the query, newest first
+    async fn search_audit(
+        &self,
+        query: &AuditQuery,
+        scope: Option<&str>,
+    ) -> anyhow::Result<Vec<AuditRecord>>;
+}
+
+/// Configuration entries of a tenant and their history
+#[async_trait]
+pub trait ConfigRepository: Send + Sync {
+    /// Create the indexes the entries rely on, run on startup
+    async fn create_indexes(&self) -> anyhow::Result<()>;
+    async fn find_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Option<ConfigurationItems>>;
+    /// Entries of a tenant, of one namespace when given, sorted by namespace and key.
+    /// `filter` holds the values the fields of the entries' values must equal, by dotted path
+    async fn list_items(
+        &self,
+        tenant: &str,
+        namespace: Option<&str>,
+        filter: &Map<String, Value>,
+    ) -> anyhow::Result<Vec<ConfigurationItems>>;
+    /// Create or replace the value of a key, bumping its revision atomically
+    async fn put_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+        value: &Value,
+        value_type: ValueType,
+        update_by: &str,
+    ) -> anyhow::Result<ConfigurationItems>;
+    async fn delete_item(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<DeleteResult>;
+    /// Apply every write of a batch to keys of `namespace` or none of them,
+    /// none when the revision a write requires is not the one of its key
+    async fn write_items(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        writes: &[ItemWrite],
+        update_by: &str,
+    ) -> anyhow::Result<BatchOutcome>;
+    async fn append_history(&self, history: &ConfigurationHistory) -> anyhow::Result<()>;
+    /// Every revision of a key, newest first
+    async fn list_history(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: &str,
+    ) -> anyhow::Result<Vec<ConfigurationHistory>>;
+    /// The revisions written together as the change `change`, sorted by namespace and key
+    async fn list_change(&self, tenant: &str, change: &str)
+        -> anyhow::Result<Vec<ConfigurationHistory>>;
+    /// The schemas attached to a namespace and to its keys
+    async fn list_schemas(&self, tenant: &str, namespace: &str)
+        -> anyhow::Result<Vec<ConfigSchema>>;
+    /// Create or replace the schema of a namespace, or of one of its keys
+    async fn put_schema(&self, schema: &ConfigSchema) -> anyhow::Result<()>;
+    async fn delete_schema(
+        &self,
+        tenant: &str,
+        namespace: &str,
+        key: Option<&str>,
+    ) -> anyhow::Result<DeleteResult>;
+}
+
+/// Schemaless JSON documents of the allowlisted document collections,
+/// documents carry their id as the string `_id`
+#[async_trait]
+pub trait DocumentRepository: Send + Sync {
+    /// Store a new document and return its id
+    async fn insert_document(
+        &self,
+        collection: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<String>;
+    async fn find_document(
+        &self,
+        collection: &str,
+        id: &str,
+    ) -> anyhow::Result<Option<Map<String, Value>>>;
+    /// A page of the documents whose fields, dotted paths allowed, equal those of `filter`,
+    /// in insertion order
+    async fn list_documents(
+        &self,
+        collection: &str,
+        filter: &Map<String, Value>,
+        skip: u64,
+        limit: i64,
+    ) -> anyhow::Result<Vec<Map<String, Value>>>;
+    async fn replace_document(
+        &self,
+        collection: &str,
+        id: &str,
+        document: &Map<String, Value>,
+    ) -> anyhow::Result<UpdateResult>;
+    async fn delete_document(&self, collection: &str, id: &str) -> anyhow::Result<DeleteResult>;
+}
+
+/// Changesets of configuration under review
+#[async_trait]
+pub trait ChangesetRepository: Send + Sync {
+    async fn insert_changeset(&self, changeset: &Changeset) -> anyhow::Result<()>;
+    async fn find_changeset(&self, tenant: &str, id: &str) -> anyhow::Result<Option<Changeset>>;
+    /// The changesets of a tenant, newest first, of one status and one namespace when given
+    async fn list_changesets(
+        &self,
+        tenant: &str,
+        status: Option<ChangesetStatus>,
+        namespace: Option<&str>,
+    ) -> anyhow::Result<Vec<Changeset>>;
+    /// Replace a changeset read at revision `changeset.revision - 1`,
+    /// false when it changed since
+    async fn replace_changeset(&self, changeset: &Changeset) -> anyhow::Result<bool>;
+}
+
+/// Configuration changes scheduled for later
+#[async_trait]
+pub trait JobRepository: Send + Sync {
+    async fn insert_job(&self, job: &ScheduledJob) -> anyhow::Result<()>;
+    async fn find_job(&self, tenant: &str, id: &str) -> anyhow::Result<Option<ScheduledJob>>;
+    /// The jobs of a tenant by the instant they apply, of one status when given
+    async fn list_jobs(
+        &self,
+        tenant: &str,
+        status: Option<JobStatus>,
+    ) -> anyhow::Result<Vec<ScheduledJob>>;
+    /// Replace a job read at revision `job.revision - 1`, false when it changed since
+    async fn replace_job(&self, job: &ScheduledJob) -> anyhow::Result<bool>;
+    /// The jobs of every tenant due at `now`, the earliest first
+    async fn due_jobs(&self, now: DateTime<Utc>) -> anyhow::Result<Vec<ScheduledJob>>;
+    /// The jobs of every tenant left applying or reverting, by a server that stopped
+    async fn running_jobs(&self) -> anyhow::Result<Vec<ScheduledJob>>;
+}
@@ -0,0 +1,145 @@
+//! Permissions scoped to configuration resources.
+//!
+//! Besides plain permission names, `UserBase.permissions` and `Role.permissions`
+//! can hold `config:<read|write|reveal>:<pattern>` entries granting access to
+//! configuration keys whose `namespace/key` path matches the pattern,
+//! e.g. `config:write:app-a/*` or `config:read:shared/*`.
+//! `*` matches any sequence of characters, write and reveal access imply read access.
+//! Reveal access decrypts secret values, it is never implied by any other grant.
+
+const PREFIX: &str = "config:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Access {
+    Read,
+    Write,
+    /// read the plain value of secrets
+    Reveal,
+}
+
+impl Access {
+    /// Whether holding `self` grants `access`
+    pub fn implies(self, access: Access) -> bool {
+        self == access || access == Access::Read
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResourcePermission {
+    pub access: Access,
+    pub pattern: String,
+}
+
+impl ResourcePermission {
+    /// Whether the permission string is meant as a resource permission
+    pub fn is_resource(permission: &str) -> bool {
+        permission.starts_with(PREFIX)
+    }
+
+    /// Parse `config:<read|write|reveal>:<pattern>`
+    pub fn parse(permission: &str) -> Option<Self> {
+        let rest = permission.strip_prefix(PREFIX)?;
+        let (access, pattern) = rest.split_once(':')?;
+        let access = match access {
+            "read" => Access::Read,
+            "write" => Access::Write,
+            "reveal" => Access::Reveal,
+            _ => return None,
+        };
+        if pattern.is_empty() {
+            return None;
+        }
+        Some(ResourcePermission {
+            access,
+            pattern: pattern.to_string(),
+        })
+    }
+
+    pub fn allows(&self, access: Access, resource: &str) -> bool {
+        self.access.implies(access) && glob_match(&self.pattern, resource)
+    }
+}
+
+/// Whether any of `permissions` grants `access` on `resource`
+pub fn allowed<'a, I>(permissions: I, access: Access, resource: &str) -> bool
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    permissions
+        .into_iter()
+        .filter_map(|p| ResourcePermission::parse(p))
+        .any(|p| p.allows(access, resource))
+}
+
+/// The resource path of a configuration key
+pub fn resource_path(namespace: &str, key: &str) -> String {
+    format!("{}/{}", namespace, key)
+}
+
+/// Match `text` against `pattern` where `*` matches any sequence of characters
+pub(crate) fn glob_match(pattern: &str, text: &str) -> bool {
+    let (p, t): (Vec<char>, Vec<char>) = (pattern.chars().collect(), text.chars().collect());
+    let (mut pi, mut ti) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ti < t.len() {
+        if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if pi < p.len() && p[pi] == t[ti] {
+            pi += 1;
+            ti += 1;
+        } else if let Some((sp, st)) = star {
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_test() {
+        let p = ResourcePermission::parse("config:write:app-a/*").unwrap();
+        assert_eq!(p.access, Access::Write);
+        assert_eq!(p.pattern, "app-a/*");
+        assert!(ResourcePermission::parse("config:delete:app-a/*").is_none());
+        assert!(ResourcePermission::parse("config:read:").is_none());
+        assert!(ResourcePermission::parse("find_user_by_id").is_none());
+        assert!(ResourcePermission::is_resource("config:bad"));
+    }
+
+    #[test]
+    fn allowed_test() {
+        let permissions = vec![
+            "config:write:app-a/*".to_string(),
+            "config:read:shared/*".to_string(),
+            "get_roles".to_string(),
+        ];
+        assert!(allowed(&permissions, Access::Write, "app-a/db.host"));
+        assert!(allowed(&permissions, Access::Read, "app-a/db.host"));
+        assert!(allowed(&permissions, Access::Read, "shared/region"));
+        assert!(!allowed(&permissions, Access::Write, "shared/region"));
+        assert!(!allowed(&permissions, Access::Read, "app-b/db.host"));
+        assert!(!allowed(&permissions, Access::Reveal, "app-a/db.host"));
+
+        let reveal = vec!["config:reveal:app-a/*".to_string()];
+        assert!(allowed(&reveal, Access::Reveal, "app-a/db.password"));
+        assert!(allowed(&reveal, Access::Read, "app-a/db.password"));
+        assert!(!allowed(&reveal, Access::Write, "app-a/db.password"));
+    }
+
+    #[test]
+    fn glob_test() {
+        assert!(glob_match("*", "anything/at/all"));
+        assert!(glob_match("app-*/db.*", "app-a/db.host"));
+        assert!(!glob_match("app-*/db.*", "app-a/cache.host"));
+        assert!(glob_match("shared/region", "shared/region"));
+        assert!(!glob_match("shared/region", "shared/regions"));
+    }
+}
@@ -0,0 +1,389 @@
+//! Roles and the permission catalog
+use crate::audit::{snapshot, AuditContext};
+use crate::repository::internal;
+use crate::resource::ResourcePermission;
+use crate::state::AppState;
+use crate::user::UserBase;
+use crate::{admin_check, authorize, Principal};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Role {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    #[serde(default)]
+    pub permissions: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Permission {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// All roles
+pub async fn list_roles(state: &AppState) -> Result<Vec<Role>, (StatusCode, String)> {
+    state.roles.list_roles().await.map_err(internal)
+}
+
+/// The permission catalog
+pub async fn list_permissions(state: &AppState) -> Result<Vec<Permission>, (StatusCode, String)> {
+    state.roles.list_permissions().await.map_err(internal)
+}
+
+/// Names in `wanted` that are missing from `known`
+fn unknown_names<'a>(wanted: &'a [String], known: &[String]) -> Vec<&'a str> {
+    wanted
+        .iter()
+        .filter(|w| !known.contains(w))
+        .map(|w| w.as_str())
+        .collect()
+}
+
+/// Check permissions exist in the catalog, 400 if any is unknown.
+/// Resource permissions are not in the catalog, they only have to be well formed
+async fn validate_permissions(
+    state: &AppState,
+    permissions: &[String],
+) -> Result<(), (StatusCode, String)> {
+    let (resources, names): (Vec<String>, Vec<String>) = permissions
+        .iter()
+        .cloned()
+        .partition(|p| ResourcePermission::is_resource(p));
+    if let Some(bad) = resources
+        .iter()
+        .find(|p| ResourcePermission::parse(p).is_none())
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Invalid resource permission: {}", bad),
+        ));
+    }
+    let known: Vec<String> = list_permissions(state)
+        .await?
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
+    let unknown = unknown_names(&names, &known);
+    if !unknown.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown permissions: {}", unknown.join(",")),
+        ));
+    }
+    Ok(())
+}
+
+/// Roles making their holders admins of every tenant
+const ADMIN_ROLES: [&str; 2] = ["super", "admin"];
+
+/// Roles the service checks by name, they exist from the first start
+const BUILTIN_ROLES: [(&str, &str); 4] = [
+    ("super", "Administers every tenant"),
+    ("admin", "Administers every tenant"),
+    ("tenant_admin", "Administers its own tenant"),
+    ("reviewer", "Reviews changesets"),
+];
+
+/// Create the built-in roles, and the roles and permissions of the legacy `data` collection,
+/// when they are missing. Run on startup, so users holding them stay valid
+pub async fn seed_roles(state: &AppState) -> anyhow::Result<()> {
+    let (legacy_roles, legacy_permissions) = state.roles.legacy_catalog().await?;
+    let roles = BUILTIN_ROLES
+        .iter()
+        .map(|(name, description)| (name.to_string(), description.to_string()))
+        .chain(legacy_roles.into_iter().map(|name| (name, String::new())));
+    for (name, description) in roles {
+        if state.roles.find_role(&name).await?.is_none() {
+            let role = Role {
+                name,
+                description,
+                permissions: vec![],
+            };
+            state.roles.insert_role(&role).await?;
+        }
+    }
+    for name in legacy_permissions {
+        if state.roles.find_permission(&name).await?.is_none() {
+            let permission = Permission {
+                name,
+                description: String::new(),
+            };
+            state.roles.insert_permission(&permission).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Only admins may hand out admin roles, 403 otherwise
+pub fn check_grantable(p: &Principal, roles: &[String]) -> Result<(), (StatusCode, String)> {
+    if !p.grants.is_admin() && roles.iter().any(|r| ADMIN_ROLES.contains(&r.as_str())) {
+        return Err((
+            StatusCode::FORBIDDEN,
+            "Only admins may grant admin roles".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Check roles exist, 400 if any is unknown
+pub async fn validate_roles(state: &AppState, roles: &[String]) -> Result<(), (StatusCode, String)> {
+    let known: Vec<String> = list_roles(state).await?.into_iter().map(|r| r.name).collect();
+    let unknown = unknown_names(roles, &known);
+    if !unknown.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown roles: {}", unknown.join(",")),
+        ));
+    }
+    Ok(())
+}
+
+/// Check the roles and permissions granted to a user exist, 400 if any is unknown
+pub async fn validate_user_grants(
+    state: &AppState,
+    user_base: &UserBase,
+) -> Result<(), (StatusCode, String)> {
+    validate_roles(state, &user_base.roles).await?;
+    validate_permissions(state, &user_base.permissions).await
+}
+
+pub async fn get_roles(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    authorize(&headers, &state, "get_roles", "").await?;
+    let roles = list_roles(&state).await?;
+    Ok(serde_json::to_string(&roles).unwrap())
+}
+
+pub async fn get_role(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    authorize(&headers, &state, "get_role", &name).await?;
+    match state.roles.find_role(&name).await.map_err(internal)? {
+        Some(role) => Ok(serde_json::to_string(&role).unwrap()),
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+    }
+}
+
+pub async fn create_role(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Role>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "create_role", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_role", &payload.name);
+    let res = insert_role(&state, &payload).await;
+    audit.record(&state, &res, None, snapshot(&payload)).await;
+    res
+}
+
+async fn insert_role(state: &AppState, role: &Role) -> Result<String, (StatusCode, String)> {
+    if state.roles.find_role(&role.name).await.map_err(internal)?.is_some() {
+        return Err((StatusCode::CONFLICT, "Role Name esists".to_string()));
+    }
+    validate_permissions(state, &role.permissions).await?;
+    state
+        .roles
+        .insert_role(role)
+        .await
+        .map(|_| role.name.clone())
+        .map_err(internal)
+}
+
+pub async fn update_role(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Role>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "update_role", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "update_role", &payload.name);
+    let before = state.roles.find_role(&payload.name).await.map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => replace_role(&state, &payload).await,
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), snapshot(&payload))
+        .await;
+    res
+}
+
+async fn replace_role(state: &AppState, role: &Role) -> Result<String, (StatusCode, String)> {
+    validate_permissions(state, &role.permissions).await?;
+    state
+        .roles
+        .replace_role(role)
+        .await
+        .map(|r| serde_json::to_string(&r).unwrap())
+        .map_err(internal)
+}
+
+/// Delete a role, refused with 409 while it is still assigned to users or groups
+pub async fn delete_role(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "delete_role", &name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_role", &name);
+    let before = state.roles.find_role(&name).await.map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => remove_role(&state, &name).await,
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+async fn remove_role(state: &AppState, name: &str) -> Result<String, (StatusCode, String)> {
+    let groups = state.groups.count_groups_with_role(name).await.map_err(internal)?;
+    if groups > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} is assigned to {} groups", name, groups),
+        ));
+    }
+    let users = state.users.count_users_with_role(name).await.map_err(internal)?;
+    if users > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} is assigned to {} users", name, users),
+        ));
+    }
+    state
+        .roles
+        .delete_role(name)
+        .await
+        .map(|r| serde_json::to_string(&r).unwrap())
+        .map_err(internal)
+}
+
+pub async fn get_permissions(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    authorize(&headers, &state, "get_permissions", "").await?;
+    let permissions = list_permissions(&state).await?;
+    Ok(serde_json::to_string(&permissions).unwrap())
+}
+
+pub async fn create_permission(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Permission>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "create_permission", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_permission", &payload.name);
+    let res = match state.roles.find_permission(&payload.name).await {
+        Ok(Some(_)) => Err((StatusCode::CONFLICT, "Permission Name esists".to_string())),
+        Ok(None) => state
+            .roles
+            .insert_permission(&payload)
+            .await
+            .map(|_| payload.name.clone())
+            .map_err(internal),
+        Err(e) => Err(internal(e)),
+    };
+    audit.record(&state, &res, None, snapshot(&payload)).await;
+    res
+}
+
+/// Delete a catalog entry, refused with 409 while a role or a user still grants it
+pub async fn delete_permission(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "delete_permission", &name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_permission", &name);
+    let before = state.roles.find_permission(&name).await.map_err(internal)?;
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => remove_permission(&state, &name).await,
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+async fn remove_permission(state: &AppState, name: &str) -> Result<String, (StatusCode, String)> {
+    let roles = state
+        .roles
+        .count_roles_with_permission(name)
+        .await
+        .map_err(internal)?;
+    if roles > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} is granted by {} roles", name, roles),
+        ));
+    }
+    let users = state
+        .users
+        .count_users_with_permission(name)
+        .await
+        .map_err(internal)?;
+    if users > 0 {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("{} is assigned to {} users", name, users),
+        ));
+    }
+    state
+        .roles
+        .delete_permission(name)
+        .await
+        .map(|r| serde_json::to_string(&r).unwrap())
+        .map_err(internal)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mail::MemoryMailer;
+    use std::sync::Arc;
+
+    #[test]
+    fn unknown_names_test() {
+        let known = vec!["read".to_string(), "write".to_string()];
+        let wanted = vec!["read".to_string(), "delete".to_string()];
+        assert_eq!(unknown_names(&wanted, &known), vec!["delete"]);
+        assert!(unknown_names(&[], &known).is_empty());
+
+        let role: Role = serde_json::from_str(r#"{"name":"viewer"}"#).unwrap();
+        assert!(role.permissions.is_empty());
+        assert_eq!(role.description, "");
+    }
+
+    #[tokio::test]
+    async fn seed_roles_test() {
+        let state = AppState::memory(Arc::new(MemoryMailer::default()), "http://cf");
+        seed_roles(&state).await.unwrap();
+        seed_roles(&state).await.unwrap();
+        let names: Vec<String> = list_roles(&state)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|r| r.name)
+            .collect();
+        assert_eq!(names, vec!["super", "admin", "tenant_admin", "reviewer"]);
+        let roles = ["admin".to_string(), "reviewer".to_string()];
+        assert!(validate_roles(&state, &roles).await.is_ok());
+        assert!(validate_roles(&state, &["ops".to_string()]).await.is_err());
+    }
+}
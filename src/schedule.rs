@@ -0,0 +1,798 @@
+//! Configuration changes applied at a later instant, and optionally undone after a while.
+//!
+//! A job holds a batch of writes to the keys of a namespace. When it is due the scheduler
+//! checks its creator may still make the change, applies it, each key pinned to the revision
+//! it is at then, and records the writes undoing it. A time boxed job is undone when its
+//! revert is due, unless its keys changed meanwhile. Jobs are stored so that they survive
+//! restarts: a job is marked running before its writes, and the jobs left running by a
+//! stopped server are finished on startup. The revisions written by a job carry its id as
+//! their change id
+use crate::audit::{snapshot, AuditContext};
+use crate::batch::{check_keys, prepare, BatchWrite, ItemWrite};
+use crate::changeset::check_unreviewed;
+use crate::configuration::{append_history, target_tenant, ChangeOp, ConfigurationItems};
+use crate::group::effective_grants;
+use crate::repository::internal;
+use crate::resource::{resource_path, Access};
+use crate::state::AppState;
+use crate::tenant::validate_tenant;
+use crate::user::UserProfile;
+use crate::{principal, Principal};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+};
+use chrono::{DateTime, TimeDelta, Utc};
+use mongodb::bson::{self, oid::ObjectId};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use tracing::{error, info};
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    /// waiting for `apply_at`
+    Pending,
+    /// applied, waiting for `revert_at`
+    Active,
+    /// being applied, the writes may or may not have gone through
+    Applying,
+    /// being undone, the writes may or may not have gone through
+    Reverting,
+    Done,
+    Cancelled,
+    Failed,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ScheduledJob {
+    pub id: String,
+    pub tenant: String,
+    pub namespace: String,
+    pub writes: Vec<ItemWrite>,
+    pub apply_at: DateTime<Utc>,
+    /// when the change is undone, never when unset
+    pub revert_at: Option<DateTime<Utc>>,
+    pub status: JobStatus,
+    /// the writes undoing the change, recorded when it is applied
+    #[serde(default)]
+    pub undo: Vec<ItemWrite>,
+    /// the name of the user who scheduled the change
+    pub created_by: String,
+    /// the id of the user who scheduled the change
+    #[serde(default)]
+    pub created_by_id: String,
+    /// why the job failed
+    pub error: Option<String>,
+    /// bumped by every step
+    pub revision: i64,
+}
+
+impl ScheduledJob {
+    /// When the job has to run next, `None` when nothing is left to do
+    pub fn due(&self) -> Option<DateTime<Utc>> {
+        match self.status {
+            JobStatus::Pending => Some(self.apply_at),
+            JobStatus::Active => self.revert_at,
+            _ => None,
+        }
+    }
+
+    /// Whether the job was claimed and its writes may be partly done
+    pub fn is_running(&self) -> bool {
+        matches!(self.status, JobStatus::Applying | JobStatus::Reverting)
+    }
+
+    /// The job with the values of secrets replaced by a placeholder
+    pub fn redacted(mut self) -> Self {
+        self.writes = self.writes.into_iter().map(ItemWrite::redacted).collect();
+        self.undo = self.undo.into_iter().map(ItemWrite::redacted).collect();
+        self
+    }
+}
+
+/// A job as stored in MongoDB, with the instant it is due as a date to query on
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ScheduledJobDB {
+    #[serde(flatten)]
+    pub job: ScheduledJob,
+    pub due: Option<bson::DateTime>,
+}
+
+impl From<ScheduledJob> for ScheduledJobDB {
+    fn from(job: ScheduledJob) -> Self {
+        ScheduledJobDB {
+            due: job
+                .due()
+                .map(|d| bson::DateTime::from_millis(d.timestamp_millis())),
+            job,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct JobPayload {
+    pub namespace: String,
+    pub writes: Vec<BatchWrite>,
+    pub apply_at: DateTime<Utc>,
+    /// undo the change that many seconds after it is applied
+    pub revert_after_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Default)]
+pub struct JobQuery {
+    pub status: Option<JobStatus>,
+    /// operate on another tenant than the caller's, admins only
+    pub tenant: Option<String>,
+}
+
+/// The write undoing `w`, `previous` being the item it replaces.
+/// It is pinned to the revision `w` writes once applied
+fn undo_of(w: &ItemWrite, previous: Option<&ConfigurationItems>) -> ItemWrite {
+    match previous {
+        Some(item) => ItemWrite {
+            key: w.key.clone(),
+            op: ChangeOp::Put,
+            value: item.value.clone(),
+            value_type: item.value_type,
+            revision: None,
+        },
+        None => ItemWrite {
+            key: w.key.clone(),
+            op: ChangeOp::Delete,
+            value: Value::Null,
+            value_type: Default::default(),
+            revision: None,
+        },
+    }
+}
+
+/// Store the next revision of a job, false when it changed meanwhile
+async fn save(state: &AppState, job: &mut ScheduledJob) -> anyhow::Result<bool> {
+    job.revision += 1;
+    state.jobs.replace_job(job).await
+}
+
+/// The items of the keys of a job by key
+async fn current_items(
+    state: &AppState,
+    job: &ScheduledJob,
+) -> anyhow::Result<BTreeMap<String, ConfigurationItems>> {
+    Ok(state
+        .configs
+        .list_items(&job.tenant, Some(&job.namespace), &Map::new())
+        .await?
+        .into_iter()
+        .map(|i| (i.key.clone(), i))
+        .collect())
+}
+
+/// Whether `current` already holds what `writes` write, a retry finding its writes done
+fn is_written(writes: &[ItemWrite], current: &BTreeMap<String, ConfigurationItems>) -> bool {
+    writes.iter().all(|w| match (&w.op, current.get(&w.key)) {
+        (ChangeOp::Put, Some(item)) => {
+            item.value == w.value
+                && (w.revision == Some(0) || w.revision.map(|r| r + 1) == Some(item.revision))
+        }
+        (ChangeOp::Delete, None) => true,
+        _ => false,
+    })
+}
+
+/// Write `writes` for a job, or find them written by an earlier attempt.
+/// Gives the revision each key is at afterwards, the failure as text
+async fn write(
+    state: &AppState,
+    job: &ScheduledJob,
+    writes: &[ItemWrite],
+) -> Result<BTreeMap<String, i64>, String> {
+    let outcome = state
+        .configs
+        .write_items(&job.tenant, &job.namespace, writes, &job.created_by)
+        .await
+        .map_err(|e| e.to_string())?;
+    match outcome {
+        Ok(revisions) => {
+            for h in &revisions {
+                append_history(state, h.op.clone(), &h.item, Some(&job.id)).await;
+            }
+            Ok(revisions
+                .into_iter()
+                .filter(|h| h.op == ChangeOp::Put)
+                .map(|h| (h.item.key, h.item.revision))
+                .collect())
+        }
+        Err(conflicts) => {
+            let current = current_items(state, job).await.map_err(|e| e.to_string())?;
+            if is_written(writes, &current) {
+                return Ok(current.into_iter().map(|(k, i)| (k, i.revision)).collect());
+            }
+            Err(format!(
+                "keys changed meanwhile: {}",
+                serde_json::to_string(&conflicts).unwrap()
+            ))
+        }
+    }
+}
+
+/// Check the creator of a job may still make its change: the user exists, is not disabled
+/// and may write every key, and the namespace does not need a changeset
+async fn check_creator(state: &AppState, job: &ScheduledJob) -> Result<(), String> {
+    check_unreviewed(state, &job.namespace).map_err(|(_, e)| e)?;
+    let super_user = UserProfile::default_super();
+    let profile = if job.created_by_id == super_user._id {
+        super_user
+    } else {
+        state
+            .users
+            .find_user_by_id(&job.created_by_id, None)
+            .await
+            .map_err(|e| e.to_string())?
+            .map(UserProfile::from)
+            .ok_or_else(|| format!("{} no longer exists", job.created_by))?
+    };
+    if profile.user_base.disabled {
+        return Err(format!("{} is disabled", job.created_by));
+    }
+    let grants = effective_grants(state, &profile.user_base)
+        .await
+        .map_err(|(_, e)| e)?;
+    let p = Principal { profile, grants };
+    for w in &job.writes {
+        let path = resource_path(&job.namespace, &w.key);
+        if !p.can_access(Access::Write, &path) {
+            return Err(format!("{} may no longer write {}", job.created_by, path));
+        }
+    }
+    Ok(())
+}
+
+/// Apply a pending job or undo an active one. The job is claimed by storing it as running
+/// first, so that a job is run once even by several servers, and finished once written
+async fn run_job(state: &AppState, mut job: ScheduledJob) -> anyhow::Result<()> {
+    match job.status {
+        JobStatus::Pending => {
+            if let Err(e) = check_creator(state, &job).await {
+                job.status = JobStatus::Failed;
+                job.error = Some(e);
+                save(state, &mut job).await?;
+                return Ok(());
+            }
+            let current = current_items(state, &job).await?;
+            for w in job.writes.iter_mut() {
+                w.revision = w
+                    .revision
+                    .or_else(|| Some(current.get(&w.key).map_or(0, |i| i.revision)));
+            }
+            job.undo = job
+                .writes
+                .iter()
+                .map(|w| undo_of(w, current.get(&w.key)))
+                .collect();
+            job.status = JobStatus::Applying;
+        }
+        JobStatus::Active => job.status = JobStatus::Reverting,
+        // left running, claimed again to finish it
+        JobStatus::Applying | JobStatus::Reverting => {}
+        _ => return Ok(()),
+    }
+    if !save(state, &mut job).await? {
+        return Ok(());
+    }
+    info!("run scheduled job {} on {}", job.id, job.namespace);
+    let writes = match job.status {
+        JobStatus::Reverting => job.undo.clone(),
+        _ => job.writes.clone(),
+    };
+    job.status = match write(state, &job, &writes).await {
+        Ok(revisions) if job.status == JobStatus::Applying && job.revert_at.is_some() => {
+            for u in job.undo.iter_mut() {
+                u.revision = Some(revisions.get(&u.key).copied().unwrap_or(0));
+            }
+            JobStatus::Active
+        }
+        Ok(_) => JobStatus::Done,
+        Err(e) => {
+            job.error = Some(e);
+            JobStatus::Failed
+        }
+    };
+    save(state, &mut job).await?;
+    Ok(())
+}
+
+/// Finish the jobs left running by a server that stopped
+pub async fn recover_jobs(state: &AppState) {
+    let jobs = match state.jobs.running_jobs().await {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            error!("list running jobs failed, {:?}", e);
+            return;
+        }
+    };
+    for job in jobs {
+        let id = job.id.clone();
+        if let Err(e) = run_job(state, job).await {
+            error!("scheduled job {} failed, {:?}", id, e);
+        }
+    }
+}
+
+/// Run every job due at `now`
+pub async fn run_due(state: &AppState, now: DateTime<Utc>) {
+    let jobs = match state.jobs.due_jobs(now).await {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            error!("list due jobs failed, {:?}", e);
+            return;
+        }
+    };
+    for job in jobs {
+        let id = job.id.clone();
+        if let Err(e) = run_job(state, job).await {
+            error!("scheduled job {} failed, {:?}", id, e);
+        }
+    }
+}
+
+/// Finish the jobs left running, then run the due jobs every `interval`, forever
+pub async fn run_scheduler(state: AppState, interval: std::time::Duration) {
+    recover_jobs(&state).await;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        run_due(&state, Utc::now()).await;
+    }
+}
+
+async fn find_job(
+    state: &AppState,
+    tenant: &str,
+    id: &str,
+) -> Result<ScheduledJob, (StatusCode, String)> {
+    state
+        .jobs
+        .find_job(tenant, id)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "Not Found".to_string()))
+}
+
+/// Schedule a batch of writes to a namespace
+pub async fn create_job(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<JobQuery>,
+    Json(payload): Json<JobPayload>,
+) -> Result<String, (StatusCode, String)> {
+    let namespace = payload.namespace.trim_matches('/').to_string();
+    let p = principal(&headers, &state, "create_job", &namespace).await?;
+    if namespace.is_empty() || payload.writes.is_empty() || payload.revert_after_secs == Some(0) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Missing namespace, writes or revert delay".to_string(),
+        ));
+    }
+    check_unreviewed(&state, &namespace)?;
+    check_keys(&p, &namespace, &payload.writes)?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_job", &namespace);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    validate_tenant(&state, &tenant).await?;
+    let writes = prepare(&state, &tenant, &namespace, payload.writes).await?;
+    let revert_at = match payload.revert_after_secs {
+        Some(secs) => Some(
+            i64::try_from(secs)
+                .ok()
+                .and_then(TimeDelta::try_seconds)
+                .and_then(|delay| payload.apply_at.checked_add_signed(delay))
+                .ok_or((
+                    StatusCode::BAD_REQUEST,
+                    format!("Invalid revert delay: {}", secs),
+                ))?,
+        ),
+        None => None,
+    };
+    let job = ScheduledJob {
+        id: ObjectId::new().to_hex(),
+        tenant,
+        namespace,
+        writes,
+        apply_at: payload.apply_at,
+        revert_at,
+        status: JobStatus::Pending,
+        undo: Vec::new(),
+        created_by: p.profile.user_base.name.clone(),
+        created_by_id: p.profile._id.clone(),
+        error: None,
+        revision: 1,
+    };
+    let res = state
+        .jobs
+        .insert_job(&job)
+        .await
+        .map_err(internal)
+        .map(|_| job.redacted());
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|j| serde_json::to_string(&j).unwrap());
+    audit.record(&state, &res, None, after).await;
+    res
+}
+
+/// The jobs of a tenant by the instant they apply, secrets redacted
+pub async fn list_jobs(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Query(query): Query<JobQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "list_jobs", "").await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let jobs: Vec<ScheduledJob> = state
+        .jobs
+        .list_jobs(&tenant, query.status)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(ScheduledJob::redacted)
+        .collect();
+    Ok(serde_json::to_string(&jobs).unwrap())
+}
+
+pub async fn get_job(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<JobQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_job", &id).await?;
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let job = find_job(&state, &tenant, &id).await?;
+    Ok(serde_json::to_string(&job.redacted()).unwrap())
+}
+
+/// Cancel a pending job, or the revert of an active one which keeps its change.
+/// Needs write access on every key of the job
+pub async fn cancel_job(
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    state: State<AppState>,
+    Query(query): Query<JobQuery>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "cancel_job", &id).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "cancel_job", &id);
+    let tenant = target_tenant(&p, query.tenant.as_deref())?;
+    let mut job = find_job(&state, &tenant, &id).await?;
+    for w in &job.writes {
+        let path = resource_path(&job.namespace, &w.key);
+        if !p.can_access(Access::Write, &path) {
+            return Err((
+                StatusCode::FORBIDDEN,
+                format!("Missing {:?} access on {}", Access::Write, path),
+            ));
+        }
+    }
+    let before = snapshot(&job.clone().redacted());
+    job.status = match job.status {
+        JobStatus::Pending => JobStatus::Cancelled,
+        JobStatus::Active => JobStatus::Done,
+        status => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("Can't cancel a job in status {:?}", status),
+            ))
+        }
+    };
+    let res = match save(&state, &mut job).await {
+        Ok(true) => Ok(job.redacted()),
+        Ok(false) => Err((
+            StatusCode::CONFLICT,
+            format!("Job {} changed meanwhile", id),
+        )),
+        Err(e) => Err(internal(e)),
+    };
+    let after = res.as_ref().ok().and_then(snapshot);
+    let res = res.map(|j| serde_json::to_string(&j).unwrap());
+    audit.record(&state, &res, before, after).await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::ValueType;
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::{UserCreation, UserProfile};
+    use chrono::Duration;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn put(key: &str, value: Value) -> BatchWrite {
+        BatchWrite {
+            key: key.to_string(),
+            op: ChangeOp::Put,
+            value: Some(value),
+            value_type: None,
+            revision: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn schedule_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        state
+            .configs
+            .put_item(
+                "default",
+                "app",
+                "mode",
+                &json!("normal"),
+                ValueType::String,
+                "admin",
+            )
+            .await
+            .unwrap();
+        let schedule = |writes: Vec<BatchWrite>, apply_at, revert_after_secs| {
+            create_job(
+                headers.clone(),
+                state.clone(),
+                Query(JobQuery::default()),
+                Json(JobPayload {
+                    namespace: "app".to_string(),
+                    writes,
+                    apply_at,
+                    revert_after_secs,
+                }),
+            )
+        };
+        let value = |key: &'static str| {
+            let state = state.clone();
+            async move {
+                state
+                    .configs
+                    .find_item("default", "app", key)
+                    .await
+                    .unwrap()
+                    .map(|i| i.value)
+            }
+        };
+        let job = |id: String| {
+            let state = state.clone();
+            async move { state.jobs.find_job("default", &id).await.unwrap().unwrap() }
+        };
+        let now = Utc::now();
+
+        // a maintenance window of an hour
+        let window = schedule(
+            vec![
+                put("mode", json!("maintenance")),
+                put("banner", json!("back soon")),
+            ],
+            now + Duration::minutes(10),
+            Some(3600),
+        )
+        .await
+        .unwrap();
+        let window: ScheduledJob = serde_json::from_str(&window).unwrap();
+        assert_eq!(window.status, JobStatus::Pending);
+
+        run_due(&state, now).await;
+        assert_eq!(value("mode").await, Some(json!("normal")));
+
+        run_due(&state, now + Duration::minutes(10)).await;
+        assert_eq!(value("mode").await, Some(json!("maintenance")));
+        assert_eq!(value("banner").await, Some(json!("back soon")));
+        assert_eq!(job(window.id.clone()).await.status, JobStatus::Active);
+        let change = state
+            .configs
+            .list_change("default", &window.id)
+            .await
+            .unwrap();
+        assert_eq!(change.len(), 2);
+
+        run_due(&state, now + Duration::minutes(70)).await;
+        assert_eq!(value("mode").await, Some(json!("normal")));
+        assert_eq!(value("banner").await, None);
+        assert_eq!(job(window.id.clone()).await.status, JobStatus::Done);
+        let change = state
+            .configs
+            .list_change("default", &window.id)
+            .await
+            .unwrap();
+        assert_eq!(change.len(), 4);
+
+        // a revert after a manual edit would lose it
+        let window = schedule(vec![put("mode", json!("readonly"))], now, Some(60))
+            .await
+            .unwrap();
+        let window: ScheduledJob = serde_json::from_str(&window).unwrap();
+        run_due(&state, now).await;
+        state
+            .configs
+            .put_item(
+                "default",
+                "app",
+                "mode",
+                &json!("manual"),
+                ValueType::String,
+                "admin",
+            )
+            .await
+            .unwrap();
+        run_due(&state, now + Duration::minutes(2)).await;
+        assert_eq!(value("mode").await, Some(json!("manual")));
+        let failed = job(window.id).await;
+        assert_eq!(failed.status, JobStatus::Failed);
+        assert!(failed.error.unwrap().contains("mode"));
+
+        // cancel a pending job, then the revert of an active one
+        let pending = schedule(
+            vec![put("mode", json!("off"))],
+            now + Duration::days(1),
+            None,
+        )
+        .await
+        .unwrap();
+        let pending: ScheduledJob = serde_json::from_str(&pending).unwrap();
+        let active = schedule(vec![put("banner", json!("sale"))], now, Some(60))
+            .await
+            .unwrap();
+        let active: ScheduledJob = serde_json::from_str(&active).unwrap();
+        run_due(&state, now).await;
+        for id in [&pending.id, &active.id] {
+            cancel_job(
+                headers.clone(),
+                Path(id.clone()),
+                state.clone(),
+                Query(JobQuery::default()),
+            )
+            .await
+            .unwrap();
+        }
+        run_due(&state, now + Duration::days(2)).await;
+        assert_eq!(value("mode").await, Some(json!("manual")));
+        assert_eq!(value("banner").await, Some(json!("sale")));
+        assert_eq!(job(pending.id.clone()).await.status, JobStatus::Cancelled);
+        assert_eq!(job(active.id).await.status, JobStatus::Done);
+        let again = cancel_job(
+            headers.clone(),
+            Path(pending.id),
+            state.clone(),
+            Query(JobQuery::default()),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(again.0, StatusCode::CONFLICT);
+
+        let listed = list_jobs(
+            headers.clone(),
+            state.clone(),
+            Query(JobQuery {
+                status: Some(JobStatus::Done),
+                tenant: None,
+            }),
+        )
+        .await
+        .unwrap();
+        let listed: Vec<ScheduledJob> = serde_json::from_str(&listed).unwrap();
+        assert_eq!(listed.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn recover_test() {
+        let state = State(AppState::memory(
+            Arc::new(MemoryMailer::default()),
+            "http://cf",
+        ));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let now = Utc::now();
+        let too_long = create_job(
+            headers.clone(),
+            state.clone(),
+            Query(JobQuery::default()),
+            Json(JobPayload {
+                namespace: "app".to_string(),
+                writes: vec![put("mode", json!("off"))],
+                apply_at: now,
+                revert_after_secs: Some(u64::MAX),
+            }),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(too_long.0, StatusCode::BAD_REQUEST);
+
+        let job_of = |id: &str, created_by_id: &str, key: &str, status: JobStatus| ScheduledJob {
+            id: id.to_string(),
+            tenant: "default".to_string(),
+            namespace: "app".to_string(),
+            writes: vec![ItemWrite {
+                key: key.to_string(),
+                op: ChangeOp::Put,
+                value: json!("on"),
+                value_type: ValueType::String,
+                revision: Some(0),
+            }],
+            apply_at: now,
+            revert_at: Some(now + Duration::hours(1)),
+            status,
+            undo: vec![ItemWrite {
+                key: key.to_string(),
+                op: ChangeOp::Delete,
+                value: Value::Null,
+                value_type: Default::default(),
+                revision: None,
+            }],
+            created_by: "someone".to_string(),
+            created_by_id: created_by_id.to_string(),
+            error: None,
+            revision: 1,
+        };
+        // stopped after writing, and before writing
+        let written = job_of("written", "0", "a", JobStatus::Applying);
+        state
+            .configs
+            .write_items("default", "app", &written.writes, "someone")
+            .await
+            .unwrap()
+            .unwrap();
+        let unwritten = job_of("unwritten", "0", "b", JobStatus::Applying);
+        let orphan = job_of("orphan", "gone", "c", JobStatus::Pending);
+        // disabled after scheduling
+        let creation: UserCreation = serde_json::from_str(
+            r#"{"name":"someone","phone":"+8613800138002","password":"hunter22","roles":["admin"],"disabled":true}"#,
+        )
+        .unwrap();
+        let disabled_id = state.users.insert_user(creation.into()).await.unwrap();
+        let disabled = job_of("disabled", &disabled_id, "d", JobStatus::Pending);
+        for job in [&written, &unwritten, &orphan, &disabled] {
+            state.jobs.insert_job(job).await.unwrap();
+        }
+        recover_jobs(&state).await;
+        run_due(&state, now).await;
+        for id in ["written", "unwritten"] {
+            let job = state.jobs.find_job("default", id).await.unwrap().unwrap();
+            assert_eq!(job.status, JobStatus::Active, "{}", id);
+            assert_eq!(job.undo[0].revision, Some(1));
+        }
+        let item = state
+            .configs
+            .find_item("default", "app", "b")
+            .await
+            .unwrap();
+        assert_eq!(item.unwrap().value, json!("on"));
+        let orphan = state
+            .jobs
+            .find_job("default", "orphan")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(orphan.status, JobStatus::Failed);
+        assert_eq!(orphan.error.as_deref(), Some("someone no longer exists"));
+        let disabled = state
+            .jobs
+            .find_job("default", "disabled")
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(disabled.status, JobStatus::Failed);
+        assert_eq!(disabled.error.as_deref(), Some("someone is disabled"));
+        for key in ["c", "d"] {
+            assert!(state
+                .configs
+                .find_item("default", "app", key)
+                .await
+                .unwrap()
+                .is_none());
+        }
+    }
+}
@@ -0,0 +1,612 @@
+//! JSON Schema validation, covering the keywords of draft 2020-12 that matter for
+//! configuration data: types, enums, numeric and length bounds, patterns, object and
+//! array shapes, combinators and local `$ref`. Errors point into the instance with JSON pointers
+use crate::validation::{into_response, FieldError};
+use axum::http::StatusCode;
+use regex::Regex;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+const TYPES: [&str; 7] = ["null", "boolean", "object", "array", "number", "integer", "string"];
+/// Nesting of sub schemas, deep enough for any parsed instance, which serde caps at 128 levels
+const MAX_DEPTH: usize = 512;
+/// Sub schemas visited by one validation, bounding combinators and `$ref` fanning out
+const MAX_STEPS: usize = 100_000;
+/// Keywords carrying no assertion
+const ANNOTATIONS: [&str; 12] = [
+    "$schema",
+    "$id",
+    "$comment",
+    "title",
+    "description",
+    "default",
+    "examples",
+    "deprecated",
+    "readOnly",
+    "writeOnly",
+    "format",
+    "contentMediaType",
+];
+
+#[derive(Debug, Clone)]
+pub struct Schema {
+    root: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl PartialEq for Schema {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root
+    }
+}
+
+/// Append a segment to a JSON pointer, `/` being the root
+fn child(path: &str, segment: &str) -> String {
+    let segment = segment.replace('~', "~0").replace('/', "~1");
+    if path == "/" {
+        format!("/{}", segment)
+    } else {
+        format!("{}/{}", path, segment)
+    }
+}
+
+fn error(errors: &mut Vec<FieldError>, path: &str, message: String) {
+    errors.push(FieldError {
+        field: path.to_string(),
+        message,
+    });
+}
+
+fn type_of(instance: &Value) -> &'static str {
+    match instance {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Object(_) => "object",
+        Value::Array(_) => "array",
+        Value::Number(n) if n.is_i64() || n.is_u64() => "integer",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+    }
+}
+
+fn has_type(instance: &Value, t: &str) -> bool {
+    let actual = type_of(instance);
+    actual == t
+        || (t == "number" && actual == "integer")
+        || (t == "integer" && instance.as_f64().is_some_and(|f| f.fract() == 0.0))
+}
+
+fn is_count(v: &Value) -> bool {
+    v.as_u64().is_some()
+}
+
+impl Schema {
+    /// Check `root` is a well formed schema, errors point into the schema
+    pub fn new(root: Value) -> Result<Schema, Vec<FieldError>> {
+        let mut errors = Vec::new();
+        let mut patterns = HashMap::new();
+        check_schema(&root, &root, "/", &mut patterns, &mut errors);
+        if errors.is_empty() {
+            Ok(Schema { root, patterns })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Parse and check a schema, a 422 response listing the problems if malformed
+    pub fn parse(text: &str) -> Result<Schema, (StatusCode, String)> {
+        let root: Value = serde_json::from_str(text).map_err(|e| {
+            into_response(vec![FieldError {
+                field: "/".to_string(),
+                message: format!("schema is not valid JSON: {}", e),
+            }])
+        })?;
+        Schema::new(root).map_err(into_response)
+    }
+
+    pub fn as_value(&self) -> &Value {
+        &self.root
+    }
+
+    /// Every violation of the schema by `instance`
+    pub fn validate(&self, instance: &Value) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        let mut steps = 0;
+        self.validate_at(&self.root, instance, "/", 0, &mut steps, &mut errors);
+        if steps > MAX_STEPS {
+            error(&mut errors, "/", "schema takes too long to validate".to_string());
+        }
+        errors
+    }
+
+    /// Validate `instance`, a 422 response listing the violations if invalid
+    pub fn check(&self, instance: &Value) -> Result<(), (StatusCode, String)> {
+        let errors = self.validate(instance);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(into_response(errors))
+        }
+    }
+
+    fn is_valid(
+        &self,
+        schema: &Value,
+        instance: &Value,
+        path: &str,
+        depth: usize,
+        steps: &mut usize,
+    ) -> bool {
+        let mut errors = Vec::new();
+        self.validate_at(schema, instance, path, depth, steps, &mut errors);
+        errors.is_empty()
+    }
+
+    fn validate_at(
+        &self,
+        schema: &Value,
+        instance: &Value,
+        path: &str,
+        depth: usize,
+        steps: &mut usize,
+        errors: &mut Vec<FieldError>,
+    ) {
+        // past the budget the result no longer matters, `validate` reports it once
+        *steps += 1;
+        if *steps > MAX_STEPS {
+            return;
+        }
+        if depth > MAX_DEPTH {
+            error(errors, path, "schema nests too deeply".to_string());
+            return;
+        }
+        let s = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return error(errors, path, "is not allowed".to_string()),
+            Value::Object(s) => s,
+            _ => return,
+        };
+        if let Some(target) = s.get("$ref").and_then(Value::as_str) {
+            if let Some(resolved) = self.root.pointer(target.trim_start_matches('#')) {
+                self.validate_at(resolved, instance, path, depth + 1, steps, errors);
+            }
+        }
+        self.validate_type(s, instance, path, errors);
+        if let Some(values) = s.get("enum").and_then(Value::as_array) {
+            if !values.contains(instance) {
+                let allowed: Vec<String> = values.iter().map(Value::to_string).collect();
+                error(errors, path, format!("must be one of {}", allowed.join(", ")));
+            }
+        }
+        if let Some(c) = s.get("const") {
+            if c != instance {
+                error(errors, path, format!("must be {}", c));
+            }
+        }
+        match instance {
+            Value::Number(_) => self.validate_number(s, instance, path, errors),
+            Value::String(text) => self.validate_string(s, text, path, errors),
+            Value::Array(items) => self.validate_array(s, items, path, depth, steps, errors),
+            Value::Object(fields) => self.validate_object(s, fields, path, depth, steps, errors),
+            _ => {}
+        }
+        self.validate_combinators(s, instance, path, depth, steps, errors);
+    }
+
+    fn validate_type(
+        &self,
+        s: &Map<String, Value>,
+        instance: &Value,
+        path: &str,
+        errors: &mut Vec<FieldError>,
+    ) {
+        let types: Vec<&str> = match s.get("type") {
+            Some(Value::String(t)) => vec![t.as_str()],
+            Some(Value::Array(ts)) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => return,
+        };
+        if !types.iter().any(|t| has_type(instance, t)) {
+            error(
+                errors,
+                path,
+                format!("must be of type {}, not {}", types.join(" or "), type_of(instance)),
+            );
+        }
+    }
+
+    fn validate_number(
+        &self,
+        s: &Map<String, Value>,
+        instance: &Value,
+        path: &str,
+        errors: &mut Vec<FieldError>,
+    ) {
+        let n = instance.as_f64().unwrap_or_default();
+        let bound = |k: &str| s.get(k).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum") {
+            if n < min {
+                error(errors, path, format!("must be at least {}", min));
+            }
+        }
+        if let Some(max) = bound("maximum") {
+            if n > max {
+                error(errors, path, format!("must be at most {}", max));
+            }
+        }
+        if let Some(min) = bound("exclusiveMinimum") {
+            if n <= min {
+                error(errors, path, format!("must be greater than {}", min));
+            }
+        }
+        if let Some(max) = bound("exclusiveMaximum") {
+            if n >= max {
+                error(errors, path, format!("must be less than {}", max));
+            }
+        }
+        if let Some(m) = bound("multipleOf") {
+            if (n / m).fract() != 0.0 {
+                error(errors, path, format!("must be a multiple of {}", m));
+            }
+        }
+    }
+
+    fn validate_string(
+        &self,
+        s: &Map<String, Value>,
+        text: &str,
+        path: &str,
+        errors: &mut Vec<FieldError>,
+    ) {
+        let len = text.chars().count() as u64;
+        if let Some(min) = s.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                error(errors, path, format!("must have at least {} characters", min));
+            }
+        }
+        if let Some(max) = s.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                error(errors, path, format!("must have at most {} characters", max));
+            }
+        }
+        if let Some(pattern) = s.get("pattern").and_then(Value::as_str) {
+            if let Some(re) = self.patterns.get(pattern) {
+                if !re.is_match(text) {
+                    error(errors, path, format!("must match {}", pattern));
+                }
+            }
+        }
+    }
+
+    fn validate_array(
+        &self,
+        s: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        depth: usize,
+        steps: &mut usize,
+        errors: &mut Vec<FieldError>,
+    ) {
+        let len = items.len() as u64;
+        if let Some(min) = s.get("minItems").and_then(Value::as_u64) {
+            if len < min {
+                error(errors, path, format!("must have at least {} items", min));
+            }
+        }
+        if let Some(max) = s.get("maxItems").and_then(Value::as_u64) {
+            if len > max {
+                error(errors, path, format!("must have at most {} items", max));
+            }
+        }
+        if s.get("uniqueItems") == Some(&Value::Bool(true)) {
+            for (i, item) in items.iter().enumerate() {
+                if items[..i].contains(item) {
+                    error(errors, &child(path, &i.to_string()), "is a duplicate".to_string());
+                }
+            }
+        }
+        if let Some(schema) = s.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                let at = child(path, &i.to_string());
+                self.validate_at(schema, item, &at, depth + 1, steps, errors);
+            }
+        }
+    }
+
+    fn validate_object(
+        &self,
+        s: &Map<String, Value>,
+        fields: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+        steps: &mut usize,
+        errors: &mut Vec<FieldError>,
+    ) {
+        let len = fields.len() as u64;
+        if let Some(min) = s.get("minProperties").and_then(Value::as_u64) {
+            if len < min {
+                error(errors, path, format!("must have at least {} properties", min));
+            }
+        }
+        if let Some(max) = s.get("maxProperties").and_then(Value::as_u64) {
+            if len > max {
+                error(errors, path, format!("must have at most {} properties", max));
+            }
+        }
+        if let Some(required) = s.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !fields.contains_key(name) {
+                    error(errors, &child(path, name), "is required".to_string());
+                }
+            }
+        }
+        let properties = s.get("properties").and_then(Value::as_object);
+        for (name, value) in fields {
+            let at = child(path, name);
+            match properties.and_then(|p| p.get(name)) {
+                Some(schema) => self.validate_at(schema, value, &at, depth + 1, steps, errors),
+                None => {
+                    if let Some(schema) = s.get("additionalProperties") {
+                        self.validate_at(schema, value, &at, depth + 1, steps, errors);
+                    }
+                }
+            }
+        }
+    }
+
+    fn validate_combinators(
+        &self,
+        s: &Map<String, Value>,
+        instance: &Value,
+        path: &str,
+        depth: usize,
+        steps: &mut usize,
+        errors: &mut Vec<FieldError>,
+    ) {
+        if let Some(all) = s.get("allOf").and_then(Value::as_array) {
+            for schema in all {
+                self.validate_at(schema, instance, path, depth + 1, steps, errors);
+            }
+        }
+        if let Some(any) = s.get("anyOf").and_then(Value::as_array) {
+            if !any
+                .iter()
+                .any(|schema| self.is_valid(schema, instance, path, depth + 1, steps))
+            {
+                error(errors, path, "must match at least one schema of anyOf".to_string());
+            }
+        }
+        if let Some(one) = s.get("oneOf").and_then(Value::as_array) {
+            let matching = one
+                .iter()
+                .filter(|schema| self.is_valid(schema, instance, path, depth + 1, steps))
+                .count();
+            if matching != 1 {
+                error(
+                    errors,
+                    path,
+                    format!("must match exactly one schema of oneOf, matches {}", matching),
+                );
+            }
+        }
+        if let Some(schema) = s.get("not") {
+            if self.is_valid(schema, instance, path, depth + 1, steps) {
+                error(errors, path, "must not match the schema of not".to_string());
+            }
+        }
+    }
+}
+
+/// Check the keywords of one schema and of its sub schemas
+fn check_schema(
+    root: &Value,
+    schema: &Value,
+    path: &str,
+    patterns: &mut HashMap<String, Regex>,
+    errors: &mut Vec<FieldError>,
+) {
+    let s = match schema {
+        Value::Bool(_) => return,
+        Value::Object(s) => s,
+        _ => return error(errors, path, "a schema must be an object or a boolean".to_string()),
+    };
+    for (keyword, value) in s {
+        let at = child(path, keyword);
+        let valid = match keyword.as_str() {
+            "type" => match value {
+                Value::String(t) => TYPES.contains(&t.as_str()),
+                Value::Array(ts) => ts
+                    .iter()
+                    .all(|t| t.as_str().is_some_and(|t| TYPES.contains(&t))),
+                _ => false,
+            },
+            "enum" | "required" => value.is_array(),
+            "const" => true,
+            "minimum" | "maximum" | "exclusiveMinimum" | "exclusiveMaximum" => value.is_number(),
+            "multipleOf" => value.as_f64().is_some_and(|m| m > 0.0),
+            "minLength" | "maxLength" | "minItems" | "maxItems" | "minProperties"
+            | "maxProperties" => is_count(value),
+            "uniqueItems" => value.is_boolean(),
+            "pattern" => match value.as_str().map(Regex::new) {
+                Some(Ok(re)) => {
+                    patterns.insert(re.as_str().to_string(), re);
+                    true
+                }
+                _ => false,
+            },
+            "$ref" => value
+                .as_str()
+                .is_some_and(|r| r.starts_with('#') && root.pointer(&r[1..]).is_some()),
+            "items" | "additionalProperties" | "not" => {
+                check_schema(root, value, &at, patterns, errors);
+                true
+            }
+            "properties" | "$defs" | "definitions" => match value.as_object() {
+                Some(subs) => {
+                    for (name, sub) in subs {
+                        check_schema(root, sub, &child(&at, name), patterns, errors);
+                    }
+                    true
+                }
+                None => false,
+            },
+            "allOf" | "anyOf" | "oneOf" => match value.as_array() {
+                Some(subs) if !subs.is_empty() => {
+                    for (i, sub) in subs.iter().enumerate() {
+                        check_schema(root, sub, &child(&at, &i.to_string()), patterns, errors);
+                    }
+                    true
+                }
+                _ => false,
+            },
+            k if ANNOTATIONS.contains(&k) => true,
+            _ => {
+                error(errors, &at, format!("unsupported keyword {}", keyword));
+                continue;
+            }
+        };
+        if !valid {
+            error(errors, &at, format!("invalid value for {}", keyword));
+        }
+    }
+    if let Some(required) = s.get("required").and_then(Value::as_array) {
+        if !required.iter().all(Value::is_string) {
+            error(errors, &child(path, "required"), "must list property names".to_string());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    /// The paths of the errors, sorted
+    fn fields(errors: &[FieldError]) -> Vec<&str> {
+        let mut fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        fields.sort();
+        fields
+    }
+
+    #[test]
+    fn validate_test() {
+        let schema = Schema::new(json!({
+            "type": "object",
+            "required": ["host", "port"],
+            "additionalProperties": false,
+            "properties": {
+                "host": {"type": "string", "minLength": 1, "pattern": "^[a-z0-9.-]+$"},
+                "port": {"type": "integer", "minimum": 1, "maximum": 65535},
+                "tags": {"type": "array", "items": {"$ref": "#/$defs/tag"}, "uniqueItems": true},
+                "mode": {"enum": ["active", "standby"]}
+            },
+            "$defs": {"tag": {"type": "string", "maxLength": 8}}
+        }))
+        .unwrap();
+        assert!(schema
+            .validate(&json!({"host": "db.local", "port": 5432, "tags": ["a", "b"]}))
+            .is_empty());
+
+        let errors = schema.validate(&json!({
+            "host": "DB",
+            "port": 70000,
+            "tags": ["a", "a", "much-too-long"],
+            "mode": "off",
+            "extra": 1
+        }));
+        assert_eq!(
+            fields(&errors),
+            vec!["/extra", "/host", "/mode", "/port", "/tags/1", "/tags/2"]
+        );
+        let errors = schema.validate(&json!({"port": "80"}));
+        assert_eq!(fields(&errors), vec!["/host", "/port"]);
+        let port = errors.iter().find(|e| e.field == "/port").unwrap();
+        assert_eq!(port.message, "must be of type integer, not string");
+    }
+
+    #[test]
+    fn combinator_test() {
+        let schema = Schema::new(json!({
+            "oneOf": [{"type": "integer"}, {"type": "number", "multipleOf": 0.5}],
+            "not": {"const": 0}
+        }))
+        .unwrap();
+        assert!(schema.validate(&json!(0.5)).is_empty());
+        assert_eq!(schema.validate(&json!(2)).len(), 1, "matches both branches");
+        assert_eq!(schema.validate(&json!(0.3)).len(), 1);
+        assert!(!schema.validate(&json!(0)).is_empty());
+        assert_eq!(fields(&Schema::new(json!(false)).unwrap().validate(&json!(1))), vec!["/"]);
+    }
+
+    #[test]
+    fn malformed_test() {
+        let errors = Schema::new(json!({
+            "type": "text",
+            "properties": {"a": {"minLength": -1}, "b": {"pattern": "("}},
+            "$ref": "#/$defs/missing"
+        }))
+        .unwrap_err();
+        assert_eq!(
+            fields(&errors),
+            vec!["/$ref", "/properties/a/minLength", "/properties/b/pattern", "/type"]
+        );
+        assert_eq!(Schema::parse("{").unwrap_err().0, StatusCode::UNPROCESSABLE_ENTITY);
+
+        let errors = Schema::new(json!({
+            "title": "limits",
+            "format": "uri",
+            "patternProperties": {"^a": {"type": "string"}},
+            "properties": {"a": {"if": {"const": 1}, "then": {"minimum": 2}}},
+            "prefixItems": [{"type": "string"}],
+            "contains": {"type": "string"},
+            "propertyNames": {"maxLength": 2},
+            "dependentRequired": {"a": ["b"]}
+        }))
+        .unwrap_err();
+        assert_eq!(
+            fields(&errors),
+            vec![
+                "/contains",
+                "/dependentRequired",
+                "/patternProperties",
+                "/prefixItems",
+                "/properties/a/if",
+                "/properties/a/then",
+                "/propertyNames"
+            ]
+        );
+        assert_eq!(errors[0].message, "unsupported keyword patternProperties");
+    }
+
+    #[test]
+    fn budget_test() {
+        // a linked list, legitimately recursing once per level of the instance
+        let schema = Schema::new(json!({
+            "$defs": {"node": {
+                "type": "object",
+                "properties": {"next": {"$ref": "#/$defs/node"}}
+            }},
+            "$ref": "#/$defs/node"
+        }))
+        .unwrap();
+        let mut list = json!({});
+        for _ in 0..100 {
+            list = json!({"next": list});
+        }
+        assert!(schema.validate(&list).is_empty());
+
+        // every level doubles the work
+        let mut defs = serde_json::Map::new();
+        defs.insert("d0".to_string(), json!({"type": "integer"}));
+        for i in 1..40 {
+            let below = json!({"$ref": format!("#/$defs/d{}", i - 1)});
+            defs.insert(format!("d{}", i), json!({"anyOf": [{"not": below}, below]}));
+        }
+        let schema = Schema::new(json!({"$defs": defs, "$ref": "#/$defs/d39"})).unwrap();
+        let errors = schema.validate(&json!("text"));
+        assert_eq!(errors.last().unwrap().message, "schema takes too long to validate");
+
+        let schema = Schema::new(json!({"$defs": {"a": {"$ref": "#/$defs/a"}}, "$ref": "#/$defs/a"}))
+            .unwrap();
+        assert!(!schema.validate(&json!(1)).is_empty());
+    }
+}
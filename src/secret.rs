@@ -0,0 +1,123 @@
+//! Envelope encryption of secret configuration values.
+//! Every value is sealed with its own random data key, the data key is sealed with the
+//! master key read from the keyfile configured in the `secret` section. Both use AES-256-GCM,
+//! the value is bound to its `tenant/namespace/key` so a sealed value can't be moved elsewhere
+use anyhow::anyhow;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::fs::read_to_string;
+
+const KEY_LEN: usize = 32;
+
+/// Shown instead of the value of a secret
+pub const REDACTED: &str = "******";
+
+/// A secret as stored, both fields base64 of a nonce followed by the ciphertext
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct SealedSecret {
+    /// the data key, sealed by the master key
+    pub key: String,
+    /// the value, sealed by the data key
+    pub data: String,
+}
+
+pub struct MasterKey {
+    key: LessSafeKey,
+}
+
+fn aes_key(bytes: &[u8]) -> anyhow::Result<LessSafeKey> {
+    let key = UnboundKey::new(&AES_256_GCM, bytes)
+        .map_err(|_| anyhow!("A key must be {} bytes", KEY_LEN))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn random(len: usize) -> anyhow::Result<Vec<u8>> {
+    let mut bytes = vec![0u8; len];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| anyhow!("No random bytes available"))?;
+    Ok(bytes)
+}
+
+fn seal_with(key: &LessSafeKey, plain: &[u8], aad: &str) -> anyhow::Result<String> {
+    let nonce = random(NONCE_LEN)?;
+    let mut in_out = plain.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::try_assume_unique_for_key(&nonce).map_err(|_| anyhow!("Invalid nonce"))?,
+        Aad::from(aad.as_bytes()),
+        &mut in_out,
+    )
+    .map_err(|_| anyhow!("Encryption failed"))?;
+    let mut sealed = nonce;
+    sealed.extend(in_out);
+    Ok(STANDARD.encode(sealed))
+}
+
+fn open_with(key: &LessSafeKey, sealed: &str, aad: &str) -> anyhow::Result<Vec<u8>> {
+    let sealed = STANDARD.decode(sealed)?;
+    if sealed.len() < NONCE_LEN {
+        return Err(anyhow!("Sealed data is truncated"));
+    }
+    let (nonce, data) = sealed.split_at(NONCE_LEN);
+    let mut in_out = data.to_vec();
+    let plain = key
+        .open_in_place(
+            Nonce::try_assume_unique_for_key(nonce).map_err(|_| anyhow!("Invalid nonce"))?,
+            Aad::from(aad.as_bytes()),
+            &mut in_out,
+        )
+        .map_err(|_| anyhow!("Decryption failed, wrong key or tampered data"))?;
+    Ok(plain.to_vec())
+}
+
+impl MasterKey {
+    pub fn new(bytes: &[u8]) -> anyhow::Result<Self> {
+        Ok(MasterKey {
+            key: aes_key(bytes)?,
+        })
+    }
+
+    /// Read the master key from a file holding the base64 of 32 random bytes,
+    /// made for instance by `openssl rand -base64 32`
+    pub fn load(path: &str) -> anyhow::Result<Self> {
+        let text = read_to_string(path)?;
+        MasterKey::new(&STANDARD.decode(text.trim())?)
+    }
+
+    /// Seal `plain` under a new data key, `context` names where it is stored
+    pub fn seal(&self, plain: &str, context: &str) -> anyhow::Result<SealedSecret> {
+        let data_key = random(KEY_LEN)?;
+        Ok(SealedSecret {
+            key: seal_with(&self.key, &data_key, context)?,
+            data: seal_with(&aes_key(&data_key)?, plain.as_bytes(), context)?,
+        })
+    }
+
+    pub fn open(&self, sealed: &SealedSecret, context: &str) -> anyhow::Result<String> {
+        let data_key = open_with(&self.key, &sealed.key, context)?;
+        let plain = open_with(&aes_key(&data_key)?, &sealed.data, context)?;
+        Ok(String::from_utf8(plain)?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn envelope_test() {
+        let master = MasterKey::new(&[7u8; KEY_LEN]).unwrap();
+        let sealed = master.seal("hunter2", "acme/db/password").unwrap();
+        assert_ne!(sealed, master.seal("hunter2", "acme/db/password").unwrap());
+        assert!(!sealed.data.contains("hunter2"));
+        assert_eq!(master.open(&sealed, "acme/db/password").unwrap(), "hunter2");
+
+        assert!(master.open(&sealed, "acme/other/password").is_err());
+        let other = MasterKey::new(&[8u8; KEY_LEN]).unwrap();
+        assert!(other.open(&sealed, "acme/db/password").is_err());
+        assert!(MasterKey::new(&[1u8; 16]).is_err());
+    }
+}
@@ -0,0 +1,97 @@
+//! The state shared by every handler
+use crate::config::ReviewConfig;
+use crate::document::DocumentCollection;
+use crate::mail::Mailer;
+use crate::memory_repository::MemoryRepository;
+use crate::mongo_api::MongoStore;
+use crate::mongo_repository::MongoRepository;
+use crate::secret::MasterKey;
+use crate::repository::{
+    AuditRepository, ChangesetRepository, ConfigRepository, DocumentRepository, GroupRepository,
+    JobRepository, RoleRepository, TenantRepository, UserRepository,
+};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub users: Arc<dyn UserRepository>,
+    pub roles: Arc<dyn RoleRepository>,
+    pub groups: Arc<dyn GroupRepository>,
+    pub tenants: Arc<dyn TenantRepository>,
+    pub audit: Arc<dyn AuditRepository>,
+    pub configs: Arc<dyn ConfigRepository>,
+    pub documents: Arc<dyn DocumentRepository>,
+    pub changesets: Arc<dyn ChangesetRepository>,
+    pub jobs: Arc<dyn JobRepository>,
+    /// the collections open to the document API, by name
+    pub collections: Arc<BTreeMap<String, DocumentCollection>>,
+    /// seals secret values, secrets can't be written without it
+    pub master_key: Option<Arc<MasterKey>>,
+    /// the namespaces changed only through reviewed changesets and their reviewers
+    pub review: Arc<ReviewConfig>,
+    pub mailer: Arc<dyn Mailer>,
+    /// prefix of the links sent by mail, like `https://cf.example.com`
+    pub link_base: String,
+}
+
+impl AppState {
+    /// Store everything in the MongoDB database `db`
+    pub fn mongo(store: MongoStore, mailer: Arc<dyn Mailer>, link_base: &str) -> Self {
+        let repo = Arc::new(MongoRepository::new(store));
+        AppState {
+            users: repo.clone(),
+            roles: repo.clone(),
+            groups: repo.clone(),
+            tenants: repo.clone(),
+            audit: repo.clone(),
+            configs: repo.clone(),
+            documents: repo.clone(),
+            changesets: repo.clone(),
+            jobs: repo,
+            collections: Arc::default(),
+            master_key: None,
+            review: Arc::default(),
+            mailer,
+            link_base: link_base.to_string(),
+        }
+    }
+
+    /// Keep everything in memory, lost on restart
+    pub fn memory(mailer: Arc<dyn Mailer>, link_base: &str) -> Self {
+        let repo = Arc::new(MemoryRepository::new());
+        AppState {
+            users: repo.clone(),
+            roles: repo.clone(),
+            groups: repo.clone(),
+            tenants: repo.clone(),
+            audit: repo.clone(),
+            configs: repo.clone(),
+            documents: repo.clone(),
+            changesets: repo.clone(),
+            jobs: repo,
+            collections: Arc::default(),
+            master_key: None,
+            review: Arc::default(),
+            mailer,
+            link_base: link_base.to_string(),
+        }
+    }
+
+    pub fn with_master_key(mut self, master_key: MasterKey) -> Self {
+        self.master_key = Some(Arc::new(master_key));
+        self
+    }
+
+    /// Require reviewed changesets to change the namespaces of `review`
+    pub fn with_review(mut self, review: ReviewConfig) -> Self {
+        self.review = Arc::new(review);
+        self
+    }
+
+    /// Open `collections` to the document API
+    pub fn with_collections(mut self, collections: BTreeMap<String, DocumentCollection>) -> Self {
+        self.collections = Arc::new(collections);
+        self
+    }
+}
@@ -0,0 +1,192 @@
+//! Tenants, each user and configuration entry belongs to one
+use crate::audit::{snapshot, AuditContext};
+use crate::repository::internal;
+use crate::state::AppState;
+use crate::{admin_check, authorize};
+use axum::http::header::HeaderMap;
+use axum::Json;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+};
+use mongodb::bson::{doc, Bson, Document};
+use serde::{Deserialize, Serialize};
+
+/// The tenant of entries stored before tenants existed
+pub const DEFAULT_TENANT: &str = "default";
+
+pub fn default_tenant() -> String {
+    DEFAULT_TENANT.to_string()
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tenant {
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+}
+
+/// Filter on the `tenant` field, entries without it belong to the default tenant
+pub fn tenant_filter(tenant: &str) -> Document {
+    if tenant == DEFAULT_TENANT {
+        doc! {"tenant": {"$in": [DEFAULT_TENANT, Bson::Null]}}
+    } else {
+        doc! {"tenant": tenant}
+    }
+}
+
+/// Restrict `filter` to the tenant of the scope, `None` leaves it unrestricted
+pub fn scoped(mut filter: Document, scope: Option<&str>) -> Document {
+    if let Some(tenant) = scope {
+        filter.extend(tenant_filter(tenant));
+    }
+    filter
+}
+
+/// Check an entry of tenant `tenant` may be written by a caller confined to `scope`
+pub fn check_scope(scope: Option<&str>, tenant: &str) -> Result<(), (StatusCode, String)> {
+    match scope {
+        Some(s) if s != tenant => Err((
+            StatusCode::FORBIDDEN,
+            format!("Not allowed to manage tenant {}", tenant),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Check the tenant exists, the default tenant always does
+pub async fn validate_tenant(state: &AppState, tenant: &str) -> Result<(), (StatusCode, String)> {
+    if tenant == DEFAULT_TENANT {
+        return Ok(());
+    }
+    match state.tenants.find_tenant(tenant).await.map_err(internal)? {
+        Some(_) => Ok(()),
+        None => Err((StatusCode::BAD_REQUEST, format!("Unknown tenant: {}", tenant))),
+    }
+}
+
+pub async fn get_tenants(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = authorize(&headers, &state, "get_tenants", "").await?;
+    let tenants = state
+        .tenants
+        .list_tenants(p.tenant_scope())
+        .await
+        .map_err(internal)?;
+    Ok(serde_json::to_string(&tenants).unwrap())
+}
+
+pub async fn create_tenant(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<Tenant>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "create_tenant", &payload.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_tenant", &payload.name);
+    let res = match state.tenants.find_tenant(&payload.name).await {
+        Ok(Some(_)) => Err((StatusCode::CONFLICT, "Tenant Name esists".to_string())),
+        Ok(None) if payload.name == DEFAULT_TENANT => {
+            Err((StatusCode::CONFLICT, "Tenant Name esists".to_string()))
+        }
+        Ok(None) => state
+            .tenants
+            .insert_tenant(&payload)
+            .await
+            .map(|_| payload.name.clone())
+            .map_err(internal),
+        Err(e) => Err(internal(e)),
+    };
+    audit.record(&state, &res, None, snapshot(&payload)).await;
+    res
+}
+
+/// Delete a tenant, refused with 409 while it still has users, groups, configuration,
+/// changesets or scheduled jobs, which a tenant created again under its name would inherit
+pub async fn delete_tenant(
+    headers: HeaderMap,
+    Path(name): Path<String>,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = admin_check(&headers, &state, "delete_tenant", &name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_tenant", &name);
+    let before = state.tenants.find_tenant(&name).await.ok().flatten();
+    let res = match before {
+        None => Err((StatusCode::NOT_FOUND, "Not Found".to_string())),
+        Some(_) => match state.tenants.tenant_entries(&name).await {
+            Ok(entries) if entries.is_empty() => state
+                .tenants
+                .delete_tenant(&name)
+                .await
+                .map(|r| serde_json::to_string(&r).unwrap())
+                .map_err(internal),
+            Ok(entries) => {
+                let entries: Vec<String> =
+                    entries.iter().map(|(kind, n)| format!("{} {}", n, kind)).collect();
+                Err((
+                    StatusCode::CONFLICT,
+                    format!("{} still has {}", name, entries.join(", ")),
+                ))
+            }
+            Err(e) => Err(internal(e)),
+        },
+    };
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::configuration::ValueType;
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
+    use crate::user::UserProfile;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn scope_test() {
+        let f = scoped(doc! {"name": "u1"}, Some("acme"));
+        assert_eq!(f, doc! {"name": "u1", "tenant": "acme"});
+        assert_eq!(scoped(doc! {"name": "u1"}, None), doc! {"name": "u1"});
+        let d = scoped(doc! {}, Some(DEFAULT_TENANT));
+        assert!(d.get_document("tenant").unwrap().contains_key("$in"));
+
+        assert!(check_scope(None, "acme").is_ok());
+        assert!(check_scope(Some("acme"), "acme").is_ok());
+        assert_eq!(
+            check_scope(Some("acme"), "other").unwrap_err().0,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[tokio::test]
+    async fn delete_test() {
+        let state = State(AppState::memory(Arc::new(MemoryMailer::default()), "http://cf"));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let acme = Tenant {
+            name: "acme".to_string(),
+            description: String::new(),
+        };
+        create_tenant(headers.clone(), state.clone(), Json(acme)).await.unwrap();
+        state
+            .configs
+            .put_item("acme", "shop", "db.host", &json!("a"), ValueType::String, "u")
+            .await
+            .unwrap();
+        let delete = || delete_tenant(headers.clone(), Path("acme".to_string()), state.clone());
+
+        // its configuration would pass to a tenant created again under its name
+        let refused = delete().await.unwrap_err();
+        assert_eq!(refused.0, StatusCode::CONFLICT);
+        assert_eq!(refused.1, "acme still has 1 configuration items");
+        state.configs.delete_item("acme", "shop", "db.host").await.unwrap();
+        delete().await.unwrap();
+    }
+}
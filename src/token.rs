@@ -1,7 +1,9 @@
 use super::user::UserProfile;
 use chrono;
 use jsonwebtoken::{
-    decode, encode, errors::Result, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+    decode, encode,
+    errors::{ErrorKind, Result},
+    Algorithm, DecodingKey, EncodingKey, Header, Validation,
 };
 use serde::{Deserialize, Serialize};
 
@@ -53,13 +55,58 @@ pub fn verify_token(token: &str) -> Result<UserProfile> {
     }
 }
 
+/// Claims of single purpose tokens sent in links, such as email verification
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct ActionClaims {
+    /// id of the user
+    pub sub: String,
+    pub purpose: String,
+    /// the state the token is bound to, it stops being valid once that state changes
+    pub binding: String,
+    pub exp: i64,
+}
+
+/// Generate a token allowing `purpose` on the user `user_id` while `binding` holds
+pub fn generate_action_token(
+    user_id: &str,
+    purpose: &str,
+    binding: &str,
+    expire_in: i64,
+) -> Result<String> {
+    let claims = ActionClaims {
+        sub: user_id.to_string(),
+        purpose: purpose.to_string(),
+        binding: binding.to_string(),
+        exp: chrono::offset::Utc::now().timestamp() + expire_in,
+    };
+    encode(
+        &Header::default(),
+        &claims,
+        &EncodingKey::from_secret(SECRET_KEY),
+    )
+}
+
+/// Verify a token made by `generate_action_token` for `purpose`
+pub fn verify_action_token(token: &str, purpose: &str) -> Result<ActionClaims> {
+    let claims = decode::<ActionClaims>(
+        token,
+        &DecodingKey::from_secret(SECRET_KEY),
+        &Validation::new(Algorithm::HS256),
+    )
+    .map(|data| data.claims)?;
+    if claims.purpose != purpose {
+        return Err(ErrorKind::InvalidToken.into());
+    }
+    Ok(claims)
+}
+
 #[cfg(test)]
 mod test {
     use crate::user::UserBase;
 
     use super::*;
     use chrono::Utc;
-    use jsonwebtoken::{decode_header, errors::ErrorKind};
+    use jsonwebtoken::decode_header;
     // Example user profile struct
 
     #[test]
@@ -69,6 +116,8 @@ mod test {
             phone: "12123".to_string(),
             roles: vec!["admin".to_string(), "super".to_string()],
             permissions: vec!["read".to_string(), "write".to_string()],
+            tenant: "acme".to_string(),
+            ..Default::default()
         };
         let user_profile = UserProfile {
             _id: "122333".to_string(),
@@ -82,20 +131,21 @@ mod test {
         let header = decode_header(&token).unwrap();
         println!("header: {:?}", header);
 
-        let user = verify_token(&*token).unwrap();
+        let user = verify_token(&token).unwrap();
         assert_eq!(user._id, user_profile._id);
+        assert_eq!(user.user_base.tenant, "acme");
 
         let token2 = generate_token(&user_profile, -100).unwrap();
         println!("token: {}", token);
 
-        if let Err(e) = verify_token(&*token2) {
+        if let Err(e) = verify_token(&token2) {
             println!("error:{:?}", e.to_string());
             match e.kind() {
                 ErrorKind::ExpiredSignature => println!("expire"),
                 _ => println!("others"),
             }
         } else {
-            assert!(false);
+            panic!("token should be rejected");
         }
 
         if let Err(e) = verify_token("123") {
@@ -106,7 +156,19 @@ mod test {
                 _ => println!("others"),
             }
         } else {
-            assert!(false);
+            panic!("token should be rejected");
         }
     }
+
+    #[test]
+    fn action_token_test() {
+        let token = generate_action_token("u1", "verify_email", "zs@example.com", 60).unwrap();
+        let claims = verify_action_token(&token, "verify_email").unwrap();
+        assert_eq!(claims.sub, "u1");
+        assert_eq!(claims.binding, "zs@example.com");
+        assert!(verify_action_token(&token, "reset_password").is_err());
+        // a login token is not an action token
+        let login = generate_token(&UserProfile::default_super(), 60).unwrap();
+        assert!(verify_action_token(&login, "verify_email").is_err());
+    }
 }
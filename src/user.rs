@@ -1,4 +1,11 @@
-use crate::{permission_check, utils};
+use crate::audit::{snapshot, AuditContext};
+use crate::group::EffectiveGrants;
+use crate::repository::internal;
+use crate::role::{check_grantable, validate_user_grants};
+use crate::state::AppState;
+use crate::tenant::{check_scope, default_tenant, validate_tenant};
+use crate::validation::{validate_email, validate_phone, validate_user_base};
+use crate::{active_profile, authorize, principal, utils, Principal};
 use axum::http::header::HeaderMap;
 use axum::Json;
 use axum::{
@@ -6,23 +13,12 @@ use axum::{
     http::StatusCode,
 };
 use chrono::{DateTime, Utc};
-use futures::stream::TryStreamExt;
-use mongodb::bson;
-use mongodb::bson::oid::ObjectId;
-use mongodb::options::FindOptions;
-use mongodb::{
-    bson::{doc, oid, Bson},
-    Collection, Database,
-};
+use mongodb::bson::oid::{self, ObjectId};
+use mongodb::bson::Bson;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use tracing::{error, info};
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
-pub struct ConfigurationItems {
-    key: String,
-    value: String, //json string
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
 pub struct UserBase {
     pub name: String,
@@ -31,6 +27,44 @@ pub struct UserBase {
     pub roles: Vec<String>,
     #[serde(default)]
     pub permissions: Vec<String>,
+    #[serde(default = "default_tenant")]
+    pub tenant: String,
+    #[serde(default)]
+    pub email: Option<String>,
+    #[serde(default)]
+    pub display_name: Option<String>,
+    #[serde(default)]
+    pub locale: Option<String>,
+    #[serde(default)]
+    pub timezone: Option<String>,
+    /// free form metadata
+    #[serde(default)]
+    pub attributes: BTreeMap<String, serde_json::Value>,
+    /// set by following the link sent to `email`, never taken from requests
+    #[serde(default)]
+    pub email_verified: bool,
+    /// disabled users can't log in
+    #[serde(default)]
+    pub disabled: bool,
+}
+
+impl Default for UserBase {
+    fn default() -> Self {
+        UserBase {
+            name: String::new(),
+            phone: String::new(),
+            roles: vec![],
+            permissions: vec![],
+            tenant: default_tenant(),
+            email: None,
+            display_name: None,
+            locale: None,
+            timezone: None,
+            attributes: BTreeMap::new(),
+            email_verified: false,
+            disabled: false,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -47,7 +81,7 @@ impl UserProfile {
             name: "super".to_string(),
             phone: "111111".to_string(),
             roles: vec!["super".to_string()],
-            permissions: vec![],
+            ..Default::default()
         };
         UserProfile {
             _id: "0".to_string(),
@@ -89,11 +123,23 @@ impl From<UserCreation> for UserCreationDB {
             user_creation: value,
         };
 
-        u.user_creation.password = utils::encrypt(&*u.user_creation.password).unwrap();
+        u.user_creation.password = utils::encrypt(&u.user_creation.password).unwrap();
         u
     }
 }
 
+impl UserCreationDB {
+    /// The user as stored under `_id`
+    pub fn into_stored(self, _id: Bson) -> UserInDB {
+        UserInDB {
+            _id,
+            password: self.user_creation.password,
+            create_at: self.create_at,
+            user_base: self.user_creation.user_base,
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct UserCreation {
     password: String,
@@ -109,53 +155,89 @@ pub struct UserInDB {
     pub user_base: UserBase,
 }
 
-const COLLECTION: &str = "user";
-
 pub async fn create_user(
     headers: HeaderMap, //the order is important!
-    db: State<Database>,
+    state: State<AppState>,
     Json(payload): Json<UserCreation>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "create_user", &*payload.user_base.name)?;
-    let c = db.collection(COLLECTION);
-    let f = c
-        .find_one(doc! {"name":&payload.user_base.name}, None)
+    let p = authorize(&headers, &state, "create_user", &payload.user_base.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "create_user", &payload.user_base.name);
+    let res = insert_user(&state, &p, payload).await;
+    let after = res.as_ref().ok().and_then(|(_, profile)| snapshot(profile));
+    let res = res.map(|(id, _)| id);
+    audit.record(&state, &res, None, after).await;
+    res
+}
+
+async fn insert_user(
+    state: &AppState,
+    p: &Principal,
+    payload: UserCreation,
+) -> Result<(String, UserProfile), (StatusCode, String)> {
+    validate_user_base(&payload.user_base)?;
+    check_scope(p.tenant_scope(), &payload.user_base.tenant)?;
+    check_grantable(p, &payload.user_base.roles)?;
+    let f = state
+        .users
+        .find_user_by_name(&payload.user_base.name, None)
         .await;
     if let Ok(Some(_)) = f {
         return Err((StatusCode::CONFLICT, "User Name esists".to_string()));
     }
-    let ud: UserCreationDB = payload.into();
-    c.insert_one(ud, None)
-        .await
-        .map(|r| match r.inserted_id {
-            Bson::ObjectId(id) => id.to_string(),
-            _ => "".to_owned(),
-        })
-        .map_err(|e| {
-            error!("creat user faield: {:?}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-        })
+    validate_tenant(state, &payload.user_base.tenant).await?;
+    validate_user_grants(state, &payload.user_base).await?;
+    let mut ud: UserCreationDB = payload.into();
+    ud.user_creation.user_base.email_verified = false;
+    let id = state.users.insert_user(ud.clone()).await.map_err(|e| {
+        error!("creat user faield: {:?}", e);
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })?;
+    let profile = UserProfile {
+        _id: id.clone(),
+        create_at: ud.create_at,
+        user_base: ud.user_creation.user_base,
+    };
+    Ok((id, profile))
 }
 
 pub async fn update_user(
     headers: HeaderMap,
-    db: State<Database>,
+    state: State<AppState>,
     Json(payload): Json<UserProfile>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "update_user", &*payload.user_base.name)?;
-
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let oid = build_obj_id(&*payload._id)?;
-
-    let filter = doc! { "_id": Bson::ObjectId(oid) };
-    let mut update_doc = bson::to_document(&payload).map_err(|e| {
-        error!("build update doc faield: {:?}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-    })?;
-    update_doc.remove("_id");
-    let update = doc! {"$set": update_doc};
+    let p = authorize(&headers, &state, "update_user", &payload.user_base.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "update_user", &payload._id);
+    build_obj_id(&payload._id)?;
+
+    let before = find_profile(&state, &payload._id, p.tenant_scope()).await;
+    let res = replace_user(&state, &p, before.as_ref(), &payload).await;
+    let after = find_profile(&state, &payload._id, p.tenant_scope()).await;
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), after.as_ref().and_then(snapshot))
+        .await;
+    res
+}
 
-    c.update_one(filter, update, None)
+async fn replace_user(
+    state: &AppState,
+    p: &Principal,
+    current: Option<&UserProfile>,
+    payload: &UserProfile,
+) -> Result<String, (StatusCode, String)> {
+    validate_user_base(&payload.user_base)?;
+    check_scope(p.tenant_scope(), &payload.user_base.tenant)?;
+    check_grantable(p, &payload.user_base.roles)?;
+    validate_tenant(state, &payload.user_base.tenant).await?;
+    validate_user_grants(state, &payload.user_base).await?;
+    let mut user_base = payload.user_base.clone();
+    // only following the link sent by mail verifies an email
+    user_base.email_verified = match current {
+        Some(u) if u.user_base.email == user_base.email => u.user_base.email_verified,
+        _ => false,
+    };
+    state
+        .users
+        .update_user_base(&payload._id, p.tenant_scope(), &user_base)
         .await
         .map(|r| serde_json::to_string(&r).unwrap())
         .map_err(|e| {
@@ -166,23 +248,38 @@ pub async fn update_user(
 
 pub async fn delete_user(
     headers: HeaderMap,
-    db: State<Database>,
+    state: State<AppState>,
     Json(payload): Json<UserProfile>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "delete_user", &*payload.user_base.name)?;
-
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let oid = build_obj_id(&*payload._id)?;
-
-    let filter = doc! { "_id": Bson::ObjectId(oid) };
-
-    c.delete_one(filter, None)
+    let p = authorize(&headers, &state, "delete_user", &payload.user_base.name).await?;
+    let audit = AuditContext::new(&headers, &p.profile, "delete_user", &payload._id);
+    build_obj_id(&payload._id)?;
+
+    let before = find_profile(&state, &payload._id, p.tenant_scope()).await;
+    let res = state
+        .users
+        .delete_user(&payload._id, p.tenant_scope())
         .await
         .map(|r| serde_json::to_string(&r).unwrap())
         .map_err(|e| {
             error!("delete faield: {:?}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-        })
+        });
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), None)
+        .await;
+    res
+}
+
+/// Load the current profile of a user for audit snapshots
+async fn find_profile(state: &AppState, id: &str, scope: Option<&str>) -> Option<UserProfile> {
+    state
+        .users
+        .find_user_by_id(id, scope)
+        .await
+        .ok()
+        .flatten()
+        .map(UserProfile::from)
 }
 
 fn user_prfile_after_find(res: Option<UserInDB>) -> Result<String, (StatusCode, String)> {
@@ -197,13 +294,13 @@ fn user_prfile_after_find(res: Option<UserInDB>) -> Result<String, (StatusCode,
 pub async fn find_user_by_id(
     headers: HeaderMap,
     Path(user_id): Path<String>,
-    db: State<Database>,
+    state: State<AppState>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "find_user_by_id", &*user_id)?;
-    let oid = build_obj_id(&*user_id)?;
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let f = c
-        .find_one(doc! {"_id":Bson::ObjectId(oid)}, None)
+    let p = authorize(&headers, &state, "find_user_by_id", &user_id).await?;
+    build_obj_id(&user_id)?;
+    let f = state
+        .users
+        .find_user_by_id(&user_id, p.tenant_scope())
         .await
         .map_err(|e| {
             error!("find user failed, {:?}", e);
@@ -215,12 +312,12 @@ pub async fn find_user_by_id(
 pub async fn find_user_by_name(
     headers: HeaderMap,
     Path(user_name): Path<String>,
-    db: State<Database>,
+    state: State<AppState>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "find_user_by_name", &*user_name)?;
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let f = c
-        .find_one(doc! {"name":user_name}, None)
+    let p = authorize(&headers, &state, "find_user_by_name", &user_name).await?;
+    let f = state
+        .users
+        .find_user_by_name(&user_name, p.tenant_scope())
         .await
         .map_err(|e| {
             error!("find user failed, {:?}", e);
@@ -236,36 +333,22 @@ pub struct NumberOfUsers {
 
 pub async fn get_number_of_all_users(
     headers: HeaderMap,
-    db: State<Database>,
+    state: State<AppState>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "find_user_by_name", "")?;
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let mut cursor = c
-        .aggregate(vec![doc! {"$count":"total"}], None)
+    let p = authorize(&headers, &state, "find_user_by_name", "").await?;
+    let num = state
+        .users
+        .count_users(p.tenant_scope())
         .await
         .map_err(|e| {
             error!("get_number_of_all_users failed, {:?}", e);
             (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
         })?;
 
-    let num = cursor
-        .try_next()
-        .await
-        .map(|res| match res {
-            Some(doc) => doc.get_i32("total").unwrap_or(-1),
-            None => -1,
-        })
-        .map_err(|e| {
-            error!("cursor browse error {}", e);
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-        })?;
-
-    Ok(
-        serde_json::to_string(&NumberOfUsers { total: num }).map_err(|e| {
-            error!("serilizer error {e:?}");
-            (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-        })?,
-    )
+    serde_json::to_string(&NumberOfUsers { total: num as i32 }).map_err(|e| {
+        error!("serilizer error {e:?}");
+        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+    })
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -277,37 +360,105 @@ pub struct QueryUserListOptions {
 
 pub async fn get_user_in_page(
     headers: HeaderMap,
-    db: State<Database>,
+    state: State<AppState>,
     Json(payload): Json<QueryUserListOptions>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "get_user_in_page", "")?;
-    let c: Collection<UserInDB> = db.collection(COLLECTION);
-    let skip = Some(payload.skip);
-    let limit = Some(payload.limit);
-    let options_builder = FindOptions::builder().skip(skip).limit(limit);
-    let options_builder2 = match payload.sort_by_name {
-        -1 => options_builder.sort(doc! {"name":-1}),
-        1 => options_builder.sort(doc! {"name":1}),
-        _ => options_builder.sort(doc! {}),
-    };
-    let options = options_builder2.build();
-    let mut cursor = c.find(doc! {}, options).await.map_err(|e| {
-        error!("get cursor failed, {:?}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-    })?;
-    let mut users = Vec::<UserProfile>::new();
-    while let Some(user_in_db) = cursor.try_next().await.map_err(|e| {
-        error!("cursor browse error {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-    })? {
-        let user_profile = UserProfile::from(user_in_db);
-        users.push(user_profile);
-    }
+    let p = authorize(&headers, &state, "get_user_in_page", "").await?;
+    let users: Vec<UserProfile> = state
+        .users
+        .list_users(p.tenant_scope(), payload.skip, payload.limit, payload.sort_by_name)
+        .await
+        .map_err(internal)?
+        .into_iter()
+        .map(UserProfile::from)
+        .collect();
     info!("users : {:?}", users);
 
     Ok(serde_json::to_string(&users).unwrap())
 }
 
+/// The authenticated user's profile with its effective grants
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CurrentUser {
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    pub effective: EffectiveGrants,
+}
+
+/// The fields users may change on their own profile
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SelfUpdate {
+    pub phone: Option<String>,
+    /// a new email has to be verified again
+    pub email: Option<String>,
+}
+
+/// Who am I: the caller's profile and effective grants
+pub async fn get_me(
+    headers: HeaderMap,
+    state: State<AppState>,
+) -> Result<String, (StatusCode, String)> {
+    let p = principal(&headers, &state, "get_me", "").await?;
+    let me = CurrentUser {
+        profile: p.profile,
+        effective: p.grants,
+    };
+    Ok(serde_json::to_string(&me).unwrap())
+}
+
+/// Update the non privileged fields of the caller's own profile
+pub async fn update_me(
+    headers: HeaderMap,
+    state: State<AppState>,
+    Json(payload): Json<SelfUpdate>,
+) -> Result<String, (StatusCode, String)> {
+    let p = active_profile(&headers, &state, "update_me", "").await?;
+    let audit = AuditContext::new(&headers, &p, "update_me", &p._id);
+    let before = find_profile(&state, &p._id, None).await;
+    let res = match &before {
+        None => Err((StatusCode::BAD_REQUEST, "User is not stored".to_string())),
+        Some(current) => match self_update(&payload, &current.user_base) {
+            Err(e) => Err(e),
+            Ok(None) => Err((StatusCode::BAD_REQUEST, "Nothing to update".to_string())),
+            Ok(Some(user_base)) => state
+                .users
+                .update_user_base(&p._id, None, &user_base)
+                .await
+                .map(|r| serde_json::to_string(&r).unwrap())
+                .map_err(|e| {
+                    error!("update faield: {:?}", e);
+                    (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
+                }),
+        },
+    };
+    let after = find_profile(&state, &p._id, None).await;
+    audit
+        .record(&state, &res, before.as_ref().and_then(snapshot), after.as_ref().and_then(snapshot))
+        .await;
+    res
+}
+
+/// Validate a self update and apply it to `current`, `None` when nothing changes
+fn self_update(
+    payload: &SelfUpdate,
+    current: &UserBase,
+) -> Result<Option<UserBase>, (StatusCode, String)> {
+    let mut updated = current.clone();
+    if let Some(phone) = &payload.phone {
+        validate_phone(phone)?;
+        updated.phone = phone.clone();
+    }
+    if let Some(email) = &payload.email {
+        validate_email(email)?;
+        if current.email.as_ref() != Some(email) {
+            updated.email = Some(email.clone());
+            updated.email_verified = false;
+        }
+    }
+    let changed = payload.phone.is_some() || updated != *current;
+    Ok(Some(updated).filter(|_| changed))
+}
+
 fn build_obj_id(id: &str) -> Result<ObjectId, (StatusCode, String)> {
     let oid = oid::ObjectId::parse_str(id).map_err(|e| {
         error!("parse id failed , {:?}", e);
@@ -316,18 +467,37 @@ fn build_obj_id(id: &str) -> Result<ObjectId, (StatusCode, String)> {
     Ok(oid)
 }
 
+/// Store a user made of `user_base` and sign its token, for the tests of the handlers
+#[cfg(test)]
+pub(crate) async fn stored_headers(state: &AppState, user_base: UserBase) -> HeaderMap {
+    let creation = UserCreation {
+        password: "hunter22".to_string(),
+        user_base,
+    };
+    let id = state.users.insert_user(creation.into()).await.unwrap();
+    let stored = state.users.find_user_by_id(&id, None).await.unwrap().unwrap();
+    let token = crate::token::generate_token(&UserProfile::from(stored), 60).unwrap();
+    let mut headers = HeaderMap::new();
+    headers.insert("authorization", token.parse().unwrap());
+    headers
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::audit::{AuditQuery, Outcome, ANONYMOUS};
+    use crate::auth::{authenticate, Authentication, AuthenticationResponse};
+    use crate::mail::MemoryMailer;
+    use crate::token::generate_token;
     use chrono::Utc;
+    use std::sync::Arc;
 
     #[test]
     fn struct_test() {
         let user_base = UserBase {
             name: "zhangsang".to_string(),
             phone: "12344".to_string(),
-            roles: vec![],
-            permissions: vec![],
+            ..Default::default()
         };
         let user_profile = UserProfile {
             _id: "asdfbasfalsjdf".to_string(),
@@ -342,5 +512,166 @@ mod test {
 
         let user: UserProfile = serde_json::from_str(ss).unwrap();
         assert_eq!(user.user_base.name, "zhangsang");
+        assert_eq!(user.user_base.tenant, "default");
+    }
+
+    #[test]
+    fn self_update_test() {
+        let mut me = UserProfile::default_super().user_base;
+        me.email = Some("old@example.com".to_string());
+        me.email_verified = true;
+        let update = SelfUpdate {
+            phone: Some("+14155550123".to_string()),
+            email: Some("new@example.com".to_string()),
+        };
+        let u = self_update(&update, &me).unwrap().unwrap();
+        assert_eq!(u.email.as_deref(), Some("new@example.com"));
+        assert!(!u.email_verified);
+
+        let same = SelfUpdate {
+            phone: None,
+            email: Some("old@example.com".to_string()),
+        };
+        assert!(self_update(&same, &me).unwrap().is_none());
+
+        let bad = SelfUpdate {
+            phone: Some("123".to_string()),
+            email: None,
+        };
+        assert!(self_update(&bad, &me).is_err());
+    }
+
+    #[tokio::test]
+    async fn memory_api_test() {
+        let state = State(AppState::memory(Arc::new(MemoryMailer::default()), "http://cf"));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+
+        let creation: UserCreation = serde_json::from_str(
+            r#"{"name":"zhangsan","phone":"+8613800138000","password":"hunter22","email":"zs@example.com"}"#,
+        )
+        .unwrap();
+        let id = create_user(headers.clone(), state.clone(), Json(creation.clone()))
+            .await
+            .unwrap();
+        let conflict = create_user(headers.clone(), state.clone(), Json(creation)).await;
+        assert_eq!(conflict.unwrap_err().0, StatusCode::CONFLICT);
+
+        let found = find_user_by_name(headers.clone(), Path("zhangsan".to_string()), state.clone())
+            .await
+            .unwrap();
+        let mut profile: UserProfile = serde_json::from_str(&found).unwrap();
+        assert_eq!(profile._id, id);
+
+        profile.user_base.display_name = Some("Zhang San".to_string());
+        update_user(headers.clone(), state.clone(), Json(profile.clone()))
+            .await
+            .unwrap();
+        let found = find_user_by_id(headers.clone(), Path(id.clone()), state.clone())
+            .await
+            .unwrap();
+        let updated: UserProfile = serde_json::from_str(&found).unwrap();
+        assert_eq!(updated.user_base.display_name.as_deref(), Some("Zhang San"));
+
+        let num = get_number_of_all_users(headers.clone(), state.clone()).await.unwrap();
+        assert_eq!(num, r#"{"total":1}"#);
+
+        delete_user(headers.clone(), state.clone(), Json(profile)).await.unwrap();
+        let gone = find_user_by_id(headers.clone(), Path(id), state.clone()).await;
+        assert_eq!(gone.unwrap_err().0, StatusCode::NOT_FOUND);
+
+        let query = AuditQuery {
+            action: Some("create_user".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(state.audit.search_audit(&query, None).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn disabled_test() {
+        let state = State(AppState::memory(Arc::new(MemoryMailer::default()), "http://cf"));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let creation: UserCreation =
+            serde_json::from_str(r#"{"name":"lisi","phone":"+8613800138001","password":"hunter22"}"#)
+                .unwrap();
+        let id = create_user(headers.clone(), state.clone(), Json(creation)).await.unwrap();
+
+        let login = || {
+            let payload = Authentication {
+                name: "lisi".to_string(),
+                password: "hunter22".to_string(),
+            };
+            authenticate(state.clone(), Json(payload))
+        };
+        let res: AuthenticationResponse = serde_json::from_str(&login().await.unwrap()).unwrap();
+        let mut user_headers = HeaderMap::new();
+        user_headers.insert("authorization", res.token.parse().unwrap());
+        get_me(user_headers.clone(), state.clone()).await.unwrap();
+
+        let found = find_user_by_id(headers.clone(), Path(id), state.clone()).await.unwrap();
+        let mut profile: UserProfile = serde_json::from_str(&found).unwrap();
+        profile.user_base.disabled = true;
+        update_user(headers.clone(), state.clone(), Json(profile)).await.unwrap();
+
+        assert_eq!(login().await.unwrap_err().0, StatusCode::FORBIDDEN);
+        // the token issued before is refused too
+        let me = get_me(user_headers.clone(), state.clone()).await;
+        assert_eq!(me.unwrap_err().0, StatusCode::FORBIDDEN);
+        // super is not stored
+        get_me(headers.clone(), state.clone()).await.unwrap();
+
+        // nor is a deleted user any longer
+        let found = find_user_by_name(headers.clone(), Path("lisi".to_string()), state.clone())
+            .await
+            .unwrap();
+        let profile: UserProfile = serde_json::from_str(&found).unwrap();
+        delete_user(headers, state.clone(), Json(profile)).await.unwrap();
+        let me = get_me(user_headers, state.clone()).await;
+        assert_eq!(me.unwrap_err().0, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn stored_grants_test() {
+        let state = State(AppState::memory(Arc::new(MemoryMailer::default()), "http://cf"));
+        let mut headers = HeaderMap::new();
+        let token = generate_token(&UserProfile::default_super(), 60).unwrap();
+        headers.insert("authorization", token.parse().unwrap());
+        let user_base = UserBase {
+            name: "wangwu".to_string(),
+            phone: "+8613800138004".to_string(),
+            roles: vec!["admin".to_string()],
+            ..Default::default()
+        };
+        let user_headers = stored_headers(&state, user_base).await;
+        get_number_of_all_users(user_headers.clone(), state.clone()).await.unwrap();
+
+        // the token still names the role, the stored user has lost it
+        let stored = state.users.find_user_by_name("wangwu", None).await.unwrap().unwrap();
+        let mut profile = UserProfile::from(stored);
+        profile.user_base.roles = vec![];
+        update_user(headers, state.clone(), Json(profile)).await.unwrap();
+        let denied = get_number_of_all_users(user_headers.clone(), state.clone()).await;
+        assert_eq!(denied.unwrap_err().0, StatusCode::FORBIDDEN);
+        let me: CurrentUser =
+            serde_json::from_str(&get_me(user_headers, state.clone()).await.unwrap()).unwrap();
+        assert!(me.profile.user_base.roles.is_empty());
+        assert!(!me.effective.is_admin());
+
+        // denials are audited, without a token too
+        let anonymous = get_number_of_all_users(HeaderMap::new(), state.clone()).await;
+        assert_eq!(anonymous.unwrap_err().0, StatusCode::UNAUTHORIZED);
+        let records = state.audit.search_audit(&AuditQuery::default(), None).await.unwrap();
+        let denials: Vec<(&str, &str)> = records
+            .iter()
+            .filter(|r| r.outcome == Outcome::Failure)
+            .map(|r| (r.actor.as_str(), r.action.as_str()))
+            .collect();
+        assert_eq!(
+            denials,
+            vec![(ANONYMOUS, "find_user_by_name"), ("wangwu", "find_user_by_name")]
+        );
     }
 }
@@ -1,25 +1,12 @@
-use crate::permission_check;
+use crate::authorize;
+use crate::role::{list_permissions, list_roles};
+use crate::state::AppState;
 use axum::http::header::HeaderMap;
 use axum::{
     extract::State,
     http::StatusCode,
 };
-use futures::stream::TryStreamExt;
-use mongodb::{
-    bson::{doc, Bson},
-    Collection, Database,
-};
 use serde::{Deserialize, Serialize};
-use tracing::error;
-
-const COLLECTION: &str = "data";
-
-#[derive(Debug, Serialize, Deserialize)]
-struct UserConfigData {
-    pub _id: Bson,
-    pub key: String,
-    pub values: Vec<String>,
-}
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 pub struct UserConfigDataResponse {
@@ -27,31 +14,18 @@ pub struct UserConfigDataResponse {
     pub permissions: Vec<String>,
 }
 
+/// Names of all roles and of the permission catalog, for building user forms
 pub async fn get_user_cfg_data(
     headers: HeaderMap, //the order is important!
-    db: State<Database>,
+    state: State<AppState>,
 ) -> Result<String, (StatusCode, String)> {
-    permission_check(&headers, "get_user_cfg_data", "")?;
-    let c: Collection<UserConfigData> = db.collection(COLLECTION);
-
-    let mut cursor = c.find(doc! {}, None).await.map_err(|e| {
-        error!("get cursor failed, {:?}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-    })?;
-
-    let mut roles: Vec<String> = Vec::new();
-    let mut permissions: Vec<String> = Vec::new();
-
-    while let Some(data) = cursor.try_next().await.map_err(|e| {
-        error!("cursor browse error {}", e);
-        (StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
-    })? {
-        if data.key == "roles" {
-            roles = data.values;
-        } else if data.key == "permissions" {
-            permissions = data.values;
-        }
-    }
+    authorize(&headers, &state, "get_user_cfg_data", "").await?;
+    let roles = list_roles(&state).await?.into_iter().map(|r| r.name).collect();
+    let permissions = list_permissions(&state)
+        .await?
+        .into_iter()
+        .map(|p| p.name)
+        .collect();
     let res = UserConfigDataResponse { roles, permissions };
     Ok(serde_json::to_string(&res).unwrap())
 }
@@ -0,0 +1,245 @@
+//! Server side validation of user input, reported as structured field errors
+use crate::user::UserBase;
+use axum::http::StatusCode;
+use serde::{Deserialize, Serialize};
+
+const NAME_MIN: usize = 3;
+const NAME_MAX: usize = 32;
+const DISPLAY_NAME_MAX: usize = 64;
+const ATTRIBUTES_MAX: usize = 50;
+const ATTRIBUTE_KEY_MAX: usize = 64;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    fn new(field: &str, message: &str) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Turn field errors into a 422 response whose body is the JSON list of errors
+pub fn into_response(errors: Vec<FieldError>) -> (StatusCode, String) {
+    (
+        StatusCode::UNPROCESSABLE_ENTITY,
+        serde_json::to_string(&errors).unwrap(),
+    )
+}
+
+/// Validate every field of a user, collecting all errors
+pub fn validate_user_base(user: &UserBase) -> Result<(), (StatusCode, String)> {
+    let mut errors = Vec::new();
+    if !is_valid_name(&user.name) {
+        errors.push(FieldError::new(
+            "name",
+            "must be 3 to 32 characters of letters, digits, '.', '_' or '-'",
+        ));
+    }
+    if !is_e164(&user.phone) {
+        errors.push(FieldError::new("phone", "must be an E.164 number like +14155550123"));
+    }
+    if let Some(email) = &user.email {
+        if !is_email(email) {
+            errors.push(FieldError::new("email", "must be a valid email address"));
+        }
+    }
+    if let Some(display_name) = &user.display_name {
+        let len = display_name.chars().count();
+        if display_name.trim().is_empty()
+            || len > DISPLAY_NAME_MAX
+            || display_name.chars().any(char::is_control)
+        {
+            errors.push(FieldError::new(
+                "display_name",
+                "must be 1 to 64 printable characters",
+            ));
+        }
+    }
+    if let Some(locale) = &user.locale {
+        if !is_locale(locale) {
+            errors.push(FieldError::new("locale", "must be a locale like en or zh-CN"));
+        }
+    }
+    if let Some(timezone) = &user.timezone {
+        if !is_timezone(timezone) {
+            errors.push(FieldError::new(
+                "timezone",
+                "must be an IANA time zone like Asia/Shanghai",
+            ));
+        }
+    }
+    if user.attributes.len() > ATTRIBUTES_MAX {
+        errors.push(FieldError::new("attributes", "must hold at most 50 entries"));
+    }
+    for key in user.attributes.keys() {
+        if key.is_empty() || key.len() > ATTRIBUTE_KEY_MAX || key.starts_with('$') || key.contains('.')
+        {
+            errors.push(FieldError::new(
+                &format!("attributes.{}", key),
+                "keys must be 1 to 64 characters without '.' or a leading '$'",
+            ));
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(into_response(errors))
+    }
+}
+
+/// Validate a phone number alone
+pub fn validate_phone(phone: &str) -> Result<(), (StatusCode, String)> {
+    if is_e164(phone) {
+        Ok(())
+    } else {
+        Err(into_response(vec![FieldError::new(
+            "phone",
+            "must be an E.164 number like +14155550123",
+        )]))
+    }
+}
+
+/// Validate an email alone
+pub fn validate_email(email: &str) -> Result<(), (StatusCode, String)> {
+    if is_email(email) {
+        Ok(())
+    } else {
+        Err(into_response(vec![FieldError::new(
+            "email",
+            "must be a valid email address",
+        )]))
+    }
+}
+
+fn is_valid_name(name: &str) -> bool {
+    (NAME_MIN..=NAME_MAX).contains(&name.len())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '_' || c == '-')
+}
+
+/// `+` followed by up to 15 digits, the first one not 0
+fn is_e164(phone: &str) -> bool {
+    match phone.strip_prefix('+') {
+        Some(digits) => {
+            (2..=15).contains(&digits.len())
+                && digits.chars().all(|c| c.is_ascii_digit())
+                && !digits.starts_with('0')
+        }
+        None => false,
+    }
+}
+
+/// The dot-atom form of RFC 5322 addresses, `local@domain`
+fn is_email(email: &str) -> bool {
+    const ATEXT_SPECIALS: &str = "!#$%&'*+/=?^_`{|}~-";
+    let Some((local, domain)) = email.rsplit_once('@') else {
+        return false;
+    };
+    if local.is_empty() || local.len() > 64 || domain.len() > 255 {
+        return false;
+    }
+    let dot_atom = |s: &str, allowed: &dyn Fn(char) -> bool| {
+        s.split('.').all(|atom| !atom.is_empty() && atom.chars().all(allowed))
+    };
+    let local_ok = dot_atom(local, &|c| {
+        c.is_ascii_alphanumeric() || ATEXT_SPECIALS.contains(c)
+    });
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_ok = labels.len() >= 2
+        && labels.iter().all(|l| {
+            !l.is_empty()
+                && l.len() <= 63
+                && !l.starts_with('-')
+                && !l.ends_with('-')
+                && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        });
+    local_ok && domain_ok
+}
+
+/// A language code optionally followed by a region, `en`, `zh-CN` or `pt_BR`
+fn is_locale(locale: &str) -> bool {
+    let mut parts = locale.split(['-', '_']);
+    let lang = parts.next().unwrap_or_default();
+    let lang_ok = (2..=3).contains(&lang.len()) && lang.chars().all(|c| c.is_ascii_lowercase());
+    let region_ok = match parts.next() {
+        None => true,
+        Some(r) => {
+            (r.len() == 2 && r.chars().all(|c| c.is_ascii_uppercase()))
+                || (r.len() == 3 && r.chars().all(|c| c.is_ascii_digit()))
+        }
+    };
+    lang_ok && region_ok && parts.next().is_none()
+}
+
+/// `UTC` or an IANA `Area/Location` name
+fn is_timezone(timezone: &str) -> bool {
+    if timezone == "UTC" {
+        return true;
+    }
+    let parts: Vec<&str> = timezone.split('/').collect();
+    parts.len() >= 2
+        && parts.iter().all(|p| {
+            !p.is_empty()
+                && p.starts_with(|c: char| c.is_ascii_uppercase())
+                && p.chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '+')
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn field_test() {
+        assert!(is_e164("+14155550123"));
+        assert!(!is_e164("14155550123"));
+        assert!(!is_e164("+0123"));
+        assert!(!is_e164("+1415555012345678"));
+
+        assert!(is_email("john.doe+cf@example.co.uk"));
+        assert!(!is_email("john..doe@example.com"));
+        assert!(!is_email("john@localhost"));
+        assert!(!is_email("@example.com"));
+        assert!(!is_email("john@-example.com"));
+
+        assert!(is_valid_name("zhang_san"));
+        assert!(!is_valid_name("zs"));
+        assert!(!is_valid_name("zhang san"));
+
+        assert!(is_locale("en"));
+        assert!(is_locale("zh-CN"));
+        assert!(is_locale("es_419"));
+        assert!(!is_locale("english"));
+
+        assert!(is_timezone("Asia/Shanghai"));
+        assert!(is_timezone("America/Argentina/Buenos_Aires"));
+        assert!(!is_timezone("shanghai"));
+    }
+
+    #[test]
+    fn user_test() {
+        let mut user = UserBase {
+            name: "zhangsan".to_string(),
+            phone: "+8613800138000".to_string(),
+            email: Some("zs@example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(validate_user_base(&user).is_ok());
+
+        user.name = "z".to_string();
+        user.phone = "123".to_string();
+        let (code, body) = validate_user_base(&user).unwrap_err();
+        assert_eq!(code, StatusCode::UNPROCESSABLE_ENTITY);
+        let errors: Vec<FieldError> = serde_json::from_str(&body).unwrap();
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["name", "phone"]);
+    }
+}